@@ -0,0 +1,780 @@
+//! Command line tool to inspect ring buffer directories and storage files.
+
+use arrow::{
+    buf::LogBuf, cursor::Cursor, log::Log, manifest::Manifest, salvage::Salvage, seal::Footer,
+    storage::Storage,
+};
+use std::{
+    collections::VecDeque,
+    env,
+    fmt::Write as _,
+    io::{self, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+const USAGE: &str = "\
+Usage: arrow-cli <COMMAND> <PATH> [OPTIONS]
+
+PATH is either a ring buffer directory or a single storage file.
+
+Commands:
+  info                 Print segments, sequence range and size
+  dump                 Print log records
+  tail                 Print the last log records
+  verify               Verify checksums and ordering of log records
+  salvage              Recover log records from a corrupted storage file
+
+Options:
+  --from <SEQ_NO>      First log record to dump [default: 0]
+  --to <SEQ_NO>        Last log record to dump [default: last]
+  --format <FORMAT>    Format of log records: hex, utf8 or json [default: utf8]
+  -n <COUNT>           Number of log records to tail [default: 10]
+  -f, --follow         Keep printing log records as they are appended
+  --output <PATH>      New storage file to write recovered log records into
+";
+
+/// Time to wait between checks for new log records when following.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("error: {error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = match args.command {
+        Command::Info => info(&args, &mut out),
+        Command::Dump => dump(&args, &mut out),
+        Command::Tail => tail(&args, &mut out),
+        Command::Verify => verify(&args, &mut out),
+        Command::Salvage => salvage(&args, &mut out),
+    };
+
+    match result.and_then(|ok| out.flush().map(|_| ok)) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+
+        // Output was closed early, for example when piped into head.
+        Err(error) if error.kind() == ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Command to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Info,
+    Dump,
+    Tail,
+    Verify,
+    Salvage,
+}
+
+/// Format to print log records in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Hex,
+    Utf8,
+    Json,
+}
+
+/// Parsed command line arguments.
+#[derive(Debug, PartialEq, Eq)]
+struct Args {
+    command: Command,
+    path: PathBuf,
+    from: u64,
+    to: u64,
+    format: Format,
+    count: usize,
+    follow: bool,
+    output: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parse command line arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Command line arguments, without the name of the binary.
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let command = match args.next().as_deref() {
+            Some("info") => Command::Info,
+            Some("dump") => Command::Dump,
+            Some("tail") => Command::Tail,
+            Some("verify") => Command::Verify,
+            Some("salvage") => Command::Salvage,
+            Some(command) => return Err(format!("unknown command {command:?}")),
+            None => return Err("missing command".to_string()),
+        };
+
+        let mut parsed = Self {
+            command,
+            path: PathBuf::new(),
+            from: 0,
+            to: u64::MAX,
+            format: Format::Utf8,
+            count: 10,
+            follow: false,
+            output: None,
+        };
+
+        let mut path = None;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {arg}"))
+            };
+            let number = |value: String| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid number {value:?}"))
+            };
+
+            match arg.as_str() {
+                "--from" => parsed.from = number(value()?)?,
+                "--to" => parsed.to = number(value()?)?,
+                "-n" => parsed.count = number(value()?)? as usize,
+                "-f" | "--follow" => parsed.follow = true,
+                "--output" => parsed.output = Some(PathBuf::from(value()?)),
+                "--format" => {
+                    parsed.format = match value()?.as_str() {
+                        "hex" => Format::Hex,
+                        "utf8" => Format::Utf8,
+                        "json" => Format::Json,
+                        format => return Err(format!("unknown format {format:?}")),
+                    }
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument {arg:?}")),
+            }
+        }
+
+        if parsed.command == Command::Salvage && parsed.output.is_none() {
+            return Err("missing --output for salvage".to_string());
+        }
+
+        parsed.path = path.ok_or("missing path")?;
+        Ok(parsed)
+    }
+}
+
+/// A segment opened for reading.
+struct Segment {
+    storage: Storage,
+    footer: Option<Footer>,
+}
+
+impl Segment {
+    /// Open a segment for reading.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the segment.
+    fn open(path: &Path) -> io::Result<Self> {
+        let storage = Storage::open_read_only(path)?;
+        let footer = Footer::read(&storage)?;
+        Ok(Self { storage, footer })
+    }
+
+    /// Offset past the last block in the segment.
+    fn end(&self) -> u64 {
+        self.footer
+            .as_ref()
+            .map_or(self.storage.len(), |footer| footer.len())
+    }
+
+    /// Cursor to read blocks in the segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the first block to read.
+    fn cursor(&self, offset: u64) -> Cursor<'_> {
+        Cursor::new(&self.storage, offset).with_end(self.end())
+    }
+}
+
+/// Paths to segments, oldest first.
+///
+/// # Arguments
+///
+/// * `path` - Ring buffer directory or a single storage file.
+fn segments(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let segments = Manifest::list(path)?;
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Visit log records in every segment, starting from a sequence number.
+///
+/// # Arguments
+///
+/// * `path` - Ring buffer directory or a single storage file.
+/// * `from` - Sequence number of the first log record to visit.
+/// * `f` - Callback invoked with log records, returns false to stop.
+fn for_each<F>(path: &Path, from: u64, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&Log<'_>) -> io::Result<bool>,
+{
+    let mut logs = LogBuf::with_capacity(0);
+    for path in segments(path)? {
+        let segment = Segment::open(&path)?;
+
+        // Skip sealed segments before the first log record.
+        let offset = match &segment.footer {
+            Some(footer) if footer.last().is_none_or(|last| last < from) => continue,
+            Some(footer) => footer.offset(from),
+            None => 0,
+        };
+
+        let mut cursor = segment.cursor(offset);
+        while cursor.next(&mut logs)? {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                if log.seq_no() >= from && !f(&log)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Visit log records after a position in a ring buffer, and move it past them.
+///
+/// Position is a segment, and the offset of the next block in it. Reading stops at
+/// the end of a segment that is not sealed yet. Segments reclaimed in the meantime
+/// are skipped.
+///
+/// # Arguments
+///
+/// * `path` - Ring buffer directory or a single storage file.
+/// * `position` - Where to continue reading, None to start from the oldest segment.
+/// * `f` - Callback invoked with log records.
+fn for_each_after<F>(path: &Path, position: &mut Option<(PathBuf, u64)>, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&Log<'_>) -> io::Result<()>,
+{
+    let mut logs = LogBuf::with_capacity(0);
+    let paths = segments(path)?;
+    let start = match position {
+        None => 0,
+        Some((current, _)) => paths.partition_point(|path| path < current),
+    };
+
+    for path in &paths[start..] {
+        let offset = match position {
+            Some((current, offset)) if current == path => *offset,
+            _ => 0,
+        };
+
+        let segment = match Segment::open(path) {
+            Ok(segment) => segment,
+            Err(error) if error.kind() == ErrorKind::NotFound => continue,
+            Err(error) => return Err(error),
+        };
+
+        let mut cursor = segment.cursor(offset);
+        while cursor.next(&mut logs)? {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                f(&log)?;
+            }
+        }
+
+        *position = Some((path.clone(), cursor.offset()));
+        if segment.footer.is_none() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print segments, sequence range and size.
+fn info<W: Write>(args: &Args, out: &mut W) -> io::Result<bool> {
+    let (mut bytes, mut count) = (0, 0);
+    let (mut first, mut last) = (None, None);
+    let mut logs = LogBuf::with_capacity(0);
+    let segments = segments(&args.path)?;
+    for path in &segments {
+        let segment = Segment::open(path)?;
+
+        // Sealed segments already know what they hold.
+        let (state, records, range) = match &segment.footer {
+            Some(footer) => ("sealed", footer.count(), footer.first().zip(footer.last())),
+            None => {
+                let (mut records, mut range) = (0, None);
+                let mut cursor = segment.cursor(0);
+                while cursor.next(&mut logs)? {
+                    records += logs.count() as u64;
+                    let start = range.map_or(logs.first(), |(start, _)| Some(start));
+                    range = start.zip(logs.last());
+                }
+
+                ("active", records, range)
+            }
+        };
+
+        let len = segment.storage.len();
+        let display = display_range(range);
+        let path = path.display();
+        writeln!(
+            out,
+            "{path}\t{state}\t{len} bytes\t{records} records\t{display}"
+        )?;
+
+        bytes += len;
+        count += records;
+        first = first.or(range.map(|(start, _)| start));
+        last = range.map(|(_, end)| end).or(last);
+    }
+
+    let segments = segments.len();
+    let display = display_range(first.zip(last));
+    writeln!(
+        out,
+        "total\t{segments} segments\t{bytes} bytes\t{count} records\t{display}"
+    )?;
+    Ok(true)
+}
+
+/// Display a range of sequence numbers.
+///
+/// # Arguments
+///
+/// * `range` - First and last sequence numbers, if any.
+fn display_range(range: Option<(u64, u64)>) -> String {
+    range.map_or("-".to_string(), |(start, end)| format!("{start}..={end}"))
+}
+
+/// Print log records in a range.
+fn dump<W: Write>(args: &Args, out: &mut W) -> io::Result<bool> {
+    let mut line = String::new();
+    for_each(&args.path, args.from, |log| {
+        if log.seq_no() > args.to {
+            return Ok(false);
+        }
+
+        line.clear();
+        render(log, args.format, &mut line);
+        writeln!(out, "{line}")?;
+        Ok(true)
+    })?;
+
+    Ok(true)
+}
+
+/// Print the last log records, optionally following new ones.
+fn tail<W: Write>(args: &Args, out: &mut W) -> io::Result<bool> {
+    let mut lines = VecDeque::with_capacity(args.count);
+    let mut position = None;
+    for_each_after(&args.path, &mut position, |log| {
+        if lines.len() == args.count {
+            lines.pop_front();
+        }
+
+        if args.count > 0 {
+            let mut line = String::new();
+            render(log, args.format, &mut line);
+            lines.push_back(line);
+        }
+
+        Ok(())
+    })?;
+
+    for line in lines {
+        writeln!(out, "{line}")?;
+    }
+
+    if !args.follow {
+        return Ok(true);
+    }
+
+    // Keep polling for new log records, from where the last poll stopped.
+    let mut line = String::new();
+    loop {
+        out.flush()?;
+        thread::sleep(POLL_INTERVAL);
+        for_each_after(&args.path, &mut position, |log| {
+            line.clear();
+            render(log, args.format, &mut line);
+            writeln!(out, "{line}")
+        })?;
+    }
+}
+
+/// Verify checksums and ordering of log records.
+fn verify<W: Write>(args: &Args, out: &mut W) -> io::Result<bool> {
+    let mut valid = true;
+    let mut last = None;
+    let mut logs = LogBuf::with_capacity(0);
+    for path in segments(&args.path)? {
+        let segment = Segment::open(&path)?;
+        let mut problems = Vec::new();
+
+        // Checksum of sealed segments.
+        if let Some(footer) = &segment.footer
+            && let Err(error) = footer.verify(&segment.storage)
+        {
+            problems.push(error.to_string());
+        }
+
+        // Every block should decode, with log records in order.
+        let (mut count, mut first) = (0, None);
+        let mut cursor = segment.cursor(0);
+        loop {
+            let offset = cursor.offset();
+            match cursor.next(&mut logs) {
+                Ok(true) => {}
+                Ok(false) if offset == segment.end() => break,
+                Ok(false) => {
+                    problems.push(format!("incomplete block at offset {offset}"));
+                    break;
+                }
+                Err(error) => {
+                    problems.push(format!("corrupted block at offset {offset}: {error}"));
+                    break;
+                }
+            }
+
+            // Decoded blocks always have log records.
+            let Some(start) = logs.first() else {
+                continue;
+            };
+
+            if last.is_some_and(|last| last >= start) {
+                problems.push(format!(
+                    "log record {start} at offset {offset} is out of order"
+                ));
+            }
+
+            count += logs.count() as u64;
+            first = first.or(Some(start));
+            last = logs.last();
+        }
+
+        // Footer should agree with blocks.
+        if let Some(footer) = &segment.footer
+            && problems.is_empty()
+            && (footer.count(), footer.first(), footer.last())
+                != (count, first, last.filter(|_| count > 0))
+        {
+            problems.push("footer does not match log records".to_string());
+        }
+
+        if problems.is_empty() {
+            writeln!(out, "{}\tok\t{count} records", path.display())?;
+        }
+
+        for problem in problems {
+            valid = false;
+            writeln!(out, "{}\terror\t{problem}", path.display())?;
+        }
+    }
+
+    Ok(valid)
+}
+
+/// Recover log records from a corrupted storage file.
+fn salvage<W: Write>(args: &Args, out: &mut W) -> io::Result<bool> {
+    let source = Storage::open_read_only(&args.path)?;
+    let output = args.output.as_ref().expect("Should have output");
+    let target = Storage::create(output)?;
+    let mut writer = target.writer().expect("Should have writer of new storage");
+    let report = Salvage::new(&source).run(&target, &mut writer)?;
+    let (blocks, records) = (report.blocks, report.records);
+    let display = display_range(report.first.zip(report.last));
+    writeln!(
+        out,
+        "recovered\t{blocks} blocks\t{records} records\t{display}"
+    )?;
+    for lost in &report.lost {
+        let bytes = format!("{}..{}", lost.bytes.start, lost.bytes.end);
+        let records = lost
+            .records
+            .as_ref()
+            .map(|records| (*records.start(), *records.end()));
+        let display = match records {
+            Some(records) => display_range(Some(records)),
+            None => "unknown".to_string(),
+        };
+
+        writeln!(out, "lost\tbytes {bytes}\trecords {display}")?;
+    }
+
+    Ok(report.lost.is_empty())
+}
+
+/// Render a log record into a line of text.
+///
+/// # Arguments
+///
+/// * `log` - Log record to render.
+/// * `format` - Format to render in.
+/// * `line` - Buffer to render into.
+fn render(log: &Log<'_>, format: Format, line: &mut String) {
+    match format {
+        Format::Hex => {
+            let _ = write!(line, "{} ", log.seq_no());
+            for byte in log.data() {
+                let _ = write!(line, "{byte:02x}");
+            }
+        }
+
+        Format::Utf8 => {
+            let _ = write!(
+                line,
+                "{} {}",
+                log.seq_no(),
+                String::from_utf8_lossy(log.data())
+            );
+        }
+
+        Format::Json => {
+            let _ = write!(line, "{{\"seq_no\":{},\"headers\":{{", log.seq_no());
+            for (i, (key, value)) in log.headers().iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+
+                json_string(key, line);
+                line.push(':');
+                json_string(value, line);
+            }
+
+            line.push_str("},\"data\":");
+            json_string(log.data(), line);
+            line.push('}');
+        }
+    }
+}
+
+/// Render bytes as a JSON string, replacing invalid UTF-8.
+///
+/// # Arguments
+///
+/// * `bytes` - Bytes to render.
+/// * `line` - Buffer to render into.
+fn json_string(bytes: &[u8], line: &mut String) {
+    line.push('"');
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+
+    line.push('"');
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use arrow::{
+        block::{Block, Codec},
+        log::Headers,
+        ring::Ring,
+    };
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        Args::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    fn append(ring: &Ring, seq_nos: std::ops::RangeInclusive<u64>) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        let mut writer = ring
+            .writer()
+            .ok_or(anyhow::anyhow!("Should obtain writer"))?;
+        Ok(ring.append(&logs, &mut writer)?)
+    }
+
+    /// Ring with two sealed segments and an active one, 10 log records each.
+    fn ring(dir: &Path) -> Result<Ring> {
+        let ring = Ring::open(dir, Block::new(Codec::default()))?.with_segment_size(1);
+        for seq_no in (1..=30).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        Ok(ring)
+    }
+
+    fn run<F>(command: F, path: &Path, options: &[&str]) -> Result<(bool, Vec<String>)>
+    where
+        F: Fn(&Args, &mut Vec<u8>) -> io::Result<bool>,
+    {
+        let mut args = vec![String::from("dump"), path.display().to_string()];
+        args.extend(options.iter().map(|option| option.to_string()));
+        let args = Args::parse(args.into_iter()).map_err(anyhow::Error::msg)?;
+
+        let mut out = Vec::new();
+        let ok = command(&args, &mut out)?;
+        let lines = String::from_utf8(out)?
+            .lines()
+            .map(str::to_string)
+            .collect();
+        Ok((ok, lines))
+    }
+
+    #[test]
+    fn parse_reads_options() {
+        let args = parse("dump ring --from 10 --to 20 --format json").unwrap();
+        assert_eq!(Command::Dump, args.command);
+        assert_eq!(PathBuf::from("ring"), args.path);
+        assert_eq!((10, 20), (args.from, args.to));
+        assert_eq!(Format::Json, args.format);
+
+        let args = parse("salvage ring.storage --output fixed.storage").unwrap();
+        assert_eq!(Command::Salvage, args.command);
+        assert_eq!(Some(PathBuf::from("fixed.storage")), args.output);
+
+        let args = parse("tail ring -n 5 -f").unwrap();
+        assert_eq!(Command::Tail, args.command);
+        assert_eq!(5, args.count);
+        assert!(args.follow);
+    }
+
+    #[test]
+    fn parse_invalid_arguments_returns_error() {
+        for args in [
+            "",
+            "explode ring",
+            "dump",
+            "dump ring --from",
+            "dump ring --from ten",
+            "dump ring --format xml",
+            "dump ring --color",
+            "dump ring other",
+            "salvage ring.storage",
+        ] {
+            assert!(parse(args).is_err(), "{args:?} should not parse");
+        }
+    }
+
+    #[test]
+    fn render_formats_log_record() {
+        let mut headers = Headers::new();
+        assert!(headers.insert(b"hero", b"Bat\"man"));
+        let log = Log::new_borrowed(7, b"I'm\nBatman").with_headers(headers);
+
+        let mut line = String::new();
+        render(&log, Format::Hex, &mut line);
+        assert_eq!("7 49276d0a4261746d616e", line);
+
+        line.clear();
+        render(&log, Format::Utf8, &mut line);
+        assert_eq!("7 I'm\nBatman", line);
+
+        line.clear();
+        render(&log, Format::Json, &mut line);
+        let expected = r#"{"seq_no":7,"headers":{"hero":"Bat\"man"},"data":"I'm\nBatman"}"#;
+        assert_eq!(expected, line);
+    }
+
+    #[test]
+    fn info_prints_segments() -> Result<()> {
+        let dir = tempdir()?;
+        ring(dir.path())?.close()?;
+
+        let (ok, lines) = run(info, dir.path(), &[])?;
+        assert!(ok);
+        assert_eq!(4, lines.len());
+        assert!(lines[0].contains("\tsealed\t"));
+        assert!(lines[0].ends_with("\t10 records\t1..=10"));
+        assert!(lines[2].contains("\tactive\t"));
+        assert!(lines[2].ends_with("\t10 records\t21..=30"));
+        assert!(lines[3].starts_with("total\t3 segments\t"));
+        assert!(lines[3].ends_with("\t30 records\t1..=30"));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_prints_range_across_segments() -> Result<()> {
+        let dir = tempdir()?;
+        ring(dir.path())?.close()?;
+
+        let (ok, lines) = run(dump, dir.path(), &["--from", "8", "--to", "22"])?;
+        assert!(ok);
+        let expected: Vec<_> = (8..=22).map(|seq_no| format!("{seq_no} Batman")).collect();
+        assert_eq!(expected, lines);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_reports_problems() -> Result<()> {
+        let dir = tempdir()?;
+        ring(dir.path())?.close()?;
+
+        let (ok, lines) = run(verify, dir.path(), &[])?;
+        assert!(ok);
+        assert_eq!(3, lines.len());
+        assert!(lines.iter().all(|line| line.ends_with("\tok\t10 records")));
+
+        // Block without log records is reported rather than trusted.
+        let path = dir.path().join("empty.storage");
+        fs::write(&path, [0; 9])?;
+        let (ok, lines) = run(verify, &path, &[])?;
+        assert!(!ok);
+        assert_eq!(1, lines.len());
+        assert!(
+            lines[0].ends_with("\terror\tcorrupted block at offset 0: Block has no log records")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn for_each_after_continues_from_position() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+
+        let mut seq_nos = Vec::new();
+        let mut position = None;
+        for_each_after(dir.path(), &mut position, |log| {
+            seq_nos.push(log.seq_no());
+            Ok(())
+        })?;
+
+        assert_eq!((1..=30).collect::<Vec<_>>(), seq_nos);
+
+        // Only new log records are read, even after the active segment rolls.
+        seq_nos.clear();
+        append(&ring, 31..=40)?;
+        for_each_after(dir.path(), &mut position, |log| {
+            seq_nos.push(log.seq_no());
+            Ok(())
+        })?;
+
+        assert_eq!((31..=40).collect::<Vec<_>>(), seq_nos);
+        Ok(ring.close()?)
+    }
+}
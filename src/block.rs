@@ -0,0 +1,697 @@
+//! Framed blocks of log records, optionally compressed.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    buf::{Limits, LogBuf},
+    log::Log,
+};
+use std::io::{Error, ErrorKind, Result};
+
+/// Compression codec used to encode a block of log records.
+///
+/// Every codec other than [`Codec::None`] sits behind a cargo feature of the
+/// same name. Blocks encoded with a codec that is not enabled in the current
+/// build cannot be decoded, attempts to do so return an error.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Log records are stored as is.
+    #[default]
+    None,
+
+    /// Log records are compressed with zstd.
+    #[cfg(feature = "zstd")]
+    Zstd,
+
+    /// Log records are compressed with lz4.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Codec {
+    /// Bit in codec identifier set when the block is encrypted.
+    const ENCRYPTED_FLAG: u8 = 1 << 7;
+
+    /// Bit in codec identifier set when log records use compact encoding.
+    const COMPACT_FLAG: u8 = 1 << 6;
+
+    const NONE_ID: u8 = 0;
+    const ZSTD_ID: u8 = 1;
+    const LZ4_ID: u8 = 2;
+
+    /// Identifier of the codec recorded in the block header.
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => Self::NONE_ID,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Self::ZSTD_ID,
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Self::LZ4_ID,
+        }
+    }
+
+    /// Codec from identifier recorded in the block header.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the codec.
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            Self::NONE_ID => Ok(Codec::None),
+
+            #[cfg(feature = "zstd")]
+            Self::ZSTD_ID => Ok(Codec::Zstd),
+
+            #[cfg(not(feature = "zstd"))]
+            Self::ZSTD_ID => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Block is compressed with zstd, but zstd feature is not enabled",
+            )),
+
+            #[cfg(feature = "lz4")]
+            Self::LZ4_ID => Ok(Codec::Lz4),
+
+            #[cfg(not(feature = "lz4"))]
+            Self::LZ4_ID => Err(Error::new(
+                ErrorKind::Unsupported,
+                "Block is compressed with lz4, but lz4 feature is not enabled",
+            )),
+
+            _ => Err(Error::new(ErrorKind::InvalidData, "Unknown block codec")),
+        }
+    }
+}
+
+/// A re-usable buffer to encode batches of log records for storage.
+///
+/// All the log records in a [`LogBuf`] are framed together into a single block,
+/// compressed with the configured [`Codec`]. Framing bytes of a block are:
+///
+/// * Codec identifier (1 byte), highest bits are flags for encryption and encoding.
+/// * Size of log records before compression (4 bytes).
+/// * Size of the block payload (4 bytes).
+///
+/// Followed by the payload itself. Before compression, payload is the sequence number
+/// of the first log record (8 bytes), followed by log records in compact encoding. Their
+/// sequence numbers are encoded relative to the first one, so the layout is the same
+/// regardless of the platform.
+///
+/// Encrypted blocks are compressed before being encrypted, with the header
+/// authenticated alongside the payload.
+pub struct Block {
+    codec: Codec,
+    memory: Vec<u8>,
+    scratch: Vec<u8>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+/// Header of a block.
+pub(crate) struct Header {
+    pub(crate) codec: Codec,
+    pub(crate) compact: bool,
+    pub(crate) encrypted: bool,
+    pub(crate) raw_len: usize,
+    pub(crate) len: usize,
+}
+
+impl Block {
+    /// Number of bytes in the header of a block.
+    pub(crate) const HEADER_SIZE: usize = 9;
+
+    /// Create a new block that encodes logs with a specific codec.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - Codec to encode log records with.
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            memory: Vec::new(),
+            scratch: Vec::new(),
+            #[cfg(feature = "encryption")]
+            keyring: None,
+        }
+    }
+
+    /// Encrypt blocks with the active key of a keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to encrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Keys used to encrypt blocks, if any.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /// Mutable reference to keys used to encrypt blocks, for example to rotate keys.
+    #[cfg(feature = "encryption")]
+    pub fn keyring_mut(&mut self) -> Option<&mut Keyring> {
+        self.keyring.as_mut()
+    }
+
+    /// Codec used to encode log records.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Number of bytes currently held in the block.
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Returns true if block is empty, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    /// Encoded bytes of the block, ready to be appended into storage.
+    pub fn bytes(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Encode a batch of log records into the block.
+    ///
+    /// This replaces any previously encoded bytes. If there are no logs
+    /// in the buffer, the block is left empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Batch of log records to encode.
+    pub fn encode(&mut self, logs: &LogBuf) -> Result<()> {
+        self.memory.clear();
+        if logs.is_empty() {
+            return Ok(());
+        }
+
+        // Encode log records relative to the first one.
+        let base = logs.first().expect("Should have logs");
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&base.to_be_bytes());
+
+        let mut iter = logs.iter();
+        while let Some(log) = iter.next() {
+            log.write_compact(base, &mut self.scratch);
+        }
+
+        // Sizes are recorded with 4 bytes in the header.
+        let src = &self.scratch;
+        let raw_len = Self::to_u32(src.len())?;
+
+        // Write header with a placeholder for payload size.
+        self.memory.push(self.codec.id() | Codec::COMPACT_FLAG);
+        self.memory.extend_from_slice(&raw_len.to_be_bytes());
+        self.memory.extend_from_slice(&[0; 4]);
+
+        // Write payload of the block.
+        match self.codec {
+            Codec::None => self.memory.extend_from_slice(src),
+
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::stream::copy_encode(src.as_slice(), &mut self.memory, 0)?,
+
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => {
+                let start = self.memory.len();
+                let max_len = lz4_flex::block::get_maximum_output_size(src.len());
+                self.memory.resize(start + max_len, 0);
+
+                let dst = &mut self.memory[start..];
+                let len = lz4_flex::block::compress_into(src, dst).map_err(Error::other)?;
+                self.memory.truncate(start + len);
+            }
+        }
+
+        // Encrypt payload of the block, if enabled.
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = &self.keyring {
+            // Take compressed payload out of the block.
+            self.scratch.clear();
+            self.scratch
+                .extend_from_slice(&self.memory[Self::HEADER_SIZE..]);
+            self.memory.truncate(Self::HEADER_SIZE);
+
+            // Header is authenticated, so it must be final before encryption.
+            let len = Self::to_u32(Keyring::OVERHEAD + self.scratch.len())?;
+            self.memory[0] |= Codec::ENCRYPTED_FLAG;
+            self.memory[5..].copy_from_slice(&len.to_be_bytes());
+
+            let mut header = [0; Self::HEADER_SIZE];
+            header.copy_from_slice(&self.memory);
+            keyring.seal(&header, &self.scratch, &mut self.memory)?;
+            return Ok(());
+        }
+
+        // Now that payload is written, fill in the actual size.
+        let len = Self::to_u32(self.memory.len() - Self::HEADER_SIZE)?;
+        self.memory[5..Self::HEADER_SIZE].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+
+    /// Parse header of a block.
+    ///
+    /// Returns an error if sizes in the header exceed limits, before anything
+    /// is read or allocated for the rest of the block.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Header bytes of the block.
+    /// * `limits` - Limits on log records in the block.
+    pub(crate) fn read_header(header: &[u8; Self::HEADER_SIZE], limits: Limits) -> Result<Header> {
+        let encrypted = header[0] & Codec::ENCRYPTED_FLAG != 0;
+        let compact = header[0] & Codec::COMPACT_FLAG != 0;
+        let codec = Codec::from_id(header[0] & !(Codec::ENCRYPTED_FLAG | Codec::COMPACT_FLAG))?;
+        let raw_len = u32::from_be_bytes(header[1..5].try_into().expect("Should never fail"));
+        let len = u32::from_be_bytes(header[5..].try_into().expect("Should never fail"));
+        let (raw_len, len) = (raw_len as usize, len as usize);
+
+        // Blocks always have log records, zeroes are left behind by a crash instead.
+        if raw_len == 0 {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Block has no log records"));
+        }
+
+        // Log records cannot exceed batch limits, and payload can only be a little
+        // larger than them. This covers worst case expansion of every codec along
+        // with the overhead of encryption.
+        if raw_len > limits.max_batch_size || len > raw_len + raw_len / 128 + 1024 {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Block exceeds maximum batch size"));
+        }
+
+        Ok(Header {
+            codec,
+            compact,
+            encrypted,
+            raw_len,
+            len,
+        })
+    }
+
+    /// Decode payload of a block into a buffer of log records.
+    ///
+    /// Payload of encrypted blocks must already be decrypted. Any logs
+    /// previously held in the buffer are cleared.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Header of the block.
+    /// * `payload` - Payload of the block.
+    /// * `scratch` - Buffer to use for intermediate bytes.
+    /// * `logs` - Buffer to decode log records into.
+    pub(crate) fn decode(
+        header: &Header,
+        payload: &[u8],
+        scratch: &mut Vec<u8>,
+        logs: &mut LogBuf,
+    ) -> Result<()> {
+        logs.clear();
+
+        // Log records in compact encoding have to be expanded.
+        if header.compact {
+            let src = if header.codec == Codec::None {
+                payload
+            } else {
+                scratch.clear();
+                Self::decompress(header.codec, header.raw_len, payload, scratch)?;
+                scratch
+            };
+
+            let result = match src.len() == header.raw_len {
+                true => Self::expand(src, logs),
+                false => Err(Error::new(ErrorKind::InvalidData, "Corrupted block")),
+            };
+
+            let result = result.and_then(|_| Self::not_empty(logs));
+
+            if result.is_err() {
+                logs.clear();
+            }
+
+            return result;
+        }
+
+        // Decode payload of the block.
+        let dst = logs.bytes_mut();
+        let raw_len = header.raw_len;
+        if let Err(error) = Self::decompress(header.codec, raw_len, payload, dst) {
+            logs.clear();
+            return Err(error);
+        }
+
+        // Make sure the block held exactly a batch of log records.
+        let len = dst.len();
+        logs.reinitialize();
+        if len != raw_len || logs.len() != raw_len {
+            logs.clear();
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupted block"));
+        }
+
+        // Make sure none of the log records exceed limits.
+        let mut iter = logs.iter();
+        let max_record_size = logs.limits().max_record_size;
+        while let Some(log) = iter.next() {
+            if log.data().len() > max_record_size {
+                logs.clear();
+                return Err(Self::record_too_large());
+            }
+        }
+
+        Self::not_empty(logs)
+    }
+
+    /// Make sure a decoded block has log records.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Log records decoded from the block.
+    fn not_empty(logs: &LogBuf) -> Result<()> {
+        if logs.is_empty() {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Block has no log records"));
+        }
+
+        Ok(())
+    }
+
+    /// Expand log records in compact encoding into a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - Base sequence number followed by log records.
+    /// * `logs` - Buffer to expand log records into.
+    fn expand(src: &[u8], logs: &mut LogBuf) -> Result<()> {
+        let corrupted = || Error::new(ErrorKind::InvalidData, "Corrupted block");
+        let Some((base, mut src)) = src.split_first_chunk() else {
+            return Err(corrupted());
+        };
+
+        let base = u64::from_be_bytes(*base);
+        let max_record_size = logs.limits().max_record_size;
+        while !src.is_empty() {
+            let Some((log, remaining)) = Log::read_compact(base, src) else {
+                return Err(corrupted());
+            };
+
+            if log.data().len() > max_record_size {
+                return Err(Self::record_too_large());
+            }
+
+            // Sequence validation of the buffer catches out of order logs.
+            if !logs.append(&log) {
+                return Err(corrupted());
+            }
+
+            src = remaining;
+        }
+
+        Ok(())
+    }
+
+    /// Error when a log record in a block exceeds limits.
+    fn record_too_large() -> Error {
+        let kind = ErrorKind::InvalidData;
+        Error::new(kind, "Block has log record exceeding maximum record size")
+    }
+
+    /// Decompress payload of a block into a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - Codec the payload was encoded with.
+    /// * `raw_len` - Size of log records in the payload.
+    /// * `payload` - Payload of the block.
+    /// * `dst` - Buffer to write decompressed bytes into.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn decompress(codec: Codec, raw_len: usize, payload: &[u8], dst: &mut Vec<u8>) -> Result<()> {
+        match codec {
+            Codec::None => dst.extend_from_slice(payload),
+
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                // Never decompress more than expected, payload could be corrupted.
+                dst.reserve(raw_len);
+                let decoder = zstd::stream::read::Decoder::with_buffer(payload)?;
+                let mut decoder = std::io::Read::take(decoder, raw_len as u64 + 1);
+                std::io::copy(&mut decoder, dst)?;
+            }
+
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => {
+                dst.resize(raw_len, 0);
+                let len = lz4_flex::block::decompress_into(payload, dst)
+                    .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+                dst.truncate(len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Size recorded in block header.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - Size to record.
+    fn to_u32(len: usize) -> Result<u32> {
+        len.try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Block is too large"))
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::log::Log;
+
+    fn logs() -> LogBuf {
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in 1..=100 {
+            let log = Log::new_borrowed(seq_no, b"Batman is better than superman!");
+            assert!(logs.append(&log));
+        }
+
+        logs
+    }
+
+    fn round_trip(codec: Codec) -> Result<()> {
+        let logs = logs();
+
+        // Encode logs into a block.
+        let mut block = Block::new(codec);
+        block.encode(&logs)?;
+        assert_eq!(codec, block.codec());
+        assert!(!block.is_empty());
+
+        // Decode them back into a different buffer.
+        let (header, payload) = block.bytes().split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+        assert_eq!(codec, header.codec);
+        assert!(header.compact);
+        assert!(!header.encrypted);
+        assert_eq!(payload.len(), header.len);
+
+        let mut r_logs = LogBuf::with_capacity(0);
+        Block::decode(&header, payload, &mut Vec::new(), &mut r_logs)?;
+
+        // Make sure logs are unchanged.
+        assert_eq!(logs.count(), r_logs.count());
+        assert_eq!(logs.first(), r_logs.first());
+        assert_eq!(logs.last(), r_logs.last());
+        assert_eq!(logs.bytes(), r_logs.bytes());
+
+        // Compact encoding (and compression) should shrink logs.
+        assert!(block.len() < logs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_no_compression() -> Result<()> {
+        round_trip(Codec::None)
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn round_trip_zstd() -> Result<()> {
+        round_trip(Codec::Zstd)
+    }
+
+    #[test]
+    #[cfg(feature = "lz4")]
+    fn round_trip_lz4() -> Result<()> {
+        round_trip(Codec::Lz4)
+    }
+
+    #[test]
+    fn encode_empty_logs_empty_block() -> Result<()> {
+        let mut block = Block::new(Codec::None);
+
+        // Encode some logs first.
+        block.encode(&logs())?;
+        assert!(!block.is_empty());
+
+        // Encoding no logs should clear the block.
+        block.encode(&LogBuf::with_capacity(0))?;
+        assert!(block.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_original_encoding() -> Result<()> {
+        let logs = logs();
+
+        // Block with log records as they are held in buffer.
+        let mut bytes = vec![Codec::None.id()];
+        bytes.extend_from_slice(&(logs.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(logs.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(logs.bytes());
+
+        // Should still be decoded.
+        let (header, payload) = bytes.split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+        assert!(!header.compact);
+
+        let mut r_logs = LogBuf::with_capacity(0);
+        Block::decode(&header, payload, &mut Vec::new(), &mut r_logs)?;
+        assert_eq!(logs.bytes(), r_logs.bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_out_of_order_logs_returns_error() -> Result<()> {
+        // Compact block where the second log goes back in sequence.
+        let mut raw = 10u64.to_be_bytes().to_vec();
+        Log::new_borrowed(12, b"Rust").write_compact(10, &mut raw);
+        Log::new_borrowed(11, b"Java").write_compact(10, &mut raw);
+
+        let mut bytes = vec![Codec::None.id() | Codec::COMPACT_FLAG];
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&raw);
+
+        let (header, payload) = bytes.split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+
+        let mut r_logs = LogBuf::with_capacity(0);
+        let result = Block::decode(&header, payload, &mut Vec::new(), &mut r_logs);
+        assert!(result.is_err());
+        assert!(r_logs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn read_header_exceeding_limits_returns_error() -> Result<()> {
+        let mut block = Block::new(Codec::None);
+        block.encode(&logs())?;
+        let header = block.bytes()[..Block::HEADER_SIZE].try_into().unwrap();
+
+        // Log records are larger than allowed.
+        let limits = Limits {
+            max_record_size: 100,
+            max_batch_size: 100,
+        };
+
+        assert!(Block::read_header(header, Limits::default()).is_ok());
+        assert!(Block::read_header(header, limits).is_err());
+
+        // Payload is implausibly larger than log records.
+        let mut header = *header;
+        header[5..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Block::read_header(&header, Limits::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_exceeding_record_limit_returns_error() -> Result<()> {
+        let mut block = Block::new(Codec::None);
+        block.encode(&logs())?;
+
+        let (header, payload) = block.bytes().split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+
+        // Payload of log records are larger than allowed.
+        let limits = Limits {
+            max_record_size: 10,
+            ..Limits::default()
+        };
+
+        let mut r_logs = LogBuf::with_capacity(0).with_limits(limits);
+        let Err(error) = Block::decode(&header, payload, &mut Vec::new(), &mut r_logs) else {
+            panic!("Should not decode block with large records");
+        };
+
+        assert!(error.to_string().contains("maximum record size"));
+        assert!(r_logs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn read_header_all_zeroes_returns_error() {
+        // Tail of storage zeroed out by a crash.
+        let header = [0; Block::HEADER_SIZE];
+        let Err(error) = Block::read_header(&header, Limits::default()) else {
+            panic!("Should not parse header of empty block");
+        };
+
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn decode_compact_block_without_logs_returns_error() -> Result<()> {
+        // Compact block with just the base sequence number.
+        let raw = 10u64.to_be_bytes();
+        let mut bytes = vec![Codec::None.id() | Codec::COMPACT_FLAG];
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&raw);
+
+        let (header, payload) = bytes.split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+
+        let mut r_logs = LogBuf::with_capacity(0);
+        let Err(error) = Block::decode(&header, payload, &mut Vec::new(), &mut r_logs) else {
+            panic!("Should not decode block without logs");
+        };
+
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert!(r_logs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn read_header_unknown_codec_returns_error() {
+        let header = [u8::MAX; Block::HEADER_SIZE];
+        assert!(Block::read_header(&header, Limits::default()).is_err());
+    }
+
+    #[test]
+    fn decode_corrupted_payload_returns_error() -> Result<()> {
+        let logs = logs();
+        let mut block = Block::new(Codec::None);
+        block.encode(&logs)?;
+
+        // Chop off bytes from the end of last log.
+        let (header, payload) = block.bytes().split_at(Block::HEADER_SIZE);
+        let header = Block::read_header(header.try_into().unwrap(), Limits::default())?;
+        let payload = &payload[..payload.len() - 3];
+
+        let mut r_logs = LogBuf::with_capacity(0);
+        let Err(error) = Block::decode(&header, payload, &mut Vec::new(), &mut r_logs) else {
+            panic!("Should not decode corrupted block");
+        };
+
+        // Nothing partial should be visible.
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert!(r_logs.is_empty());
+        Ok(())
+    }
+}
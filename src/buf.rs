@@ -5,10 +5,34 @@ use crate::log::Log;
 /// A growable, reusable buffer of sequenced log records.
 pub struct LogBuf {
     count: usize,
+    limits: Limits,
     memory: Vec<u8>,
     last: Option<u64>,
 }
 
+/// Limits on size of log records held in a buffer.
+///
+/// Limits are enforced when log records are appended into a buffer, and when they
+/// are read back from storage. The latter makes sure corrupted sizes on disk cannot
+/// turn into huge reads or allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of bytes in payload of a log record.
+    pub max_record_size: usize,
+
+    /// Maximum number of bytes a batch of log records can occupy.
+    pub max_batch_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_record_size: 16 * 1024 * 1024,
+            max_batch_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
 impl LogBuf {
     /// Create a new buffer with some pre-defined capacity.
     ///
@@ -19,10 +43,28 @@ impl LogBuf {
         Self {
             count: 0,
             last: None,
+            limits: Limits::default(),
             memory: Vec::with_capacity(capacity),
         }
     }
 
+    /// Enforce limits on log records held in the buffer.
+    ///
+    /// Log records already in the buffer are not validated against new limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - Limits to enforce.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Limits enforced on log records held in the buffer.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
     /// Number of log records in the buffer.
     pub fn count(&self) -> usize {
         self.count
@@ -70,7 +112,8 @@ impl LogBuf {
     /// # Returns
     ///
     /// Returns true if the append was successful. false if sequence validation
-    /// failed, when this happens log is not appended into the buffer.
+    /// failed or if the log exceeds [`Limits`] of the buffer, when this happens
+    /// log is not appended into the buffer.
     #[must_use = "returns true only if appended successfully"]
     pub fn append(&mut self, log: &Log<'_>) -> bool {
         // Perform sequence validation.
@@ -80,8 +123,18 @@ impl LogBuf {
             return false;
         }
 
+        // Make sure the log itself is not too large.
+        if log.data().len() > self.limits.max_record_size {
+            return false;
+        }
+
         // Write log bytes into underlying buffer.
-        log.write(&mut self.memory);
+        // Rollback if that makes the batch too large.
+        let len = self.memory.len();
+        if len + log.write(&mut self.memory) > self.limits.max_batch_size {
+            self.memory.truncate(len);
+            return false;
+        }
 
         // Keep track of the new state.
         self.count += 1;
@@ -126,13 +179,11 @@ impl LogBuf {
     }
 
     /// Mutable reference to bytes backing this buffer.
-    #[allow(dead_code)]
     pub(crate) fn bytes_mut(&mut self) -> &mut Vec<u8> {
         &mut self.memory
     }
 
     /// Reinitialize state of the buffer with contents of memory.
-    #[allow(dead_code)]
     pub(crate) fn reinitialize(&mut self) {
         // Go over all the logs and
         let mut count = 0;
@@ -234,6 +285,32 @@ mod tests {
         assert_eq!(None, logs.next());
     }
 
+    #[test]
+    fn append_exceeding_limits_is_rejected() {
+        let limits = Limits {
+            max_record_size: 5,
+            max_batch_size: 50,
+        };
+
+        let mut buf = LogBuf::with_capacity(32).with_limits(limits);
+        assert_eq!(limits, buf.limits());
+
+        // Payload of the log is too large.
+        assert!(!buf.append(&LOG_3));
+        assert!(buf.is_empty());
+
+        // Third log does not fit in the batch.
+        assert!(buf.append(&LOG_1));
+        assert!(buf.append(&LOG_2));
+        let len = buf.len();
+        assert!(!buf.append(&Log::new_borrowed(3, b"Go")));
+
+        // Make sure state of the buffer is unchanged.
+        assert_eq!(len, buf.len());
+        assert_eq!(2, buf.count());
+        assert_eq!(Some(2), buf.last());
+    }
+
     #[test]
     fn clear_resets_buf() {
         let mut buf = LogBuf::with_capacity(32);
@@ -319,7 +396,7 @@ mod tests {
         let dst = buf_2.bytes_mut();
 
         // Copy bytes and initialize state.
-        dst.extend_from_slice(&src);
+        dst.extend_from_slice(src);
         buf_2.reinitialize();
 
         // Make sure new state is correct.
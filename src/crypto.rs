@@ -0,0 +1,278 @@
+//! At-rest encryption of blocks of log records.
+
+use chacha20poly1305::{
+    Key, KeyInit, Tag, XChaCha20Poly1305, XNonce,
+    aead::{AeadCore, AeadInPlace, OsRng},
+};
+use std::{
+    collections::HashMap,
+    error,
+    fmt::{self, Display, Formatter},
+    io::{Error, ErrorKind, Result},
+};
+
+/// Keys used to encrypt and decrypt blocks of log records.
+///
+/// Blocks are encrypted with XChaCha20-Poly1305, using the active key of the keyring.
+/// Identifier of the key is recorded alongside every encrypted block, so keys can be
+/// rotated without having to re-encrypt existing blocks. As long as a key remains in
+/// the keyring, blocks encrypted with it can be decrypted.
+#[derive(Clone)]
+pub struct Keyring {
+    active: u32,
+    keys: HashMap<u32, XChaCha20Poly1305>,
+}
+
+impl Keyring {
+    /// Number of bytes encryption adds to a block.
+    ///
+    /// This includes key identifier, nonce and authentication tag.
+    pub(crate) const OVERHEAD: usize = 4 + 24 + 16;
+
+    /// Create a new keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the active key.
+    /// * `key` - Active key used to encrypt blocks.
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(id, XChaCha20Poly1305::new(&Key::from(key)));
+        Self { active: id, keys }
+    }
+
+    /// Identifier of the key used to encrypt blocks.
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    /// Add a key that can only be used to decrypt blocks.
+    ///
+    /// Replaces the key if one already exists with the same identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the key.
+    /// * `key` - Key to decrypt blocks with.
+    pub fn insert(&mut self, id: u32, key: [u8; 32]) {
+        self.keys
+            .insert(id, XChaCha20Poly1305::new(&Key::from(key)));
+    }
+
+    /// Rotate to a new key for encryption.
+    ///
+    /// Previously active key is retained to decrypt existing blocks.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the key.
+    /// * `key` - Key to encrypt blocks with.
+    pub fn rotate(&mut self, id: u32, key: [u8; 32]) {
+        self.insert(id, key);
+        self.active = id;
+    }
+
+    /// Remove a key from the keyring.
+    ///
+    /// Blocks encrypted with this key can no longer be decrypted. The active
+    /// key cannot be removed, returns false if attempted.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the key.
+    #[must_use = "returns true only if removed successfully"]
+    pub fn remove(&mut self, id: u32) -> bool {
+        id != self.active && self.keys.remove(&id).is_some()
+    }
+
+    /// Encrypt plaintext with the active key into a buffer.
+    ///
+    /// Writes key identifier, nonce, ciphertext and authentication tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `aad` - Additional data to authenticate, but not encrypt.
+    /// * `plain` - Plaintext to encrypt.
+    /// * `dst` - Buffer to write encrypted bytes into.
+    pub(crate) fn seal(&self, aad: &[u8], plain: &[u8], dst: &mut Vec<u8>) -> Result<()> {
+        let cipher = &self.keys[&self.active];
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        // Write everything that is needed to decrypt later.
+        dst.extend_from_slice(&self.active.to_be_bytes());
+        dst.extend_from_slice(&nonce);
+
+        // Encrypt plaintext in place.
+        let start = dst.len();
+        dst.extend_from_slice(plain);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, aad, &mut dst[start..])
+            .map_err(|_| Error::other("Failed to encrypt block"))?;
+
+        dst.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Decrypt bytes written by [`Keyring::seal`] in place.
+    ///
+    /// Returns reference to the decrypted plaintext.
+    ///
+    /// # Arguments
+    ///
+    /// * `aad` - Additional data authenticated with plaintext.
+    /// * `sealed` - Encrypted bytes.
+    pub(crate) fn open<'a>(&self, aad: &[u8], sealed: &'a mut [u8]) -> Result<&'a [u8]> {
+        if sealed.len() < Self::OVERHEAD {
+            return Err(TamperedError::error());
+        }
+
+        // Fetch everything needed to decrypt.
+        let (id, sealed) = sealed.split_at_mut(4);
+        let (nonce, sealed) = sealed.split_at_mut(24);
+        let (ciphertext, tag) = sealed.split_at_mut(sealed.len() - 16);
+        let id = u32::from_be_bytes((&*id).try_into().expect("Should never fail"));
+
+        // Find key used to encrypt the block.
+        let Some(cipher) = self.keys.get(&id) else {
+            let kind = ErrorKind::NotFound;
+            return Err(Error::new(
+                kind,
+                format!("Missing key {id} to decrypt block"),
+            ));
+        };
+
+        // Decrypt ciphertext in place.
+        let nonce = XNonce::from_slice(nonce);
+        let tag = Tag::from_slice(tag);
+        cipher
+            .decrypt_in_place_detached(nonce, aad, ciphertext, tag)
+            .map_err(|_| TamperedError::error())?;
+
+        Ok(ciphertext)
+    }
+}
+
+/// Error when an encrypted block fails authentication.
+///
+/// Unlike torn writes, which leave an incomplete block at the end of storage, this
+/// means a complete block was modified after it was written. It is returned wrapped
+/// in an [`Error`] of kind [`ErrorKind::InvalidData`], use [`TamperedError::matches`]
+/// to detect it.
+#[derive(Debug)]
+pub struct TamperedError;
+
+impl TamperedError {
+    /// Returns true if an error is caused by a tampered block, false otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - Error to inspect.
+    pub fn matches(error: &Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<TamperedError>())
+    }
+
+    /// I/O error wrapping a tampered error.
+    fn error() -> Error {
+        Error::new(ErrorKind::InvalidData, TamperedError)
+    }
+}
+
+impl Display for TamperedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Encrypted block failed authentication")
+    }
+}
+
+impl error::Error for TamperedError {}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    const AAD: &[u8] = b"header";
+    const PLAIN: &[u8] = b"Batman is better than superman!";
+
+    #[test]
+    fn seal_open_round_trip() -> Result<()> {
+        let keyring = Keyring::new(1, [7; 32]);
+
+        // Encrypt some bytes.
+        let mut sealed = Vec::new();
+        keyring.seal(AAD, PLAIN, &mut sealed)?;
+        assert_eq!(PLAIN.len() + Keyring::OVERHEAD, sealed.len());
+        assert!(!sealed.windows(PLAIN.len()).any(|window| window == PLAIN));
+
+        // Decrypt them back.
+        let plain = keyring.open(AAD, &mut sealed)?;
+        assert_eq!(PLAIN, plain);
+        Ok(())
+    }
+
+    #[test]
+    fn open_after_rotation_uses_old_key() -> Result<()> {
+        let mut keyring = Keyring::new(1, [7; 32]);
+
+        // Encrypt with the original key.
+        let mut sealed_1 = Vec::new();
+        keyring.seal(AAD, PLAIN, &mut sealed_1)?;
+
+        // Encrypt with the new key.
+        keyring.rotate(2, [9; 32]);
+        assert_eq!(2, keyring.active());
+        let mut sealed_2 = Vec::new();
+        keyring.seal(AAD, PLAIN, &mut sealed_2)?;
+
+        // Both should be readable.
+        assert_eq!(PLAIN, keyring.open(AAD, &mut sealed_1)?);
+        assert_eq!(PLAIN, keyring.open(AAD, &mut sealed_2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn open_missing_key_returns_error() -> Result<()> {
+        let mut keyring = Keyring::new(1, [7; 32]);
+        let mut sealed = Vec::new();
+        keyring.seal(AAD, PLAIN, &mut sealed)?;
+
+        // Active key cannot be removed.
+        assert!(!keyring.remove(1));
+
+        // Remove the key used to encrypt.
+        keyring.rotate(2, [9; 32]);
+        assert!(keyring.remove(1));
+
+        let Err(error) = keyring.open(AAD, &mut sealed) else {
+            panic!("Should not decrypt without key");
+        };
+
+        assert_eq!(ErrorKind::NotFound, error.kind());
+        assert!(!TamperedError::matches(&error));
+        Ok(())
+    }
+
+    #[test]
+    fn open_tampered_returns_tampered_error() -> Result<()> {
+        let keyring = Keyring::new(1, [7; 32]);
+        let mut sealed = Vec::new();
+        keyring.seal(AAD, PLAIN, &mut sealed)?;
+
+        // Flip a bit in ciphertext.
+        let mut tampered = sealed.clone();
+        tampered[Keyring::OVERHEAD] ^= 1;
+        let error = keyring.open(AAD, &mut tampered).unwrap_err();
+        assert!(TamperedError::matches(&error));
+
+        // Change authenticated data.
+        let mut tampered = sealed.clone();
+        let error = keyring.open(b"other", &mut tampered).unwrap_err();
+        assert!(TamperedError::matches(&error));
+
+        // Chop off bytes.
+        let error = keyring.open(AAD, &mut sealed[..10]).unwrap_err();
+        assert!(TamperedError::matches(&error));
+        Ok(())
+    }
+}
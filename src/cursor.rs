@@ -0,0 +1,508 @@
+//! Cursors to read log records back from storage.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    block::{Block, Header},
+    buf::LogBuf,
+    storage::Storage,
+};
+use std::io::{Error, ErrorKind, Result};
+
+/// A cursor to read blocks of log records from storage.
+///
+/// Blocks are decoded transparently, regardless of the codec they were encoded
+/// with. Like storage itself, any number of cursors can concurrently read from
+/// storage without synchronization.
+pub struct Cursor<'a> {
+    offset: u64,
+    end: Option<u64>,
+    next_seq_no: Option<u64>,
+    scratch: Vec<u8>,
+    storage: &'a Storage,
+    decoder: Decoder<'a>,
+}
+
+/// Decrypts and decodes blocks read from anywhere, not just storage.
+#[derive(Default)]
+pub(crate) struct Decoder<'a> {
+    raw: Vec<u8>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<&'a Keyring>,
+    #[cfg(not(feature = "encryption"))]
+    _keyring: std::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Cursor<'a> {
+    /// Create a new cursor.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage to read blocks from.
+    /// * `offset` - Offset in storage of the first block to read.
+    pub fn new(storage: &'a Storage, offset: u64) -> Self {
+        Self {
+            offset,
+            storage,
+            end: None,
+            next_seq_no: None,
+            scratch: Vec::new(),
+            decoder: Decoder::default(),
+        }
+    }
+
+    /// Decrypt encrypted blocks with keys from a keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to decrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: &'a Keyring) -> Self {
+        self.decoder = self.decoder.with_keyring(keyring);
+        self
+    }
+
+    /// Track loss of log records, starting with the log record expected next.
+    ///
+    /// See [`Cursor::poll`] for how loss is tracked.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_no` - Sequence number of the log record expected next.
+    pub fn with_next_seq_no(mut self, seq_no: u64) -> Self {
+        self.next_seq_no = Some(seq_no);
+        self
+    }
+
+    /// Stop reading blocks at an offset in storage.
+    ///
+    /// For example, at the end of log records in a sealed segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `end` - Offset in storage past the last block to read.
+    pub fn with_end(mut self, end: u64) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// Sequence number of the log record expected next, if known.
+    pub fn next_seq_no(&self) -> Option<u64> {
+        self.next_seq_no
+    }
+
+    /// Offset in storage of the next block to read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Storage the cursor reads blocks from.
+    pub(crate) fn storage(&self) -> &'a Storage {
+        self.storage
+    }
+
+    /// Read the next block of log records.
+    ///
+    /// Returns true if a block was read into the buffer. false if storage does not
+    /// have a complete block at the current offset, buffer is left unchanged then.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Buffer to read log records into.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self, logs: &mut LogBuf) -> Result<bool> {
+        let len = self.storage.len();
+        let end = self.end.map_or(len, |end| end.min(len));
+        let remaining = end.saturating_sub(self.offset);
+
+        // Fetch header of the next block.
+        let mut header = [0; Block::HEADER_SIZE];
+        if remaining < header.len() as u64 {
+            return Ok(false);
+        }
+
+        self.storage.read_exact_at(self.offset, &mut header)?;
+        let block = Block::read_header(&header, logs.limits())?;
+
+        // Fetch payload of the block.
+        let size = (header.len() + block.len) as u64;
+        if remaining < size {
+            return Ok(false);
+        }
+
+        self.scratch.resize(block.len, 0);
+        let payload_offset = self.offset + header.len() as u64;
+        self.storage
+            .read_exact_at(payload_offset, &mut self.scratch)?;
+
+        // Decode log records in the block.
+        self.decoder
+            .decode(&header, &block, &mut self.scratch, logs)?;
+        self.offset += size;
+        Ok(true)
+    }
+
+    /// Read the next block of log records, reporting log records that were lost.
+    ///
+    /// Space is reclaimed without waiting for readers to catch up, so a slow reader
+    /// can find that log records it expected next no longer exist. Rather than silently
+    /// skipping ahead, this reports [`Event::Lost`] with the range of sequence numbers
+    /// that were skipped. The following read continues from the oldest log record
+    /// that is still available.
+    ///
+    /// Loss is detected with sequence numbers, assuming they are contiguous. Unless
+    /// set with [`Cursor::with_next_seq_no`], the first log record read by the cursor
+    /// is where tracking starts. A cursor reads a single storage, so it cannot tell
+    /// how many bytes were lost, use [`ring::Reader`](crate::ring::Reader) for that.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Buffer to read log records into.
+    pub fn poll(&mut self, logs: &mut LogBuf) -> Result<Event> {
+        let offset = self.offset;
+        if !self.next(logs)? {
+            return Ok(Event::Pending);
+        }
+
+        let (Some(first), Some(last)) = (logs.first(), logs.last()) else {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Block has no log records"));
+        };
+
+        // Rewind, so that the block is read again after reporting loss.
+        if let Some(expected) = self.next_seq_no
+            && expected < first
+        {
+            logs.clear();
+            self.offset = offset;
+            self.next_seq_no = Some(first);
+            event!(
+                warn,
+                from = expected,
+                to = first - 1,
+                "Log records were lost before they could be read"
+            );
+            return Ok(Event::Lost {
+                from: expected,
+                to: first - 1,
+                bytes: None,
+            });
+        }
+
+        self.next_seq_no = Some(last.saturating_add(1));
+        Ok(Event::Logs)
+    }
+}
+
+impl<'a> Decoder<'a> {
+    /// Decrypt encrypted blocks with keys from a keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to decrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn with_keyring(mut self, keyring: &'a Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Decrypt and decode log records in a block.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Encoded header of the block.
+    /// * `block` - Decoded header of the block.
+    /// * `payload` - Payload of the block, decrypted in place.
+    /// * `logs` - Buffer to read log records into.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn decode(
+        &mut self,
+        header: &[u8; Block::HEADER_SIZE],
+        block: &Header,
+        payload: &mut [u8],
+        logs: &mut LogBuf,
+    ) -> Result<()> {
+        // Decrypt payload of the block, if encrypted.
+        #[cfg(feature = "encryption")]
+        let payload = match (block.encrypted, self.keyring) {
+            (false, _) => &payload[..],
+            (true, Some(keyring)) => keyring.open(header, payload)?,
+            (true, None) => {
+                let kind = ErrorKind::NotFound;
+                let error = "Block is encrypted, but cursor has no keys";
+                return Err(Error::new(kind, error));
+            }
+        };
+
+        #[cfg(not(feature = "encryption"))]
+        let payload = match block.encrypted {
+            false => &payload[..],
+            true => {
+                let kind = ErrorKind::Unsupported;
+                let error = "Block is encrypted, but encryption feature is not enabled";
+                return Err(Error::new(kind, error));
+            }
+        };
+
+        Block::decode(block, payload, &mut self.raw, logs)
+    }
+}
+
+/// Outcome of polling a cursor for log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A block of log records was read into the buffer.
+    Logs,
+
+    /// Storage does not have a complete block to read yet.
+    Pending,
+
+    /// Log records were reclaimed before they could be read.
+    Lost {
+        /// Sequence number of the first lost log record.
+        from: u64,
+
+        /// Sequence number of the last lost log record.
+        to: u64,
+
+        /// Number of bytes lost, None if unknown. Only readers that follow segments
+        /// of a [`Ring`](crate::ring::Ring) know where they were when overtaken.
+        bytes: Option<u64>,
+    },
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{block::Codec, log::Log};
+    use anyhow::{Result, anyhow};
+    use tempfile::tempdir;
+
+    #[test]
+    fn next_reads_appended_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Append a few blocks of logs into storage.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in (1..=30).step_by(10) {
+            logs.clear();
+            for seq_no in seq_no..seq_no + 10 {
+                assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        // Read them back from the beginning.
+        let mut cursor = Cursor::new(&storage, 0);
+        let mut next_seq_no = 1;
+        while cursor.next(&mut logs)? {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                assert_eq!(next_seq_no, log.seq_no());
+                next_seq_no += 1;
+            }
+        }
+
+        // Everything should have been read.
+        assert_eq!(31, next_seq_no);
+        assert_eq!(storage.len(), cursor.offset());
+
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn next_decrypts_encrypted_blocks() -> Result<()> {
+        use crate::crypto::{Keyring, TamperedError};
+
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Append blocks encrypted with different keys.
+        let keyring = Keyring::new(1, [7; 32]);
+        let mut block = Block::new(Codec::default()).with_keyring(keyring.clone());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in 1..=2 {
+            logs.clear();
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+
+            block.keyring_mut().unwrap().rotate(2, [9; 32]);
+        }
+
+        // Cannot read without keys.
+        let mut cursor = Cursor::new(&storage, 0);
+        assert!(cursor.next(&mut logs).is_err());
+
+        // Cannot read blocks written with missing keys.
+        let mut cursor = Cursor::new(&storage, 0).with_keyring(&keyring);
+        assert!(cursor.next(&mut logs)?);
+        assert!(cursor.next(&mut logs).is_err());
+
+        // Read all blocks with all the keys.
+        let keyring = block.keyring_mut().unwrap();
+        let mut cursor = Cursor::new(&storage, 0).with_keyring(keyring);
+        for seq_no in 1..=2 {
+            assert!(cursor.next(&mut logs)?);
+            assert_eq!(Some(seq_no), logs.first());
+        }
+
+        // Modify a byte in the payload of first block.
+        let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+        std::os::unix::fs::FileExt::write_all_at(&file, b"X", 20)?;
+
+        let mut cursor = Cursor::new(&storage, 0).with_keyring(keyring);
+        let error = cursor.next(&mut logs).unwrap_err();
+        assert!(TamperedError::matches(&error));
+
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn next_corrupted_size_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Header of a block claiming to be absurdly large.
+        let mut header = [0; Block::HEADER_SIZE];
+        header[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        header[5..].copy_from_slice(&u32::MAX.to_be_bytes());
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&header, &mut writer)?,
+        };
+
+        // Should fail without attempting to read the block.
+        let mut logs = LogBuf::with_capacity(0);
+        let mut cursor = Cursor::new(&storage, 0);
+        let error = cursor.next(&mut logs).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+        assert_eq!(0, cursor.offset());
+
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn poll_reports_lost_logs() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Append blocks with a gap in between them.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_nos in [1..=10, 21..=30] {
+            logs.clear();
+            for seq_no in seq_nos {
+                assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        // Reader expects to start from the very beginning.
+        let mut cursor = Cursor::new(&storage, 0).with_next_seq_no(0);
+        assert_eq!(
+            Event::Lost {
+                from: 0,
+                to: 0,
+                bytes: None
+            },
+            cursor.poll(&mut logs)?
+        );
+        assert_eq!(Event::Logs, cursor.poll(&mut logs)?);
+        assert_eq!(Some(1), logs.first());
+
+        // Gap should be reported before reading the next block.
+        assert_eq!(
+            Event::Lost {
+                from: 11,
+                to: 20,
+                bytes: None
+            },
+            cursor.poll(&mut logs)?
+        );
+        assert!(logs.is_empty());
+        assert_eq!(Event::Logs, cursor.poll(&mut logs)?);
+        assert_eq!(Some(21), logs.first());
+
+        // Nothing more to read.
+        assert_eq!(Event::Pending, cursor.poll(&mut logs)?);
+        assert_eq!(Some(31), cursor.next_seq_no());
+
+        // Without expectations, tracking starts with the first log.
+        let mut cursor = Cursor::new(&storage, 0);
+        assert_eq!(None, cursor.next_seq_no());
+        assert_eq!(Event::Logs, cursor.poll(&mut logs)?);
+        assert_eq!(Some(11), cursor.next_seq_no());
+
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn poll_zeroes_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Zeroes left behind by a crash, rather than a block.
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0; 16], &mut writer)?,
+        };
+
+        let mut logs = LogBuf::with_capacity(0);
+        let mut cursor = Cursor::new(&storage, 0).with_next_seq_no(1);
+        let error = cursor.poll(&mut logs).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, error.kind());
+        assert_eq!(0, cursor.offset());
+
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn next_incomplete_block_returns_false() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+
+        // Encode a block of logs.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        assert!(logs.append(&Log::new_borrowed(1, b"Batman")));
+        block.encode(&logs)?;
+
+        // Append only part of the block into storage.
+        let partial = &block.bytes()[..block.len() - 1];
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(partial, &mut writer)?,
+        };
+
+        // There is no complete block to read.
+        let mut cursor = Cursor::new(&storage, 0);
+        assert!(!cursor.next(&mut logs)?);
+        assert_eq!(0, cursor.offset());
+
+        Ok(storage.close()?)
+    }
+}
@@ -0,0 +1,787 @@
+//! Fan-out of log records from a ring buffer to remote readers over TCP.
+//!
+//! A publisher serves any number of subscribers, each reading log records from
+//! the ring at its own pace, starting from the segment its index points at. Unlike replication, subscribers get decoded log records
+//! rather than blocks, starting from any sequence number or from the latest.
+//!
+//! Subscribers control the flow with credits, one per log record. The publisher
+//! never sends more log records than granted, nor buffers them for slow subscribers,
+//! it reads them from the ring once there are credits. If log records are reclaimed
+//! before a subscriber gets to them, it is notified of the loss and continues from
+//! the oldest log record still available.
+//!
+//! # Protocol
+//!
+//! Every message is a frame, size of the body (u32 BE) followed by the body. Body starts
+//! with a tag (u8) that tells what follows.
+//!
+//! Subscriber starts with a subscription, the kind of start (u8, 0 for a sequence number
+//! and 1 for latest), the sequence number to start from (u64 BE, ignored for latest) and
+//! initial credits (u32 BE). It then grants more credits (u32 BE) whenever it likes.
+//!
+//! Publisher sends log records, the sequence number of the first one (u64 BE) followed
+//! by log records in the same compact encoding as blocks, relative to it. A frame holds
+//! as many log records as there are credits for, up to 64 MiB. Publisher also sends loss
+//! with the first and last sequence numbers lost (u64 BE each) and errors as UTF-8 text.
+
+use crate::{buf::LogBuf, cursor::Event, log::Log, net::Server, ring::Ring};
+use std::{
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering::*},
+    },
+    thread,
+    time::Duration,
+};
+
+/// Tag of a frame with a subscription, from subscribers.
+const SUBSCRIBE: u8 = 0;
+
+/// Tag of a frame granting credits, from subscribers.
+const CREDIT: u8 = 1;
+
+/// Tag of a frame carrying log records, from the publisher.
+const LOGS: u8 = 0;
+
+/// Tag of a frame reporting lost log records, from the publisher.
+const LOST: u8 = 1;
+
+/// Tag of a frame reporting an error, from the publisher.
+const ERROR: u8 = 2;
+
+/// Kind of start from a sequence number.
+const FROM_SEQ_NO: u8 = 0;
+
+/// Kind of start from the latest log record.
+const FROM_LATEST: u8 = 1;
+
+/// Maximum number of bytes in the body of a frame.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maximum time to wait on a peer for the subscription and writes.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a subscription starts reading log records from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    /// From the log record with a sequence number, or the first one after it.
+    SeqNo(u64),
+
+    /// From log records appended after subscribing.
+    Latest,
+}
+
+/// What a subscriber received from the publisher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Log records were read into the buffer.
+    Logs,
+
+    /// Log records were reclaimed before they could be sent.
+    Lost {
+        /// Sequence number of the first lost log record.
+        from: u64,
+
+        /// Sequence number of the last lost log record.
+        to: u64,
+    },
+}
+
+/// Serves log records from a ring buffer to subscribers.
+pub struct Publisher {
+    poll: Duration,
+    max_frame_size: usize,
+    ring: Arc<Ring>,
+}
+
+/// Subscriber reading log records from a publisher.
+pub struct Client {
+    window: u32,
+    consumed: u32,
+    body: Vec<u8>,
+    stream: BufReader<TcpStream>,
+}
+
+/// Credits granted by a subscriber, shared with the thread receiving them.
+struct Credits {
+    state: Mutex<(u64, bool)>,
+    changed: Condvar,
+}
+
+impl Publisher {
+    /// Create a publisher that serves log records from a ring buffer.
+    ///
+    /// Log records are read with the keys of the ring, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `ring` - Ring buffer to read log records from.
+    pub fn new(ring: Arc<Ring>) -> Self {
+        Self {
+            ring,
+            poll: Duration::from_millis(50),
+            max_frame_size: MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Time to wait before checking the ring for new blocks, or subscribers for credits.
+    ///
+    /// # Arguments
+    ///
+    /// * `poll` - Time between checks.
+    pub fn with_poll_interval(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Serve subscribers that connect to a listener, each from a thread of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Listener to accept subscribers from.
+    pub fn serve(self, listener: TcpListener) -> Result<Server> {
+        Server::spawn(listener, "arrow-publisher", move |stream, shutdown| {
+            self.subscribe(stream, shutdown)
+        })
+    }
+
+    /// Serve a subscriber until it disconnects or the server stops.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the subscriber.
+    /// * `shutdown` - Set when the server stops.
+    fn subscribe(&self, stream: TcpStream, shutdown: &AtomicBool) -> Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut body = Vec::new();
+        read_frame(&mut &stream, &mut body)?;
+        let (start, credits) = match body[..] {
+            [SUBSCRIBE, kind, ref rest @ ..] if rest.len() == 12 => {
+                let seq_no = u64::from_be_bytes(rest[..8].try_into().expect("Should be 8 bytes"));
+                let credits = u32::from_be_bytes(rest[8..].try_into().expect("Should be 4 bytes"));
+                match kind {
+                    FROM_SEQ_NO => (Start::SeqNo(seq_no), credits),
+                    FROM_LATEST => (Start::Latest, credits),
+                    _ => return Err(reject(&stream, "Unexpected start of subscription")),
+                }
+            }
+
+            _ => return Err(reject(&stream, "Unexpected subscription")),
+        };
+
+        // Subscribers can stay idle for as long as they like.
+        stream.set_read_timeout(None)?;
+        let credits = Arc::new(Credits {
+            state: Mutex::new((credits.into(), false)),
+            changed: Condvar::new(),
+        });
+
+        let receiver = stream.try_clone()?;
+        let granted = credits.clone();
+        let handle = thread::Builder::new()
+            .name("arrow-publisher-credits".to_string())
+            .spawn(move || granted.receive(receiver))?;
+
+        let result = self.publish(&stream, start, &credits, shutdown);
+        if let Err(error) = &result {
+            reject(&stream, &error.to_string());
+        }
+
+        // Unblock the thread receiving credits.
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = handle.join();
+        result
+    }
+
+    /// Send log records to a subscriber as long as it has credits.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the subscriber.
+    /// * `start` - Where the subscription starts.
+    /// * `credits` - Credits granted by the subscriber.
+    /// * `shutdown` - Set when the server stops.
+    fn publish(
+        &self,
+        stream: &TcpStream,
+        start: Start,
+        credits: &Credits,
+        shutdown: &AtomicBool,
+    ) -> Result<()> {
+        let last = self.ring.last();
+        let from = match start {
+            Start::Latest => last.map_or(0, |last| last.saturating_add(1)),
+            Start::SeqNo(from) => from,
+        };
+
+        let mut reader = self.ring.reader(from);
+        let mut empty = start == Start::Latest && last.is_none();
+
+        let mut logs = LogBuf::with_capacity(0);
+        let mut body = Vec::new();
+        let (mut next, mut count) = (0, 0);
+        let mut stream = BufWriter::new(stream);
+        while !shutdown.load(Acquire) {
+            if next < count {
+                // Flush before waiting, so that the subscriber can catch up.
+                let remaining = (count - next) as u64;
+                let granted = match credits.take(remaining, Duration::ZERO) {
+                    Some(0) => {
+                        stream.flush()?;
+                        credits.take(remaining, self.poll)
+                    }
+                    granted => granted,
+                };
+
+                let granted = match granted {
+                    None => return Ok(()),
+                    Some(0) => continue,
+                    Some(granted) => granted,
+                };
+
+                // Send as many log records as there are credits for, and fit in a frame.
+                let end = next + granted as usize;
+                let mut sent = next;
+                body.clear();
+                body.push(LOGS);
+                let (mut iter, mut index, mut base) = (logs.iter(), 0, None);
+                while let Some(log) = iter.next().filter(|_| index < end) {
+                    index += 1;
+                    if index <= next {
+                        continue;
+                    }
+
+                    let base = *base.get_or_insert_with(|| {
+                        body.extend_from_slice(&log.seq_no().to_be_bytes());
+                        log.seq_no()
+                    });
+
+                    let len = body.len();
+                    log.write_compact(base, &mut body);
+                    if body.len() > self.max_frame_size {
+                        if sent == next {
+                            let kind = ErrorKind::InvalidData;
+                            return Err(Error::new(kind, "Log record is too large for a frame"));
+                        }
+
+                        body.truncate(len);
+                        break;
+                    }
+
+                    sent += 1;
+                }
+
+                // Credits of log records that did not fit are kept for the next frame.
+                credits.put_back((end - sent) as u64);
+                write_frame(&mut stream, &body)?;
+                next = sent;
+                continue;
+            }
+
+            match reader.poll(&mut logs)? {
+                // Log records before the first one appended into an empty ring are not lost.
+                Event::Lost { .. } if empty => empty = false,
+
+                Event::Logs => {
+                    empty = false;
+
+                    // Only the first block can have log records before the start.
+                    let (mut iter, mut skipped) = (logs.iter(), 0);
+                    while let Some(log) = iter.next() {
+                        skipped += usize::from(log.seq_no() < from);
+                    }
+
+                    (next, count) = (skipped, logs.count());
+                }
+
+                Event::Lost { from, to, .. } => {
+                    body.clear();
+                    body.push(LOST);
+                    body.extend_from_slice(&from.to_be_bytes());
+                    body.extend_from_slice(&to.to_be_bytes());
+                    write_frame(&mut stream, &body)?;
+                }
+
+                // Caught up, send whatever is buffered and wait for new blocks.
+                Event::Pending => {
+                    stream.flush()?;
+                    if credits.is_closed() {
+                        return Ok(());
+                    }
+
+                    thread::sleep(self.poll);
+                }
+            }
+        }
+
+        stream.flush()
+    }
+}
+
+impl Client {
+    /// Connect to a publisher and subscribe to log records.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address of the publisher.
+    /// * `start` - Where to start reading log records from.
+    /// * `window` - Maximum number of log records in flight, must be non-zero.
+    pub fn connect<A: ToSocketAddrs>(addr: A, start: Start, window: u32) -> Result<Self> {
+        let window = window.max(1);
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let (kind, seq_no) = match start {
+            Start::SeqNo(seq_no) => (FROM_SEQ_NO, seq_no),
+            Start::Latest => (FROM_LATEST, 0),
+        };
+
+        let mut body = vec![SUBSCRIBE, kind];
+        body.extend_from_slice(&seq_no.to_be_bytes());
+        body.extend_from_slice(&window.to_be_bytes());
+        write_frame(&mut &stream, &body)?;
+
+        Ok(Self {
+            window,
+            body,
+            consumed: 0,
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Maximum time to wait for the publisher, forever if None.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Time to wait for reads.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.get_ref().set_read_timeout(timeout)
+    }
+
+    /// Wait for the next delivery from the publisher.
+    ///
+    /// Log records from previous deliveries are considered consumed, credits for
+    /// them are granted back once half the window is consumed.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Buffer to read log records into, cleared first.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self, logs: &mut LogBuf) -> Result<Delivery> {
+        if self.consumed >= self.window.div_ceil(2) {
+            let mut body = [CREDIT, 0, 0, 0, 0];
+            body[1..].copy_from_slice(&self.consumed.to_be_bytes());
+            write_frame(&mut self.stream.get_ref(), &body)?;
+            self.consumed = 0;
+        }
+
+        logs.clear();
+        read_frame(&mut self.stream, &mut self.body)?;
+        match self.body[..] {
+            [LOGS, ref records @ ..] if records.len() > 8 => {
+                let (base, mut records) = records.split_at(8);
+                let base = u64::from_be_bytes(base.try_into().expect("Should be 8 bytes"));
+                while !records.is_empty() {
+                    match Log::read_compact(base, records) {
+                        Some((log, remaining)) if logs.append(&log) => records = remaining,
+                        _ => {
+                            let kind = ErrorKind::InvalidData;
+                            return Err(Error::new(kind, "Unexpected log records from publisher"));
+                        }
+                    }
+                }
+
+                self.consumed = self.consumed.saturating_add(logs.count() as u32);
+                Ok(Delivery::Logs)
+            }
+
+            [LOST, ref range @ ..] if range.len() == 16 => Ok(Delivery::Lost {
+                from: u64::from_be_bytes(range[..8].try_into().expect("Should be 8 bytes")),
+                to: u64::from_be_bytes(range[8..].try_into().expect("Should be 8 bytes")),
+            }),
+
+            [ERROR, ref message @ ..] => Err(Error::other(String::from_utf8_lossy(message))),
+
+            _ => {
+                let kind = ErrorKind::InvalidData;
+                Err(Error::new(kind, "Unexpected frame from publisher"))
+            }
+        }
+    }
+}
+
+impl Credits {
+    /// Take credits, waiting for some if there are none.
+    ///
+    /// Returns the number of credits taken, or None if the subscriber disconnected.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - Maximum number of credits to take.
+    /// * `timeout` - Maximum time to wait for credits.
+    fn take(&self, max: u64, timeout: Duration) -> Option<u64> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |(credits, closed)| {
+                *credits == 0 && !*closed
+            })
+            .unwrap_or_else(|e| e.into_inner());
+
+        let (credits, closed) = &mut *state;
+        if *closed {
+            return None;
+        }
+
+        let taken = max.min(*credits);
+        *credits -= taken;
+        Some(taken)
+    }
+
+    /// Return credits that were taken, but not used.
+    ///
+    /// # Arguments
+    ///
+    /// * `credits` - Number of credits to return.
+    fn put_back(&self, credits: u64) {
+        if credits > 0 {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.0 = state.0.saturating_add(credits);
+            self.changed.notify_all();
+        }
+    }
+
+    /// True if the subscriber disconnected, false otherwise.
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).1
+    }
+
+    /// Receive credits from a subscriber until it disconnects.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the subscriber.
+    fn receive(&self, stream: TcpStream) {
+        let mut body = Vec::new();
+        let mut stream = BufReader::new(stream);
+        while read_frame(&mut stream, &mut body).is_ok()
+            && let [CREDIT, ref granted @ ..] = body[..]
+            && let Ok(granted) = <[u8; 4]>::try_from(granted)
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.0 = state.0.saturating_add(u32::from_be_bytes(granted).into());
+            self.changed.notify_all();
+        }
+
+        // Disconnected or misbehaving, either way the subscription is over.
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).1 = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Send an error to a subscriber, on a best effort basis.
+///
+/// Returns the error, for convenience.
+///
+/// # Arguments
+///
+/// * `stream` - Connection to the subscriber.
+/// * `message` - Description of the error.
+fn reject(stream: &TcpStream, message: &str) -> Error {
+    let mut body = vec![ERROR];
+    body.extend_from_slice(message.as_bytes());
+    let _ = write_frame(&mut &*stream, &body);
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Write a frame with a body.
+///
+/// # Arguments
+///
+/// * `stream` - Stream to write the frame into.
+/// * `body` - Body of the frame.
+fn write_frame<W: Write>(stream: &mut W, body: &[u8]) -> Result<()> {
+    let Ok(size) = u32::try_from(body.len()) else {
+        let kind = ErrorKind::InvalidInput;
+        return Err(Error::new(kind, "Frame is too large"));
+    };
+
+    stream.write_all(&size.to_be_bytes())?;
+    stream.write_all(body)
+}
+
+/// Read a frame, replacing contents of the body.
+///
+/// # Arguments
+///
+/// * `stream` - Stream to read the frame from.
+/// * `body` - Buffer to read the body into.
+fn read_frame<R: Read>(stream: &mut R, body: &mut Vec<u8>) -> Result<()> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size)?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size == 0 || size > MAX_FRAME_SIZE {
+        let kind = ErrorKind::InvalidData;
+        return Err(Error::new(kind, "Unexpected size of frame"));
+    }
+
+    body.resize(size, 0);
+    stream.read_exact(body)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        retention::Retention,
+    };
+    use anyhow::Result;
+    use std::{ops::RangeInclusive, path::Path};
+    use tempfile::tempdir;
+
+    fn append(ring: &Ring, seq_nos: RangeInclusive<u64>) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        let mut writer = ring.writer().expect("Should obtain writer");
+        ring.append(&logs, &mut writer)?;
+        Ok(())
+    }
+
+    fn ring(dir: &Path) -> Result<Arc<Ring>> {
+        let ring = Ring::open(dir, Block::new(Codec::default()))?.with_segment_size(256);
+        Ok(Arc::new(ring))
+    }
+
+    fn serve(ring: &Arc<Ring>) -> Result<Server> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let publisher = Publisher::new(ring.clone()).with_poll_interval(Duration::from_millis(5));
+        Ok(publisher.serve(listener)?)
+    }
+
+    fn connect(server: &Server, start: Start, window: u32) -> Result<Client> {
+        let client = Client::connect(server.local_addr(), start, window)?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(client)
+    }
+
+    fn receive(client: &mut Client, window: usize, last: u64) -> Result<Vec<u64>> {
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut seq_nos = Vec::new();
+        while seq_nos.last() != Some(&last) {
+            assert_eq!(Delivery::Logs, client.next(&mut logs)?);
+            assert!(logs.count() <= window);
+
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        Ok(seq_nos)
+    }
+
+    #[test]
+    fn client_receives_logs_from_seq_no() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        append(&ring, 1..=10)?;
+        append(&ring, 11..=20)?;
+
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::SeqNo(5), 4)?;
+        assert_eq!((5..=20).collect::<Vec<_>>(), receive(&mut client, 4, 20)?);
+
+        // Keeps following the ring, across segments.
+        append(&ring, 21..=30)?;
+        assert_eq!((21..=30).collect::<Vec<_>>(), receive(&mut client, 4, 30)?);
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_receives_logs_from_latest() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        append(&ring, 1..=10)?;
+
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::Latest, 100)?;
+
+        // Give the publisher time to handle the subscription.
+        thread::sleep(Duration::from_millis(100));
+        append(&ring, 11..=20)?;
+        assert_eq!(
+            (11..=20).collect::<Vec<_>>(),
+            receive(&mut client, 100, 20)?
+        );
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_is_notified_of_lost_logs() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        append(&ring, 100..=110)?;
+
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::SeqNo(50), 8)?;
+        let mut logs = LogBuf::with_capacity(1024);
+        assert_eq!(Delivery::Lost { from: 50, to: 99 }, client.next(&mut logs)?);
+
+        assert_eq!(
+            (100..=110).collect::<Vec<_>>(),
+            receive(&mut client, 8, 110)?
+        );
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_is_notified_of_logs_reclaimed_while_waiting() -> Result<()> {
+        let dir = tempdir()?;
+        let retention = Retention {
+            max_records: Some(10),
+            ..Retention::default()
+        };
+
+        let ring = Arc::new(
+            Ring::open(dir.path(), Block::new(Codec::default()))?
+                .with_segment_size(1)
+                .with_retention(retention),
+        );
+
+        append(&ring, 1..=10)?;
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::SeqNo(1), 2)?;
+        let mut logs = LogBuf::with_capacity(1024);
+        assert_eq!(Delivery::Logs, client.next(&mut logs)?);
+        assert_eq!((Some(1), Some(2)), (logs.first(), logs.last()));
+
+        // Segments after the one being sent are reclaimed before it is done.
+        for seq_no in (11..=50).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        let first = ring.first().expect("Should have log records");
+        let mut seq_nos = Vec::new();
+        while seq_nos.last() != Some(&50) {
+            match client.next(&mut logs)? {
+                Delivery::Lost { from, to } => {
+                    assert_eq!((11, first - 1), (from, to));
+                    seq_nos.push(to);
+                }
+
+                Delivery::Logs => {
+                    let mut iter = logs.iter();
+                    while let Some(log) = iter.next() {
+                        seq_nos.push(log.seq_no());
+                    }
+                }
+            }
+        }
+
+        let mut expected = (3..=10).collect::<Vec<_>>();
+        expected.push(first - 1);
+        expected.extend(first..=50);
+        assert_eq!(expected, seq_nos);
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn client_receives_logs_from_latest_of_empty_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::Latest, 100)?;
+
+        // Nothing is lost before the first log record.
+        thread::sleep(Duration::from_millis(100));
+        append(&ring, 1..=10)?;
+        assert_eq!((1..=10).collect::<Vec<_>>(), receive(&mut client, 100, 10)?);
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn publisher_waits_for_credits() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        append(&ring, 1..=10)?;
+
+        // Without credits for more, nothing but the first two arrive.
+        let server = serve(&ring)?;
+        let mut client = connect(&server, Start::SeqNo(1), 2)?;
+        let mut logs = LogBuf::with_capacity(1024);
+        assert_eq!(Delivery::Logs, client.next(&mut logs)?);
+        assert_eq!((Some(1), Some(2)), (logs.first(), logs.last()));
+
+        client.consumed = 0;
+        client.set_read_timeout(Some(Duration::from_millis(100)))?;
+        assert!(client.next(&mut logs).is_err());
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn publisher_splits_frames_by_size() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        append(&ring, 1..=10)?;
+
+        // Frames fit a few log records, despite credits for all of them.
+        let mut publisher =
+            Publisher::new(ring.clone()).with_poll_interval(Duration::from_millis(5));
+        publisher.max_frame_size = 64;
+        let server = publisher.serve(TcpListener::bind("127.0.0.1:0")?)?;
+        let mut client = connect(&server, Start::SeqNo(1), 100)?;
+
+        let (mut logs, mut seq_nos, mut frames) = (LogBuf::with_capacity(1024), Vec::new(), 0);
+        while seq_nos.last() != Some(&10) {
+            assert_eq!(Delivery::Logs, client.next(&mut logs)?);
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+
+            frames += 1;
+        }
+
+        assert!(frames > 1);
+        assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos);
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn publisher_rejects_unexpected_subscription() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let server = serve(&ring)?;
+
+        let mut stream = TcpStream::connect(server.local_addr())?;
+        write_frame(&mut stream, &[CREDIT, 0, 0, 0, 1])?;
+
+        let mut body = Vec::new();
+        read_frame(&mut stream, &mut body)?;
+        assert_eq!(Some(&ERROR), body.first());
+
+        server.close()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,688 @@
+//! Embedded HTTP server for producers and consumers that cannot link the crate.
+//!
+//! Serves a minimal subset of HTTP/1.1, a single request per connection with bodies
+//! sized by `Content-Length`. Endpoints are:
+//!
+//! * `POST /append` appends log records in the body as a single block. Log records are
+//!   newline-delimited by default, or length-prefixed (u32 BE) with content type
+//!   `application/octet-stream`. Sequence numbers are assigned by the server, responds
+//!   with JSON, `{"first":N,"last":N}`.
+//! * `GET /records?from=N&limit=N` reads up to `limit` log records, starting from the
+//!   first one with a sequence number of at least `from`. Responds with log records,
+//!   each a sequence number (u64 BE), size of the payload (u32 BE) and the payload.
+//! * `GET /status` responds with JSON, `{"head":N,"tail":N,"size":N,"segments":N}`. Head
+//!   and tail are sequence numbers of the first and last log records, null if there are
+//!   none. Size is the number of bytes in segments of the ring buffer.
+//!
+//! Errors are mapped to status codes by their kind and described in plain text.
+
+use crate::{
+    buf::LogBuf,
+    cursor::Event,
+    log::Log,
+    net::Server,
+    ring::{Ring, Writer},
+};
+use std::{
+    io::{self, BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex, atomic::AtomicBool},
+    time::Duration,
+};
+
+/// Maximum number of bytes in the request line and headers.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+
+/// Number of log records read when the request does not have a limit.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// Maximum time to wait on a peer for reads and writes.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves a ring buffer over HTTP.
+///
+/// Appends go through the ring, so segments roll, retention is enforced and
+/// back-pressure applies to them as usual. Reads seek with the index of segments.
+pub struct Endpoint {
+    max_body: usize,
+    max_limit: usize,
+    ring: Arc<Ring>,
+    appender: Mutex<Appender>,
+}
+
+/// State needed to append into the ring buffer, one request at a time.
+struct Appender {
+    writer: Writer,
+    logs: LogBuf,
+}
+
+/// Request, as much of it as endpoints need.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Response to a request.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Endpoint {
+    /// Create an endpoint that serves a ring buffer.
+    ///
+    /// Log records are encoded and read with the block of the ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `ring` - Ring buffer to serve.
+    /// * `writer` - Writer of the ring buffer, to append log records with.
+    pub fn new(ring: Arc<Ring>, writer: Writer) -> Self {
+        let appender = Appender {
+            writer,
+            logs: LogBuf::with_capacity(0),
+        };
+
+        Self {
+            ring,
+            max_body: 16 * 1024 * 1024,
+            max_limit: 10_000,
+            appender: Mutex::new(appender),
+        }
+    }
+
+    /// Maximum number of bytes in the body of a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_body` - Number of bytes.
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Maximum number of log records read by a single request.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_limit` - Number of log records, must be non-zero.
+    pub fn with_max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = max_limit.max(1);
+        self
+    }
+
+    /// Serve requests that connect to a listener, each from a thread of its own.
+    ///
+    /// Returns an error if the writer belongs to some other ring buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Listener to accept requests from.
+    pub fn serve(self, listener: TcpListener) -> Result<Server> {
+        let appender = self.appender.lock().unwrap_or_else(|e| e.into_inner());
+        if !self.ring.owns(&appender.writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different ring"));
+        }
+
+        drop(appender);
+        Server::spawn(listener, "arrow-http", move |stream, shutdown| {
+            self.handle(stream, shutdown)
+        })
+    }
+
+    /// Read a request from a connection and respond to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the client.
+    /// * `shutdown` - Set when the server stops.
+    fn handle(&self, stream: TcpStream, _shutdown: &AtomicBool) -> Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let response = self
+            .read(&stream)
+            .and_then(|request| self.route(&request))
+            .unwrap_or_else(Response::from);
+
+        response.write(&stream)?;
+
+        // Drain what is left of the request, closing with unread bytes resets the
+        // connection and the client might never see the response.
+        stream.shutdown(Shutdown::Write)?;
+        io::copy(&mut (&stream).take(self.max_body as u64), &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Read a request from a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the client.
+    fn read(&self, stream: &TcpStream) -> Result<Request> {
+        let mut reader = BufReader::new(stream).take(MAX_HEAD_SIZE);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(_)) = (parts.next(), parts.next(), parts.next())
+        else {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Malformed request line"));
+        };
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            content_type: None,
+            body: Vec::new(),
+        };
+
+        // Only a couple of headers matter, the rest are skipped.
+        let mut size = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::InvalidInput, "Incomplete headers"));
+            }
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(Error::new(ErrorKind::InvalidInput, "Malformed header"));
+            };
+
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                size = value
+                    .parse()
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Malformed content length"))?;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                // Parameters such as charset do not matter.
+                let media_type = value.split(';').next().unwrap_or_default();
+                request.content_type = Some(media_type.trim().to_ascii_lowercase());
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                let kind = ErrorKind::Unsupported;
+                return Err(Error::new(kind, "Transfer encoding is not supported"));
+            }
+        }
+
+        if size > self.max_body {
+            return Err(Error::new(ErrorKind::FileTooLarge, "Body is too large"));
+        }
+
+        // Body might be partly buffered along with headers.
+        let mut reader = reader.into_inner();
+        request.body.resize(size, 0);
+        reader.read_exact(&mut request.body)?;
+        Ok(request)
+    }
+
+    /// Dispatch a request to its endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Request to respond to.
+    fn route(&self, request: &Request) -> Result<Response> {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/append") => self.append(request),
+            ("GET", "/records") => self.records(request),
+            ("GET", "/status") => self.status(),
+            (_, "/append" | "/records" | "/status") => {
+                Ok(Response::text(405, "Method is not allowed"))
+            }
+            _ => Ok(Response::text(404, "Endpoint does not exist")),
+        }
+    }
+
+    /// Append log records in the body of a request as a single block.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Request with log records in the body.
+    fn append(&self, request: &Request) -> Result<Response> {
+        let mut appender = self.appender.lock().unwrap_or_else(|e| e.into_inner());
+        let Appender { writer, logs } = &mut *appender;
+
+        // Sequence numbers continue from the last log record, even after restarts.
+        logs.clear();
+        let first = self.ring.last().map_or(1, |last| last + 1);
+        let mut seq_no = first;
+        let mut push = |data: &[u8]| {
+            if !logs.append(&Log::new_borrowed(seq_no, data)) {
+                let kind = ErrorKind::FileTooLarge;
+                return Err(Error::new(kind, "Log records exceed limits"));
+            }
+
+            seq_no += 1;
+            Ok(())
+        };
+
+        let mut body = &request.body[..];
+        if request.content_type.as_deref() == Some("application/octet-stream") {
+            while !body.is_empty() {
+                let Some((size, rest)) = body.split_first_chunk::<4>() else {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Malformed size of record",
+                    ));
+                };
+
+                let size = u32::from_be_bytes(*size) as usize;
+                let Some((data, rest)) = rest.split_at_checked(size) else {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Incomplete record"));
+                };
+
+                push(data)?;
+                body = rest;
+            }
+        } else {
+            // Empty lines, including the one after a trailing newline, are skipped.
+            for line in body.split(|&byte| byte == b'\n') {
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                if !line.is_empty() {
+                    push(line)?;
+                }
+            }
+        }
+
+        let Some(end) = logs.last() else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Body has no records"));
+        };
+
+        self.ring.append(logs, writer)?;
+
+        let body = format!(r#"{{"first":{first},"last":{end}}}"#);
+        Ok(Response::json(body))
+    }
+
+    /// Read log records, starting from a sequence number.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Request with the range in the query.
+    fn records(&self, request: &Request) -> Result<Response> {
+        let (mut from, mut limit) = (0, DEFAULT_LIMIT);
+        for param in request.query.split('&').filter(|param| !param.is_empty()) {
+            let malformed = || Error::new(ErrorKind::InvalidInput, "Malformed query");
+            match param.split_once('=').ok_or_else(malformed)? {
+                ("from", value) => from = value.parse().map_err(|_| malformed())?,
+                ("limit", value) => limit = value.parse().map_err(|_| malformed())?,
+                _ => {}
+            }
+        }
+
+        let limit = limit.min(self.max_limit);
+        let mut body = Vec::new();
+        let mut logs = LogBuf::with_capacity(0);
+        let mut reader = self.ring.reader(from);
+        let mut count = 0;
+        while count < limit {
+            // Reclaimed log records are skipped, like those that never existed.
+            match reader.poll(&mut logs)? {
+                Event::Logs => {}
+                Event::Lost { .. } => continue,
+                Event::Pending => break,
+            }
+
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next().filter(|_| count < limit) {
+                if log.seq_no() < from {
+                    continue;
+                }
+
+                // Payloads are bound by limits, well within u32.
+                body.extend_from_slice(&log.seq_no().to_be_bytes());
+                body.extend_from_slice(&(log.data().len() as u32).to_be_bytes());
+                body.extend_from_slice(log.data());
+                count += 1;
+            }
+        }
+
+        Ok(Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        })
+    }
+
+    /// Describe sequence numbers and size of the ring buffer.
+    fn status(&self) -> Result<Response> {
+        // Hold appends while reading, so that head and tail are consistent.
+        let appender = self.appender.lock().unwrap_or_else(|e| e.into_inner());
+        let json = |seq_no: Option<u64>| seq_no.map_or("null".to_string(), |s| s.to_string());
+        let body = format!(
+            r#"{{"head":{},"tail":{},"size":{},"segments":{}}}"#,
+            json(self.ring.first()),
+            json(self.ring.last()),
+            self.ring.size(),
+            self.ring.segments().len()
+        );
+
+        drop(appender);
+        Ok(Response::json(body))
+    }
+}
+
+impl Response {
+    /// Create a response with a plain text body.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - Status code of the response.
+    /// * `text` - Body of the response.
+    fn text(status: u16, text: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+
+    /// Create a successful response with a JSON body.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - Body of the response.
+    fn json(json: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            body: json.into_bytes(),
+        }
+    }
+
+    /// Write the response into a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the client.
+    fn write(&self, mut stream: &TcpStream) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            501 => "Not Implemented",
+            507 => "Insufficient Storage",
+            _ => "Internal Server Error",
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+impl From<Error> for Response {
+    fn from(error: Error) -> Self {
+        let status = match error.kind() {
+            ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => 400,
+            ErrorKind::NotFound => 404,
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => 408,
+            ErrorKind::FileTooLarge => 413,
+            ErrorKind::Unsupported => 501,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => 507,
+            _ => 500,
+        };
+
+        Self::text(status, &error.to_string())
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        offsets::Consumer,
+        retention::Retention,
+        ring::BackPressure,
+    };
+    use anyhow::{Result, anyhow};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn ring(dir: &Path) -> Result<Arc<Ring>> {
+        let ring = Ring::open(dir, Block::new(Codec::default()))?.with_segment_size(1);
+        Ok(Arc::new(ring))
+    }
+
+    fn serve(ring: &Arc<Ring>) -> Result<Server> {
+        let writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        let endpoint = Endpoint::new(ring.clone(), writer);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        Ok(endpoint.with_max_body(1024).serve(listener)?)
+    }
+
+    fn send(server: &Server, head: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect(server.local_addr())?;
+        let head = format!("{head}\r\nContent-Length: {}\r\n\r\n", body.len());
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or(anyhow!("Should have headers"))?;
+
+        let status = String::from_utf8_lossy(&response[9..12]).parse()?;
+        Ok((status, response[split + 4..].to_vec()))
+    }
+
+    fn records(body: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut records = Vec::new();
+        let mut body = body;
+        while let Some((seq_no, rest)) = body.split_first_chunk::<8>()
+            && let Some((size, rest)) = rest.split_first_chunk::<4>()
+        {
+            let (data, rest) = rest.split_at(u32::from_be_bytes(*size) as usize);
+            records.push((u64::from_be_bytes(*seq_no), data.to_vec()));
+            body = rest;
+        }
+
+        records
+    }
+
+    #[test]
+    fn append_then_read_records() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let server = serve(&ring)?;
+
+        let (status, body) = send(
+            &server,
+            "POST /append HTTP/1.1",
+            b"Batman\r\nJoker\n\nBane\n",
+        )?;
+        assert_eq!(200, status);
+        assert_eq!(br#"{"first":1,"last":3}"#.to_vec(), body);
+
+        let mut prefixed = Vec::new();
+        for data in [&b"Robin"[..], b""] {
+            prefixed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            prefixed.extend_from_slice(data);
+        }
+
+        let head = "POST /append HTTP/1.1\r\nContent-Type: application/octet-stream";
+        let (status, body) = send(&server, head, &prefixed)?;
+        assert_eq!(200, status);
+        assert_eq!(br#"{"first":4,"last":5}"#.to_vec(), body);
+
+        let (status, body) = send(&server, "GET /records?from=2&limit=3 HTTP/1.1", b"")?;
+        assert_eq!(200, status);
+        assert_eq!(
+            vec![
+                (2, b"Joker".to_vec()),
+                (3, b"Bane".to_vec()),
+                (4, b"Robin".to_vec())
+            ],
+            records(&body)
+        );
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn records_follow_ring_across_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        let endpoint = Endpoint::new(ring.clone(), writer);
+
+        let request = |method: &str, path: &str, query: &str, body: &[u8]| Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            content_type: None,
+            body: body.to_vec(),
+        };
+
+        // Blocks of 3 log records each, every one in a segment of its own.
+        for _ in 0..3 {
+            let request = request("POST", "/append", "", b"Batman\nJoker\nBane\n");
+            assert_eq!(200, endpoint.route(&request)?.status);
+        }
+
+        assert_eq!(3, ring.segments().len());
+        let response = endpoint.route(&request("GET", "/records", "from=5&limit=2", b""))?;
+        assert_eq!(
+            vec![(5, b"Joker".to_vec()), (6, b"Bane".to_vec())],
+            records(&response.body)
+        );
+
+        let response = endpoint.route(&request("GET", "/records", "from=0", b""))?;
+        let seq_nos = records(&response.body)
+            .into_iter()
+            .map(|(seq_no, _)| seq_no);
+        assert_eq!((1..=9).collect::<Vec<_>>(), seq_nos.collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn append_applies_back_pressure_of_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let _audit = Consumer::register(dir.path(), "audit")?;
+        let retention = Retention {
+            max_records: Some(3),
+            ..Retention::default()
+        };
+
+        let ring = Ring::open(dir.path(), Block::new(Codec::default()))?
+            .with_segment_size(1)
+            .with_retention(retention)
+            .with_back_pressure(BackPressure::Fail);
+
+        let ring = Arc::new(ring);
+        let server = serve(&ring)?;
+        assert_eq!(200, send(&server, "POST /append HTTP/1.1", b"Batman\n")?.0);
+        assert_eq!(
+            200,
+            send(&server, "POST /append HTTP/1.1", b"Joker\nBane\nRobin\n")?.0
+        );
+
+        // Consumer holds back reclamation, so the ring is full.
+        assert_eq!(507, send(&server, "POST /append HTTP/1.1", b"Alfred\n")?.0);
+        assert_eq!((Some(1), Some(4)), (ring.first(), ring.last()));
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn status_reports_sequence_numbers_and_size() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let server = serve(&ring)?;
+
+        let (status, body) = send(&server, "GET /status HTTP/1.1", b"")?;
+        assert_eq!(200, status);
+        assert_eq!(
+            br#"{"head":null,"tail":null,"size":0,"segments":0}"#.to_vec(),
+            body
+        );
+
+        send(&server, "POST /append HTTP/1.1", b"Batman\nJoker\n")?;
+        send(&server, "POST /append HTTP/1.1", b"Bane\n")?;
+        let (_, body) = send(&server, "GET /status HTTP/1.1", b"")?;
+        let expected = format!(
+            r#"{{"head":1,"tail":3,"size":{},"segments":2}}"#,
+            ring.size()
+        );
+        assert_eq!(expected.into_bytes(), body);
+
+        // Sequence numbers continue after restarts.
+        server.close()?;
+        drop(ring);
+        let ring = self::ring(dir.path())?;
+        let server = serve(&ring)?;
+        let (_, body) = send(&server, "POST /append HTTP/1.1", b"Robin\n")?;
+        assert_eq!(br#"{"first":4,"last":4}"#.to_vec(), body);
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_mapped_to_status_codes() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let server = serve(&ring)?;
+
+        assert_eq!(404, send(&server, "GET /unknown HTTP/1.1", b"")?.0);
+        assert_eq!(405, send(&server, "GET /append HTTP/1.1", b"")?.0);
+        assert_eq!(400, send(&server, "GET /records?from=x HTTP/1.1", b"")?.0);
+        assert_eq!(400, send(&server, "POST /append HTTP/1.1", b"\n\n")?.0);
+        assert_eq!(
+            413,
+            send(&server, "POST /append HTTP/1.1", &[b'a'; 2048])?.0
+        );
+
+        let head = "POST /append HTTP/1.1\r\nContent-Type: application/octet-stream";
+        assert_eq!(400, send(&server, head, &[0, 0, 0, 10, 1])?.0);
+        assert_eq!(None, ring.last());
+
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn serve_fails_with_writer_of_other_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let other = tempdir()?;
+        let ring = ring(dir.path())?;
+        let other = self::ring(other.path())?;
+        let writer = other.writer().ok_or(anyhow!("Should obtain writer"))?;
+
+        let endpoint = Endpoint::new(ring, writer);
+        let error = endpoint
+            .serve(TcpListener::bind("127.0.0.1:0")?)
+            .err()
+            .ok_or(anyhow!("Should fail to serve"))?;
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        Ok(())
+    }
+}
@@ -4,15 +4,51 @@
 //! new records. Any number of readers can concurrently read from the ring buffer without any
 //! conflicts/synchronization.
 //!
+//! Log records are kept in a [`Ring`](ring::Ring) of segments in a directory. Oldest segments
+//! are reclaimed as the ring rolls, as decided by its [`Retention`](retention::Retention) policy.
+//!
 //! This sort of thing is typically useful to buffer large amounts of logs locally for fan-out
-//! purposes. However it is important to note that space is reclaimed when ring buffer is full,
-//! it does not wait for anyone to catchup. In other words, there is no back-pressure between
-//! readers and writer.
+//! purposes. However it is important to note that by default space is reclaimed when ring buffer
+//! is full, it does not wait for anyone to catchup. In other words, there is no back-pressure
+//! between readers and writer. Where losing log records is unacceptable, opt in to back-pressure
+//! from registered consumers with [`BackPressure`](ring::BackPressure).
 
 // To customize parts of code that is included in coverage analysis.
 #![cfg_attr(coverage_nightly, feature(coverage_attribute))]
 
+/// Emit a `tracing` event, compiled out entirely without the `tracing` feature.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+)
+    };
+}
+
+pub mod block;
 pub mod buf;
+#[cfg(feature = "encryption")]
+pub mod crypto;
+pub mod cursor;
+#[cfg(feature = "fanout")]
+pub mod fanout;
+#[cfg(feature = "http")]
+pub mod http;
 pub mod lock;
 pub mod log;
+pub mod maintenance;
+pub mod manifest;
+pub mod metrics;
+pub mod net;
+pub mod offsets;
+#[cfg(feature = "tokio")]
+pub mod queue;
+pub mod replication;
+pub mod retention;
+pub mod ring;
+pub mod salvage;
+pub mod seal;
+pub mod snapshot;
 pub mod storage;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod truncate;
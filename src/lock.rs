@@ -1,16 +1,77 @@
-//! Futex free lock for exclusive mutations to ring buffer.
+//! Exclusive lock for mutations, such as appends into storage.
+//!
+//! Obtaining an uncontended lock is a single atomic operation. Contended waiters
+//! spin briefly, then park on a [`Mutex`] and [`Condvar`] until the lock is
+//! released. Locks are unfair by default, or hand out tickets to be obtained in
+//! FIFO order.
 
-use std::sync::atomic::{AtomicBool, Ordering::*};
+use crate::metrics;
+use std::{
+    hint, mem,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::*, fence},
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of attempts to obtain the lock by spinning before parking.
+const SPINS: usize = 100;
 
 /// An exclusive lock to protect against concurrent updates.
-pub struct MutLock(AtomicBool);
+///
+/// [`MutLock::try_lock`] never waits. [`MutLock::lock`] and [`MutLock::lock_timeout`]
+/// spin for a bounded number of attempts, then register as waiters and park on a
+/// condition variable until the lock is released. Unlocking only takes the mutex of
+/// the condition variable, and wakes waiters up, when there are any. So syscalls are
+/// only involved when the lock is contended.
+///
+/// By default waiters are not ordered. Every release wakes all of them up to race for
+/// the lock, along with threads that just started trying, so an unlucky waiter can keep
+/// losing to others. A lock created with [`MutLock::fair`] instead hands out tickets,
+/// and waiters obtain the lock in the order they started waiting. Then even
+/// [`MutLock::try_lock`] only succeeds when nobody is waiting. Tickets of waiters that
+/// time out are skipped.
+pub struct MutLock {
+    fair: bool,
+    locked: AtomicBool,
+    next: AtomicU64,
+    serving: AtomicU64,
+    waiters: AtomicUsize,
+    abandoned: Mutex<Vec<u64>>,
+    unlocked: Condvar,
+}
 
 impl MutLock {
     /// Create a new lock.
     ///
     /// This lock will be unlocked when newly created.
     pub const fn new() -> Self {
-        Self(AtomicBool::new(false))
+        Self::with_fairness(false)
+    }
+
+    /// Create a new lock that is obtained in FIFO order.
+    ///
+    /// This lock will be unlocked when newly created.
+    pub const fn fair() -> Self {
+        Self::with_fairness(true)
+    }
+
+    /// Create a new unlocked lock.
+    ///
+    /// # Arguments
+    ///
+    /// * `fair` - True to obtain the lock in FIFO order.
+    const fn with_fairness(fair: bool) -> Self {
+        Self {
+            fair,
+            locked: AtomicBool::new(false),
+            next: AtomicU64::new(0),
+            serving: AtomicU64::new(0),
+            waiters: AtomicUsize::new(0),
+            abandoned: Mutex::new(Vec::new()),
+            unlocked: Condvar::new(),
+        }
     }
 
     /// Try to obtain exclusive write lock.
@@ -18,11 +79,173 @@ impl MutLock {
     /// If another writer is already in process, this returns early without
     /// obtaining a write lock. This does not involve waiting or syscalls.
     pub fn try_lock(&self) -> Option<MutGuard<'_>> {
-        if self.0.swap(true, Acquire) {
+        let guard = self.try_acquire();
+        if guard.is_none() {
+            metrics::record(|recorder| recorder.contended());
+        }
+
+        guard
+    }
+
+    /// Try to obtain exclusive write lock, without reporting contention.
+    fn try_acquire(&self) -> Option<MutGuard<'_>> {
+        if self.fair {
+            let serving = self.serving.load(Acquire);
+            let next = self
+                .next
+                .compare_exchange(serving, serving + 1, Acquire, Relaxed);
+            return next.ok().map(|_| MutGuard(self));
+        }
+
+        if self.locked.swap(true, Acquire) {
             return None;
         }
 
-        Some(MutGuard(&self.0))
+        Some(MutGuard(self))
+    }
+
+    /// Obtain exclusive write lock, waiting as long as it takes.
+    pub fn lock(&self) -> MutGuard<'_> {
+        self.acquire(None).expect("Should wait without deadline")
+    }
+
+    /// Obtain exclusive write lock, waiting at most for a timeout.
+    ///
+    /// Returns None if the lock could not be obtained in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait for the lock.
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutGuard<'_>> {
+        // Deadlines that far in the future are as good as no deadline.
+        self.acquire(Instant::now().checked_add(timeout))
+    }
+
+    /// Try to obtain exclusive write lock, held until the owned guard is dropped.
+    ///
+    /// Same as [`MutLock::try_lock`], but the guard can outlive the borrow of the lock.
+    pub fn try_lock_owned(self: &Arc<Self>) -> Option<OwnedMutGuard> {
+        self.try_lock().map(|guard| self.own(guard))
+    }
+
+    /// Obtain exclusive write lock, held until the owned guard is dropped.
+    ///
+    /// Same as [`MutLock::lock`], but the guard can outlive the borrow of the lock.
+    pub fn lock_owned(self: &Arc<Self>) -> OwnedMutGuard {
+        self.own(self.lock())
+    }
+
+    /// Obtain exclusive write lock, held until the owned guard is dropped.
+    ///
+    /// Same as [`MutLock::lock_timeout`], but the guard can outlive the borrow of the lock.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait for the lock.
+    pub fn lock_timeout_owned(self: &Arc<Self>, timeout: Duration) -> Option<OwnedMutGuard> {
+        self.lock_timeout(timeout).map(|guard| self.own(guard))
+    }
+
+    /// Hand over unlocking from a guard to an owned guard.
+    ///
+    /// # Arguments
+    ///
+    /// * `guard` - Guard of this lock.
+    fn own(self: &Arc<Self>, guard: MutGuard<'_>) -> OwnedMutGuard {
+        mem::forget(guard);
+        OwnedMutGuard(self.clone())
+    }
+
+    /// Obtain exclusive write lock, spinning and then parking until a deadline.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - When to stop waiting, None to wait forever.
+    fn acquire(&self, deadline: Option<Instant>) -> Option<MutGuard<'_>> {
+        // With tickets, waiting is just a matter of waiting for our turn.
+        let ticket = self.fair.then(|| self.next.fetch_add(1, Relaxed));
+        let attempt = || match ticket {
+            None => self.try_acquire(),
+            Some(ticket) => (self.serving.load(Acquire) == ticket).then(|| MutGuard(self)),
+        };
+
+        for _ in 0..SPINS {
+            if let Some(guard) = attempt() {
+                return Some(guard);
+            }
+
+            hint::spin_loop();
+        }
+
+        // Register as a waiter before checking again, so that unlocks that
+        // race with parking always wake this thread up.
+        let mut abandoned = self.abandoned.lock().unwrap_or_else(|e| e.into_inner());
+        self.waiters.fetch_add(1, SeqCst);
+        fence(SeqCst);
+
+        loop {
+            if let Some(guard) = attempt() {
+                self.waiters.fetch_sub(1, SeqCst);
+                return Some(guard);
+            }
+
+            let timeout = match deadline {
+                None => None,
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => {
+                        // Whoever unlocks when it's this ticket's turn skips it,
+                        // and stops counting it as a waiter.
+                        match ticket {
+                            Some(ticket) => abandoned.push(ticket),
+                            None => _ = self.waiters.fetch_sub(1, SeqCst),
+                        }
+
+                        return None;
+                    }
+                },
+            };
+
+            abandoned = match timeout {
+                None => self
+                    .unlocked
+                    .wait(abandoned)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(timeout) => match self.unlocked.wait_timeout(abandoned, timeout) {
+                    Ok((abandoned, _)) => abandoned,
+                    Err(poisoned) => poisoned.into_inner().0,
+                },
+            };
+        }
+    }
+
+    /// Release the lock, waking up waiters if there are any.
+    fn unlock(&self) {
+        match self.fair {
+            true => _ = self.serving.fetch_add(1, Release),
+            false => self.locked.store(false, Release),
+        };
+
+        // Uncontended unlocks never touch the mutex.
+        fence(SeqCst);
+        if self.waiters.load(SeqCst) == 0 {
+            return;
+        }
+
+        let mut abandoned = self.abandoned.lock().unwrap_or_else(|e| e.into_inner());
+        if self.fair {
+            // Skip over tickets of waiters that gave up.
+            let mut serving = self.serving.load(Acquire);
+            while let Some(index) = abandoned.iter().position(|ticket| *ticket == serving) {
+                abandoned.swap_remove(index);
+                self.waiters.fetch_sub(1, SeqCst);
+                serving += 1;
+                self.serving.store(serving, Release);
+            }
+        }
+
+        drop(abandoned);
+        self.unlocked.notify_all();
     }
 }
 
@@ -33,11 +256,23 @@ impl Default for MutLock {
 }
 
 /// RAII guard to unlock the lock when the guard goes out of scope.
-pub struct MutGuard<'a>(&'a AtomicBool);
+pub struct MutGuard<'a>(&'a MutLock);
 
 impl Drop for MutGuard<'_> {
     fn drop(&mut self) {
-        self.0.store(false, Release);
+        self.0.unlock();
+    }
+}
+
+/// RAII guard to unlock the lock when the guard goes out of scope.
+///
+/// Unlike [`MutGuard`], it keeps the lock alive, so it can be moved across threads
+/// and held across `.await`.
+pub struct OwnedMutGuard(pub(crate) Arc<MutLock>);
+
+impl Drop for OwnedMutGuard {
+    fn drop(&mut self) {
+        self.0.unlock();
     }
 }
 
@@ -45,7 +280,7 @@ impl Drop for MutGuard<'_> {
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
     use super::*;
-    use std::{sync::atomic::AtomicU64, thread};
+    use std::{sync::Arc, thread};
 
     #[test]
     fn lock_acquire_only_one_wins() {
@@ -69,7 +304,7 @@ mod tests {
 
         // Regardless of number of threads contending for lock,
         // only one of the threads should win and obtain lock.
-        let obtained = guards.into_iter().filter_map(|guard| guard).count();
+        let obtained = guards.into_iter().flatten().count();
         assert_eq!(1, obtained);
     }
 
@@ -104,4 +339,118 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn lock_waits_until_released() {
+        for lock in [MutLock::new(), MutLock::fair()] {
+            let count = AtomicU64::new(0);
+            thread::scope(|scope| {
+                for _ in 0..10 {
+                    scope.spawn(|| {
+                        for _ in 0..1000 {
+                            let _guard = lock.lock();
+
+                            // Not atomic on purpose, only one thread holds the lock.
+                            let value = count.load(Relaxed);
+                            count.store(value + 1, Relaxed);
+                        }
+                    });
+                }
+            });
+
+            assert_eq!(10_000, count.load(Relaxed));
+            assert!(lock.try_lock().is_some());
+        }
+    }
+
+    #[test]
+    fn lock_timeout_gives_up_when_held() {
+        for lock in [MutLock::new(), MutLock::fair()] {
+            let guard = lock.lock();
+            thread::scope(|scope| {
+                let waiter = scope.spawn(|| lock.lock_timeout(Duration::from_millis(20)).is_some());
+                assert!(!waiter.join().expect("Should join successfully"));
+            });
+
+            // Lock is usable after waiters give up.
+            drop(guard);
+            assert!(lock.lock_timeout(Duration::from_millis(20)).is_some());
+            assert!(lock.try_lock().is_some());
+        }
+    }
+
+    #[test]
+    fn lock_timeout_waits_for_release() {
+        let lock = Arc::new(MutLock::fair());
+        let guard = lock.lock();
+
+        let waiter = lock.clone();
+        let handle = thread::spawn(move || waiter.lock_timeout(Duration::from_secs(5)).is_some());
+
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        assert!(handle.join().expect("Should join successfully"));
+    }
+
+    #[test]
+    fn owned_guard_unlocks_when_dropped() {
+        let lock = Arc::new(MutLock::fair());
+        let guard = lock.lock_owned();
+        assert!(lock.try_lock_owned().is_none());
+
+        let waiter = lock.clone();
+        let handle = thread::spawn(move || waiter.lock_timeout_owned(Duration::from_secs(5)));
+
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        let guard = handle.join().expect("Should join successfully");
+        assert!(guard.is_some());
+        assert!(lock.try_lock().is_none());
+
+        drop(guard);
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn fair_lock_is_obtained_in_order() {
+        let lock = MutLock::fair();
+        let order = Mutex::new(Vec::new());
+        let guard = lock.lock();
+
+        thread::scope(|scope| {
+            for id in 0..5 {
+                // Wait for the previous waiter to take a ticket.
+                while lock.next.load(Relaxed) != id + 1 {
+                    thread::yield_now();
+                }
+
+                let (lock, order) = (&lock, &order);
+                scope.spawn(move || {
+                    let _guard = lock.lock();
+                    order.lock().expect("Should not be poisoned").push(id);
+                });
+            }
+
+            while lock.next.load(Relaxed) != 6 {
+                thread::yield_now();
+            }
+
+            drop(guard);
+        });
+
+        let order = order.into_inner().expect("Should not be poisoned");
+        assert_eq!(vec![0, 1, 2, 3, 4], order);
+    }
+
+    #[test]
+    fn fair_lock_skips_abandoned_tickets() {
+        let lock = MutLock::fair();
+        let guard = lock.lock();
+        assert!(lock.lock_timeout(Duration::from_millis(10)).is_none());
+        assert!(lock.lock_timeout(Duration::from_millis(10)).is_none());
+
+        drop(guard);
+        assert!(lock.try_lock().is_some());
+        assert_eq!(0, lock.waiters.load(Relaxed));
+    }
 }
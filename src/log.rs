@@ -2,6 +2,12 @@
 
 use std::{borrow::Cow, cmp::Ordering};
 
+/// Bit in the encoded payload length signalling a headers block follows.
+///
+/// Payloads can never be that large, so records written before headers
+/// existed never have this bit set and continue to parse as they are.
+const HEADERS_FLAG: usize = 1 << (usize::BITS - 1);
+
 /// A user generated sequenced log record.
 ///
 /// This is the only type of record that can be appended into
@@ -12,6 +18,7 @@ where
     [u8]: ToOwned<Owned = Vec<u8>>,
 {
     seq_no: u64,
+    headers: Headers<'a>,
     data: Cow<'a, [u8]>,
 }
 
@@ -25,6 +32,7 @@ impl Log<'_> {
     pub const fn new_borrowed(seq_no: u64, data: &[u8]) -> Log<'_> {
         Log {
             seq_no,
+            headers: Headers::new(),
             data: Cow::Borrowed(data),
         }
     }
@@ -38,6 +46,7 @@ impl Log<'_> {
     pub const fn new_owned(seq_no: u64, data: Vec<u8>) -> Log<'static> {
         Log {
             seq_no,
+            headers: Headers::new(),
             data: Cow::Owned(data),
         }
     }
@@ -47,6 +56,11 @@ impl Log<'_> {
         self.seq_no
     }
 
+    /// Reference to headers attached to the log.
+    pub fn headers(&self) -> &Headers<'_> {
+        &self.headers
+    }
+
     /// Reference to data held in log.
     pub fn data(&self) -> &[u8] {
         &self.data
@@ -57,6 +71,15 @@ impl Log<'_> {
         (self.seq_no, self.data.into_owned())
     }
 
+    /// Obtain a log that owns all its bytes, headers included.
+    pub fn into_static(self) -> Log<'static> {
+        Log {
+            seq_no: self.seq_no,
+            headers: Headers(Cow::Owned(self.headers.0.into_owned())),
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
+
     /// Append log bytes into a buffer.
     ///
     /// Returns the number of bytes written into buffer.
@@ -65,17 +88,25 @@ impl Log<'_> {
     ///
     /// * `buf` - Buffer to write log bytes into.
     pub(crate) fn write(&self, buf: &mut Vec<u8>) -> usize {
-        let seq_no_bytes = self.seq_no.to_be_bytes();
-        let size_bytes = self.data.len().to_be_bytes();
+        let start = buf.len();
+
+        // Headers are optional, only pay for them when there are some.
+        let mut size = self.data.len();
+        if !self.headers.is_empty() {
+            size |= HEADERS_FLAG;
+        }
 
         // TODO: Add checksums for integrity checks.
         // Append all the bytes into the buffer.
-        buf.extend_from_slice(&seq_no_bytes);
-        buf.extend_from_slice(&size_bytes);
+        buf.extend_from_slice(&self.seq_no.to_be_bytes());
+        buf.extend_from_slice(&size.to_be_bytes());
+        if !self.headers.is_empty() {
+            self.headers.write(buf);
+        }
         buf.extend_from_slice(&self.data);
 
         // Return total number of bytes appended into buffer.
-        seq_no_bytes.len() + size_bytes.len() + self.data.len()
+        buf.len() - start
     }
 
     /// Parse log bytes from a buffer.
@@ -95,11 +126,132 @@ impl Log<'_> {
         let (size_bytes, buf) = Self::const_copy_n(buf)?;
         let size = usize::from_be_bytes(size_bytes);
 
+        // Fetch headers of the log, if any.
+        let (headers, buf) = if size & HEADERS_FLAG != 0 {
+            Headers::read(buf)?
+        } else {
+            (Headers::new(), buf)
+        };
+
         // Fetch the log payload.
-        let (data, buf) = Self::next_n(buf, size)?;
+        let (data, buf) = Self::next_n(buf, size & !HEADERS_FLAG)?;
 
         // Cool, have everything to construct a log record.
-        Some((Log::new_borrowed(seq_no, data), buf))
+        let log = Log {
+            seq_no,
+            headers,
+            data: Cow::Borrowed(data),
+        };
+
+        Some((log, buf))
+    }
+
+    /// Append log bytes into a buffer, using compact encoding.
+    ///
+    /// Sequence number is encoded as a delta from a base sequence number, and along
+    /// with size of the payload, as a varint. Unlike [`Log::write`], encoded bytes
+    /// don't depend on the platform.
+    ///
+    /// Returns the number of bytes written into buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Base sequence number, cannot be larger than sequence number of the log.
+    /// * `buf` - Buffer to write log bytes into.
+    pub(crate) fn write_compact(&self, base: u64, buf: &mut Vec<u8>) -> usize {
+        let start = buf.len();
+
+        // Lowest bit of size signals a headers block follows.
+        let has_headers = !self.headers.is_empty();
+        let size = (self.data.len() as u64) << 1 | u64::from(has_headers);
+
+        // Append all the bytes into the buffer.
+        Self::write_varint(self.seq_no - base, buf);
+        Self::write_varint(size, buf);
+        if has_headers {
+            self.headers.write(buf);
+        }
+        buf.extend_from_slice(&self.data);
+
+        // Return total number of bytes appended into buffer.
+        buf.len() - start
+    }
+
+    /// Parse log bytes, in compact encoding, from a buffer.
+    ///
+    /// Returns parsed log and bytes remaining after parsing one log. If
+    /// enough logs are not available to parse an entire log, returns None.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Base sequence number the log was encoded with.
+    /// * `buf` - Buffer to read log bytes from.
+    pub(crate) fn read_compact(base: u64, buf: &[u8]) -> Option<(Log<'_>, &[u8])> {
+        // Fetch the sequence number of the log.
+        let (delta, buf) = Self::read_varint(buf)?;
+        let seq_no = base.checked_add(delta)?;
+
+        // Fetch the size of log payload.
+        let (size, buf) = Self::read_varint(buf)?;
+
+        // Fetch headers of the log, if any.
+        let (headers, buf) = if size & 1 != 0 {
+            Headers::read(buf)?
+        } else {
+            (Headers::new(), buf)
+        };
+
+        // Fetch the log payload.
+        let (data, buf) = Self::next_n(buf, (size >> 1).try_into().ok()?)?;
+
+        // Cool, have everything to construct a log record.
+        let log = Log {
+            seq_no,
+            headers,
+            data: Cow::Borrowed(data),
+        };
+
+        Some((log, buf))
+    }
+
+    /// Helper to append a LEB128 varint into a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value to encode.
+    /// * `buf` - Buffer to write encoded bytes into.
+    fn write_varint(mut value: u64, buf: &mut Vec<u8>) {
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+
+        buf.push(value as u8);
+    }
+
+    /// Helper to parse a LEB128 varint from a source buffer.
+    ///
+    /// Returns decoded value along with remaining bytes. If source buffer does not
+    /// have a complete varint, or if it does not fit in 64 bits, returns None.
+    ///
+    /// # Arguments
+    ///
+    /// * `src` - Buffer to read encoded bytes from.
+    fn read_varint(src: &[u8]) -> Option<(u64, &[u8])> {
+        let mut value = 0;
+        for (i, byte) in src.iter().enumerate().take(10) {
+            // Last byte can only hold the most significant bit.
+            if i == 9 && *byte > 1 {
+                return None;
+            }
+
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some((value, &src[i + 1..]));
+            }
+        }
+
+        None
     }
 
     /// Helper to copy next N (compile time known) bytes from a source buffer.
@@ -122,6 +274,20 @@ impl Log<'_> {
     }
 }
 
+impl<'a> Log<'a> {
+    /// Attach headers to the log.
+    ///
+    /// Replaces any headers previously attached to the log.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers` - Headers to attach to the log.
+    pub fn with_headers(mut self, headers: Headers<'a>) -> Self {
+        self.headers = headers;
+        self
+    }
+}
+
 impl Ord for Log<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.seq_no.cmp(&other.seq_no)
@@ -134,6 +300,136 @@ impl PartialOrd for Log<'_> {
     }
 }
 
+/// A small set of binary key/value metadata attached to a log record.
+///
+/// Headers are meant for things like routing information (tenant id, content
+/// type, trace id and such), so they are kept compact. A key can be at most
+/// [`u8::MAX`] bytes and all the headers together, including their encoding
+/// overhead, must fit in [`Headers::MAX_SIZE`] bytes.
+///
+/// Duplicate keys are allowed, lookups return the first match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers<'a>(Cow<'a, [u8]>);
+
+impl Headers<'_> {
+    /// Maximum number of bytes all encoded headers can occupy.
+    pub const MAX_SIZE: usize = u16::MAX as usize;
+
+    /// Create an empty set of headers.
+    pub const fn new() -> Headers<'static> {
+        Headers(Cow::Borrowed(&[]))
+    }
+
+    /// Number of headers in the set.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns true if there are no headers, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Value of the first header with a matching key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key of the header to lookup.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// An iterator to iterate through headers as key/value pairs.
+    pub fn iter(&self) -> HeadersIter<'_> {
+        HeadersIter(&self.0)
+    }
+
+    /// Add a new header to the set.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Key of the header.
+    /// * `value` - Value of the header.
+    ///
+    /// # Returns
+    ///
+    /// Returns true if the header was added. false if the key is too long or
+    /// if adding the header would exceed [`Headers::MAX_SIZE`], when this happens
+    /// headers are left unchanged.
+    #[must_use = "returns true only if added successfully"]
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        // Make sure the new header can be encoded.
+        let size = 1 + key.len() + 2 + value.len();
+        if key.len() > u8::MAX as usize || self.0.len() + size > Self::MAX_SIZE {
+            return false;
+        }
+
+        // Both of these are guaranteed to fit because of the checks above.
+        let buf = self.0.to_mut();
+        buf.push(key.len() as u8);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        buf.extend_from_slice(value);
+        true
+    }
+
+    /// Append headers bytes into a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to write headers bytes into.
+    fn write(&self, buf: &mut Vec<u8>) {
+        // Size is capped on insert, so it always fits.
+        buf.extend_from_slice(&(self.0.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.0);
+    }
+
+    /// Parse headers bytes from a buffer.
+    ///
+    /// Returns parsed headers and bytes remaining after parsing. If enough
+    /// bytes are not available, or they don't hold valid headers, returns None.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Buffer to read headers bytes from.
+    fn read(buf: &[u8]) -> Option<(Headers<'_>, &[u8])> {
+        // Fetch the size of all the headers.
+        let (size_bytes, buf) = Log::const_copy_n(buf)?;
+        let size = u16::from_be_bytes(size_bytes) as usize;
+
+        // Make sure every header is complete.
+        let (bytes, buf) = Log::next_n(buf, size)?;
+        let mut headers = HeadersIter(bytes);
+        while headers.next().is_some() {}
+        if !headers.0.is_empty() {
+            return None;
+        }
+
+        Some((Headers(Cow::Borrowed(bytes)), buf))
+    }
+}
+
+/// An iterator to iterate through headers of a log.
+pub struct HeadersIter<'a>(&'a [u8]);
+
+impl<'a> Iterator for HeadersIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Fetch the key of the header.
+        let ([key_size], buf) = Log::const_copy_n(self.0)?;
+        let (key, buf) = Log::next_n(buf, key_size as usize)?;
+
+        // Fetch the value of the header.
+        let (value_size, buf) = Log::const_copy_n(buf)?;
+        let (value, buf) = Log::next_n(buf, u16::from_be_bytes(value_size) as usize)?;
+
+        // Track the bytes to read next header from.
+        self.0 = buf;
+        Some((key, value))
+    }
+}
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
@@ -171,6 +467,17 @@ mod tests {
         assert_eq!(&data, &r_data);
     }
 
+    #[test]
+    fn into_static_keeps_headers() {
+        let data = vec![9; 250];
+        let mut headers = Headers::new();
+        assert!(headers.insert(b"tenant", b"gotham"));
+
+        let log = Log::new_borrowed(22, &data).with_headers(headers);
+        let owned = log.clone().into_static();
+        assert_eq!(log, owned);
+    }
+
     #[test]
     fn cmp_compares_logs() {
         let log_1 = Log::new_borrowed(1, b"data");
@@ -195,7 +502,7 @@ mod tests {
 
         // Parse log records back.
         let (r_log_1, buf) = Log::read(&buf).expect("Should parse log");
-        let (r_log_2, buf) = Log::read(&buf).expect("Should parse log");
+        let (r_log_2, buf) = Log::read(buf).expect("Should parse log");
 
         // Make sure expected results.
         assert_eq!(log_1, r_log_1);
@@ -203,6 +510,154 @@ mod tests {
         assert!(buf.is_empty()); // No more logs.
     }
 
+    #[test]
+    fn headers_round_trip() {
+        let mut buf = Vec::new();
+
+        // Attach some headers to a log.
+        let mut headers = Headers::new();
+        assert!(headers.insert(b"tenant", b"wayne-enterprises"));
+        assert!(headers.insert(b"content-type", b"text/plain"));
+        let log = Log::new_borrowed(69, b"batman").with_headers(headers);
+
+        // Write it into buffer and parse it back.
+        log.write(&mut buf);
+        let (r_log, buf) = Log::read(&buf).expect("Should parse log");
+
+        // Make sure expected results.
+        assert_eq!(log, r_log);
+        assert_eq!(2, r_log.headers().len());
+        assert_eq!(
+            Some(&b"text/plain"[..]),
+            r_log.headers().get(b"content-type")
+        );
+        assert_eq!(None, r_log.headers().get(b"trace-id"));
+        assert!(buf.is_empty()); // No more logs.
+    }
+
+    #[test]
+    fn no_headers_uses_original_encoding() {
+        let mut buf = Vec::new();
+
+        // Hand craft a log in the encoding that predates headers.
+        buf.extend_from_slice(&69u64.to_be_bytes());
+        buf.extend_from_slice(&6usize.to_be_bytes());
+        buf.extend_from_slice(b"batman");
+
+        // It should still be readable.
+        let (r_log, _) = Log::read(&buf).expect("Should parse log");
+        assert_eq!(Log::new_borrowed(69, b"batman"), r_log);
+        assert!(r_log.headers().is_empty());
+
+        // And logs without headers should be written the same way.
+        let mut r_buf = Vec::new();
+        let written = r_log.write(&mut r_buf);
+        assert_eq!(buf.len(), written);
+        assert_eq!(buf, r_buf);
+    }
+
+    #[test]
+    fn headers_insert_too_large_is_rejected() {
+        let mut headers = Headers::new();
+
+        // Keys cannot be longer than 255 bytes.
+        assert!(!headers.insert(&[1; 256], b"value"));
+        assert!(headers.is_empty());
+
+        // All headers together should fit within limits.
+        let value = vec![9; Headers::MAX_SIZE / 2];
+        assert!(headers.insert(b"key", &value));
+        assert!(!headers.insert(b"key", &value));
+        assert_eq!(1, headers.len());
+    }
+
+    #[test]
+    fn read_malformed_headers_returns_empty() {
+        let mut buf = Vec::new();
+
+        // Write a log with headers into buffer.
+        let mut headers = Headers::new();
+        assert!(headers.insert(b"tenant", b"wayne-enterprises"));
+        Log::new_borrowed(69, b"batman")
+            .with_headers(headers)
+            .write(&mut buf);
+
+        // Corrupt length of the header key, it no longer fits.
+        buf[18] = u8::MAX;
+        assert!(Log::read(&buf).is_none());
+    }
+
+    #[test]
+    fn compact_serialization_round_trip() {
+        let mut buf = Vec::new();
+
+        // Write some log records into buffer.
+        let mut headers = Headers::new();
+        assert!(headers.insert(b"tenant", b"wayne-enterprises"));
+        let log_1 = Log::new_borrowed(69, b"batman").with_headers(headers);
+        let log_2 = Log::new_borrowed(u64::MAX, b"superman");
+
+        let written = log_1.write_compact(60, &mut buf);
+        log_2.write_compact(60, &mut buf);
+
+        // Sequence delta and size both fit in a byte.
+        assert_eq!(1 + 1 + 28 + 6, written);
+
+        // Parse log records back.
+        let (r_log_1, buf) = Log::read_compact(60, &buf).expect("Should parse log");
+        let (r_log_2, buf) = Log::read_compact(60, buf).expect("Should parse log");
+
+        // Make sure expected results.
+        assert_eq!(log_1, r_log_1);
+        assert_eq!(log_2, r_log_2);
+        assert!(buf.is_empty()); // No more logs.
+    }
+
+    #[test]
+    fn compact_read_not_enough_bytes_returns_empty() {
+        let mut buf = Vec::new();
+
+        // Empty buffer should not parse log.
+        assert!(Log::read_compact(0, &buf).is_none());
+
+        // Write a log record into buffer.
+        let log = Log::new_borrowed(u64::MAX, b"batman");
+        log.write_compact(0, &mut buf);
+
+        // Remove the last bytes from the buffer.
+        for _ in 0..buf.len() {
+            buf.truncate(buf.len() - 1);
+
+            // Buffer should not have enough bytes read next log.
+            assert!(Log::read_compact(0, &buf).is_none());
+        }
+    }
+
+    #[test]
+    fn compact_read_overflow_returns_empty() {
+        let mut buf = Vec::new();
+
+        // Sequence number overflows when added to base.
+        Log::new_borrowed(u64::MAX, b"batman").write_compact(0, &mut buf);
+        assert!(Log::read_compact(1, &buf).is_none());
+
+        // Varint that does not fit in 64 bits.
+        let buf = [u8::MAX; 11];
+        assert!(Log::read_compact(0, &buf).is_none());
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut buf = Vec::new();
+            Log::write_varint(value, &mut buf);
+
+            let (r_value, remaining) = Log::read_varint(&buf).expect("Should parse varint");
+            assert_eq!(value, r_value);
+            assert!(remaining.is_empty());
+        }
+    }
+
     #[test]
     fn read_not_enough_bytes_returns_empty() {
         let mut buf = Vec::new();
@@ -0,0 +1,310 @@
+//! Periodic maintenance off the append hot path.
+
+use std::{
+    io::{Error, Result},
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Periodic maintenance tasks, such as syncing storage to disk.
+///
+/// Tasks can either be driven explicitly with [`Maintenance::tick`], or in the
+/// background by a dedicated thread with [`Maintenance::spawn`].
+#[derive(Default)]
+pub struct Maintenance {
+    tasks: Vec<Task>,
+}
+
+/// A named maintenance task that runs at an interval.
+struct Task {
+    name: &'static str,
+    due: Instant,
+    interval: Duration,
+    run: Box<dyn FnMut() -> Result<()> + Send>,
+}
+
+impl Maintenance {
+    /// Create maintenance without any tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a task that runs at an interval.
+    ///
+    /// First run of the task is due one interval from now.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the task, included in errors.
+    /// * `interval` - Time between consecutive runs of the task.
+    /// * `task` - Task to run.
+    pub fn with_task<F>(mut self, name: &'static str, interval: Duration, task: F) -> Self
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        self.tasks.push(Task {
+            name,
+            interval,
+            run: Box::new(task),
+            due: Instant::now() + interval,
+        });
+
+        self
+    }
+
+    /// Run all tasks that are due.
+    ///
+    /// Every due task runs, even if some of them fail. A failed task is retried
+    /// after its interval, like any other task. Returns the first error, if any.
+    pub fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        self.run(|task| task.due <= now)
+    }
+
+    /// Run all tasks, regardless of whether they are due.
+    ///
+    /// Returns the first error, if any.
+    pub fn flush(&mut self) -> Result<()> {
+        self.run(|_| true)
+    }
+
+    /// Run maintenance in a background thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `on_error` - Callback invoked with errors from tasks.
+    pub fn spawn<F>(mut self, mut on_error: F) -> Result<Worker>
+    where
+        F: FnMut(Error) + Send + 'static,
+    {
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = shutdown.clone();
+
+        let handle = thread::Builder::new()
+            .name("arrow-maintenance".to_string())
+            .spawn(move || {
+                let (lock, condvar) = &*signal;
+                loop {
+                    // Sleep until the next task is due, or until asked to shutdown.
+                    let closed = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    let timeout = self.next_due().saturating_duration_since(Instant::now());
+                    let (closed, _) = condvar
+                        .wait_timeout_while(closed, timeout, |closed| !*closed)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+                    if *closed {
+                        break;
+                    }
+
+                    // Tasks run without holding the lock, so shutdown is never blocked by them.
+                    drop(closed);
+                    if let Err(error) = self.tick() {
+                        on_error(error);
+                    }
+                }
+
+                // Run all tasks one last time during shutdown.
+                self.flush()
+            })?;
+
+        Ok(Worker {
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Run tasks that match a predicate.
+    ///
+    /// # Arguments
+    ///
+    /// * `predicate` - Returns true for tasks that should run.
+    fn run<P: Fn(&Task) -> bool>(&mut self, predicate: P) -> Result<()> {
+        let mut result = Ok(());
+        for task in self.tasks.iter_mut().filter(|task| predicate(task)) {
+            task.due = Instant::now() + task.interval;
+            if let Err(error) = (task.run)() {
+                let error = Error::new(error.kind(), format!("{}: {error}", task.name));
+                result = result.and(Err(error));
+            }
+        }
+
+        result
+    }
+
+    /// When the next task is due.
+    fn next_due(&self) -> Instant {
+        let later = Instant::now() + Duration::from_secs(3600);
+        self.tasks
+            .iter()
+            .map(|task| task.due)
+            .min()
+            .unwrap_or(later)
+    }
+}
+
+/// Background thread running maintenance tasks.
+///
+/// Dropping the worker stops the thread, but errors from the final run of tasks
+/// are lost. Use [`Worker::close`] to observe them.
+pub struct Worker {
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl Worker {
+    /// Gracefully shutdown the worker.
+    ///
+    /// Waits for the worker to run all tasks one last time, and returns the
+    /// first error from that run, if any.
+    pub fn close(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    /// Signal the thread to stop and wait for it to exit.
+    fn stop(&mut self) -> Result<()> {
+        let Some(handle) = self.handle.take() else {
+            return Ok(());
+        };
+
+        let (lock, condvar) = &*self.shutdown;
+        *lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        condvar.notify_all();
+
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(Error::other("Maintenance thread panicked")))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use std::{
+        io::ErrorKind,
+        sync::atomic::{AtomicUsize, Ordering::*},
+    };
+
+    fn counter() -> (
+        Arc<AtomicUsize>,
+        impl FnMut() -> Result<()> + Send + 'static,
+    ) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let task_count = count.clone();
+        let task = move || {
+            task_count.fetch_add(1, Relaxed);
+            Ok(())
+        };
+
+        (count, task)
+    }
+
+    #[test]
+    fn tick_runs_only_due_tasks() -> Result<()> {
+        let (often, often_task) = counter();
+        let (rarely, rarely_task) = counter();
+        let mut maintenance = Maintenance::new()
+            .with_task("often", Duration::ZERO, often_task)
+            .with_task("rarely", Duration::from_secs(3600), rarely_task);
+
+        maintenance.tick()?;
+        maintenance.tick()?;
+        assert_eq!(2, often.load(Relaxed));
+        assert_eq!(0, rarely.load(Relaxed));
+
+        // Flush runs everything.
+        maintenance.flush()?;
+        assert_eq!(3, often.load(Relaxed));
+        assert_eq!(1, rarely.load(Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn tick_runs_all_tasks_and_returns_error() {
+        let (count, task) = counter();
+        let mut maintenance = Maintenance::new()
+            .with_task("sync", Duration::ZERO, || Err(Error::other("disk on fire")))
+            .with_task("count", Duration::ZERO, task);
+
+        let error = maintenance.tick().unwrap_err();
+        assert_eq!("sync: disk on fire", error.to_string());
+        assert_eq!(1, count.load(Relaxed));
+    }
+
+    #[test]
+    fn worker_runs_tasks_in_background() -> Result<()> {
+        let (count, task) = counter();
+        let worker = Maintenance::new()
+            .with_task("count", Duration::from_millis(1), task)
+            .spawn(|_| {})?;
+
+        // Wait for the task to run a few times.
+        while count.load(Relaxed) < 3 {
+            thread::yield_now();
+        }
+
+        // Closing runs tasks one more time.
+        worker.close()?;
+        let closed = count.load(Relaxed);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(closed, count.load(Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn worker_runs_tasks_without_holding_lock() -> Result<()> {
+        let (started, finished) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (task_started, task_finished) = (started.clone(), finished.clone());
+        let worker = Maintenance::new()
+            .with_task("slow", Duration::from_millis(1), move || {
+                task_started.fetch_add(1, Relaxed);
+                thread::sleep(Duration::from_millis(50));
+                task_finished.fetch_add(1, Relaxed);
+                Ok(())
+            })
+            .spawn(|_| {})?;
+
+        while started.load(Relaxed) == 0 {
+            thread::yield_now();
+        }
+
+        // Shutdown can be signalled while the task is still running.
+        assert!(worker.shutdown.0.try_lock().is_ok());
+        assert_eq!(0, finished.load(Relaxed));
+
+        // Closing waits for the running task, and runs it one last time.
+        worker.close()?;
+        assert_eq!(started.load(Relaxed), finished.load(Relaxed));
+        Ok(())
+    }
+
+    #[test]
+    fn worker_reports_errors() -> Result<()> {
+        let errors = Arc::new(AtomicUsize::new(0));
+        let on_error_errors = errors.clone();
+        let worker = Maintenance::new()
+            .with_task("sync", Duration::from_millis(1), || {
+                Err(Error::new(ErrorKind::StorageFull, "disk full"))
+            })
+            .spawn(move |error| {
+                assert_eq!(ErrorKind::StorageFull, error.kind());
+                on_error_errors.fetch_add(1, Relaxed);
+            })?;
+
+        // Errors should be reported through callback.
+        while errors.load(Relaxed) < 2 {
+            thread::yield_now();
+        }
+
+        // And from the final run during close.
+        assert!(worker.close().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,750 @@
+//! Manifest of segments in a ring buffer directory.
+
+use crate::{
+    cursor::Cursor,
+    metrics,
+    seal::{self, Footer},
+    storage::{Storage, Writer},
+};
+use crc32fast::Hasher;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+};
+
+/// Magic bytes at the start of every manifest.
+const MAGIC: [u8; 8] = *b"ARROWMAN";
+
+/// Number of bytes used to record a segment in the manifest.
+const SEGMENT_SIZE: usize = 8 + 8 + 8 + 1;
+
+/// Segments of a ring buffer, durably recorded in its directory.
+///
+/// Segments are files named after the sequence number of their first log record.
+/// Every segment is sealed, except for the newest one that is being appended into.
+///
+/// # Durability
+///
+/// Manifest is replaced atomically, the same way as positions of a
+/// [`Consumer`](crate::offsets::Consumer). Changes are ordered such that a crash
+/// at any point leaves behind at most some extra files that are not in the manifest:
+///
+/// * On roll, active segment is sealed and synced before it is recorded as sealed.
+/// * On roll, new segment is created before it is added to the manifest.
+/// * On reclaim, segments are removed from the manifest before they are deleted.
+/// * On truncate, newer segments are removed from the manifest before they are deleted.
+///
+/// Log records should only be appended into a segment after roll completes.
+///
+/// # Repair
+///
+/// Opening the manifest reconciles it with files in the directory. Leftovers from
+/// an interrupted roll or truncation (segments newer than the active one) or
+/// reclamation (segments older than the oldest one) are deleted. Anything else that
+/// does not match is reported as an error rather than guessed at.
+pub struct Manifest {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+}
+
+/// A segment recorded in the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Sequence number of the first log record in the segment.
+    pub base: u64,
+
+    /// Position of the segment in the ring buffer, the number of bytes in every
+    /// segment before it, including reclaimed ones.
+    pub offset: u64,
+
+    /// Number of bytes in the segment, as of the last time manifest was opened or
+    /// updated. Active segment can grow beyond this.
+    pub len: u64,
+
+    /// State of the segment.
+    pub state: State,
+}
+
+/// State of a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Segment no longer changes.
+    Sealed,
+
+    /// Segment is being appended into.
+    Active,
+}
+
+/// Repairs made when opening a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Repair {
+    /// Leftover files that were deleted.
+    pub removed: Vec<PathBuf>,
+
+    /// True if there was no manifest, and it was rebuilt from segments on disk.
+    pub rebuilt: bool,
+}
+
+impl Manifest {
+    /// Name of the manifest file.
+    const NAME: &str = "MANIFEST";
+
+    /// Name of the temporary file used to replace manifest.
+    const TMP_NAME: &str = "MANIFEST.tmp";
+
+    /// Extension of segment files.
+    const EXTENSION: &str = "segment";
+
+    /// Open manifest of a ring buffer directory, repairing leftovers.
+    ///
+    /// If there is no manifest yet, it is rebuilt from segments in the directory.
+    /// Newest of them is active, the rest are sealed.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory of the ring buffer.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip_all, fields(dir = %dir.as_ref().display()), err)
+    )]
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<(Self, Repair)> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut repair = Repair::default();
+
+        // Leftover from an interrupted update of the manifest.
+        let tmp_path = dir.join(Self::TMP_NAME);
+        match fs::remove_file(&tmp_path) {
+            Ok(()) => {
+                event!(warn, path = %tmp_path.display(), "Removed interrupted update of manifest");
+                repair.removed.push(tmp_path);
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+
+        // Segments that actually exist on disk.
+        let mut files = BTreeMap::new();
+        for (base, path) in Self::list(&dir)? {
+            files.insert(base, fs::metadata(&path)?.len());
+        }
+
+        let mut manifest = Self {
+            dir,
+            segments: Vec::new(),
+        };
+
+        // Rebuild manifest from disk when there isn't one.
+        let bytes = match fs::read(manifest.dir.join(Self::NAME)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                // Positions of segments reclaimed before are lost with the manifest.
+                let mut offset = 0;
+                let mut segments: Vec<_> = files
+                    .into_iter()
+                    .map(|(base, len)| {
+                        offset += len;
+                        Segment {
+                            base,
+                            len,
+                            offset: offset - len,
+                            state: State::Sealed,
+                        }
+                    })
+                    .collect();
+
+                if let Some(active) = segments.last_mut() {
+                    active.state = State::Active;
+                }
+
+                manifest.write(&segments)?;
+                event!(
+                    warn,
+                    segments = segments.len(),
+                    "Rebuilt missing manifest from segments"
+                );
+                manifest.segments = segments;
+                repair.rebuilt = true;
+                return Ok((manifest, repair));
+            }
+            Err(error) => return Err(error),
+        };
+
+        // Every segment in the manifest must exist on disk.
+        let mut segments = Self::decode(&bytes)?;
+        for segment in &mut segments {
+            let Some(len) = files.remove(&segment.base) else {
+                let path = manifest.path(segment.base);
+                let error = format!("Segment is missing: {}", path.display());
+                return Err(Error::new(ErrorKind::NotFound, error));
+            };
+
+            match segment.state {
+                State::Active => segment.len = len,
+                State::Sealed if segment.len == len => {}
+                State::Sealed => {
+                    let path = manifest.path(segment.base);
+                    let error = format!("Sealed segment changed size: {}", path.display());
+                    return Err(Error::new(ErrorKind::InvalidData, error));
+                }
+            }
+        }
+
+        // Delete leftovers from interrupted roll, truncation or reclamation.
+        manifest.segments = segments;
+        let first = manifest.segments.first().map(|segment| segment.base);
+        let last = manifest.segments.last().map(|segment| segment.base);
+        for base in files.into_keys() {
+            let path = manifest.path(base);
+            let reclaimed = first.is_some_and(|first| base < first);
+            let newer = last.is_none_or(|last| base > last);
+            if !reclaimed && !newer {
+                let error = format!("Segment is not in manifest: {}", path.display());
+                return Err(Error::new(ErrorKind::InvalidData, error));
+            }
+
+            fs::remove_file(&path)?;
+            event!(warn, path = %path.display(), reclaimed, "Removed leftover segment");
+            repair.removed.push(path);
+        }
+
+        Ok((manifest, repair))
+    }
+
+    /// Segment files in a directory, oldest first.
+    ///
+    /// Manifest is neither read nor repaired, so this is safe to use on the directory
+    /// of a ring buffer that is open elsewhere.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory of the ring buffer.
+    pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<(u64, PathBuf)>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != Self::EXTENSION) {
+                continue;
+            }
+
+            let base = path.file_stem().and_then(|stem| stem.to_str());
+            if let Some(base) = base.and_then(|base| base.parse::<u64>().ok()) {
+                segments.push((base, path));
+            }
+        }
+
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Directory of the ring buffer.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Segments in the manifest, oldest first.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Segment being appended into, if any.
+    pub fn active(&self) -> Option<&Segment> {
+        self.segments.last()
+    }
+
+    /// Path to a segment file.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Sequence number of the first log record in the segment.
+    pub fn path(&self, base: u64) -> PathBuf {
+        self.dir.join(format!("{base:020}.{}", Self::EXTENSION))
+    }
+
+    /// Seal the active segment, and start a new one.
+    ///
+    /// Returns storage of the new segment, which is now active. Active segment is
+    /// sealed, and with that synced to disk, before the manifest records it as sealed.
+    /// If it was already sealed by an interrupted roll, it is not sealed again.
+    ///
+    /// # Arguments
+    ///
+    /// * `active` - Cursor at the start of the active segment, and its writer. Required
+    ///   if there is an active segment.
+    /// * `base` - Sequence number of the first log record in the new segment.
+    pub fn roll(
+        &mut self,
+        active: Option<(&mut Cursor<'_>, &mut Writer)>,
+        base: u64,
+    ) -> Result<Storage> {
+        if self.active().is_some_and(|active| active.base >= base) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Segments must have increasing base"));
+        }
+
+        // Seal the active segment with its final size.
+        let mut segments = self.segments.clone();
+        if let Some(segment) = segments.last_mut() {
+            let Some((cursor, writer)) = active else {
+                let kind = ErrorKind::InvalidInput;
+                return Err(Error::new(kind, "Active segment must be sealed on roll"));
+            };
+
+            let storage = cursor.storage();
+            if storage.path() != self.path(segment.base) {
+                let kind = ErrorKind::InvalidInput;
+                return Err(Error::new(kind, "Cursor must read the active segment"));
+            }
+
+            if Footer::read(storage)?.is_none() {
+                seal::seal(cursor, writer)?;
+            }
+
+            segment.len = storage.len();
+            segment.state = State::Sealed;
+        }
+
+        // Segment is created before it is recorded in the manifest.
+        let storage = Storage::create(self.path(base))?;
+        let offset = segments
+            .last()
+            .map_or(0, |active| active.offset + active.len);
+        segments.push(Segment {
+            base,
+            offset,
+            len: 0,
+            state: State::Active,
+        });
+
+        self.write(&segments)?;
+        self.segments = segments;
+        metrics::record(|recorder| recorder.rolled());
+        event!(info, base, "Rolled segment");
+        Ok(storage)
+    }
+
+    /// Reclaim oldest segments.
+    ///
+    /// Active segment is never reclaimed, even if asked to.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - Number of oldest segments to reclaim.
+    pub fn reclaim(&mut self, count: usize) -> Result<()> {
+        let count = count.min(self.segments.len().saturating_sub(1));
+        if count == 0 {
+            return Ok(());
+        }
+
+        // Segments are removed from the manifest before they are deleted.
+        let segments = self.segments.split_off(count);
+        self.write(&segments)?;
+        let reclaimed = std::mem::replace(&mut self.segments, segments);
+        for segment in reclaimed {
+            match fs::remove_file(self.path(segment.base)) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+
+        metrics::record(|recorder| recorder.reclaimed(count as u64));
+        event!(info, count, "Reclaimed segments");
+        Ok(())
+    }
+
+    /// Remove segments newer than a segment, which becomes the active one again.
+    ///
+    /// Segments are removed from the manifest before they are deleted. Log records
+    /// after the sequence number to truncate at are left for the caller to remove
+    /// from the segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Sequence number of the first log record in the segment.
+    pub fn truncate(&mut self, base: u64) -> Result<()> {
+        let Some(index) = self
+            .segments
+            .iter()
+            .position(|segment| segment.base == base)
+        else {
+            let error = format!("Segment is not in manifest: {}", self.path(base).display());
+            return Err(Error::new(ErrorKind::NotFound, error));
+        };
+
+        let mut segments = self.segments[..=index].to_vec();
+        segments[index].state = State::Active;
+        self.write(&segments)?;
+
+        let removed = std::mem::replace(&mut self.segments, segments).split_off(index + 1);
+        for segment in &removed {
+            match fs::remove_file(self.path(segment.base)) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+                _ => {}
+            }
+        }
+
+        event!(info, base, count = removed.len(), "Truncated segments");
+        Ok(())
+    }
+
+    /// Atomically replace manifest on disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` - Segments to record in the manifest.
+    fn write(&self, segments: &[Segment]) -> Result<()> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 8 + SEGMENT_SIZE * segments.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&(segments.len() as u64).to_be_bytes());
+        for segment in segments {
+            bytes.extend_from_slice(&segment.base.to_be_bytes());
+            bytes.extend_from_slice(&segment.offset.to_be_bytes());
+            bytes.extend_from_slice(&segment.len.to_be_bytes());
+            bytes.push(match segment.state {
+                State::Sealed => 0,
+                State::Active => 1,
+            });
+        }
+
+        let mut hasher = Hasher::new();
+        hasher.update(&bytes);
+        bytes.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+        // Write new manifest into a temporary file.
+        let tmp_path = self.dir.join(Self::TMP_NAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+
+        // Atomically replace the previous manifest.
+        fs::rename(&tmp_path, self.dir.join(Self::NAME))?;
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Parse segments recorded in a manifest.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Bytes of the manifest.
+    fn decode(bytes: &[u8]) -> Result<Vec<Segment>> {
+        let corrupted = || Error::new(ErrorKind::InvalidData, "Corrupted manifest");
+        let read_u64 =
+            |bytes: &[u8]| u64::from_be_bytes(bytes.try_into().expect("Should never fail"));
+
+        // Verify checksum before anything else.
+        let Some((bytes, checksum)) = bytes.split_last_chunk::<4>() else {
+            return Err(corrupted());
+        };
+
+        let mut hasher = Hasher::new();
+        hasher.update(bytes);
+        if hasher.finalize() != u32::from_be_bytes(*checksum) {
+            return Err(corrupted());
+        }
+
+        let Some(bytes) = bytes.strip_prefix(&MAGIC) else {
+            return Err(corrupted());
+        };
+
+        let Some((count, bytes)) = bytes.split_first_chunk::<8>() else {
+            return Err(corrupted());
+        };
+
+        if u64::from_be_bytes(*count) != (bytes.len() / SEGMENT_SIZE) as u64
+            || bytes.len() % SEGMENT_SIZE != 0
+        {
+            return Err(corrupted());
+        }
+
+        let mut segments = Vec::new();
+        for bytes in bytes.chunks_exact(SEGMENT_SIZE) {
+            let state = match bytes[24] {
+                0 => State::Sealed,
+                1 => State::Active,
+                _ => return Err(corrupted()),
+            };
+
+            segments.push(Segment {
+                state,
+                base: read_u64(&bytes[..8]),
+                offset: read_u64(&bytes[8..16]),
+                len: read_u64(&bytes[16..24]),
+            });
+        }
+
+        Ok(segments)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        buf::LogBuf,
+        log::Log,
+    };
+    use anyhow::{Result, anyhow};
+    use tempfile::tempdir;
+
+    fn append(storage: &Storage, seq_no: u64) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(64);
+        assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+
+        let mut block = Block::new(Codec::default());
+        block.encode(&logs)?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+        };
+
+        Ok(())
+    }
+
+    fn roll(manifest: &mut Manifest, active: Option<&Storage>, base: u64) -> Result<Storage> {
+        let Some(active) = active else {
+            return Ok(manifest.roll(None, base)?);
+        };
+
+        let mut cursor = Cursor::new(active, 0);
+        match active.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => Ok(manifest.roll(Some((&mut cursor, &mut writer)), base)?),
+        }
+    }
+
+    #[test]
+    fn roll_survives_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, repair) = Manifest::open(dir.path())?;
+        assert!(repair.rebuilt);
+        assert!(manifest.segments().is_empty());
+
+        // Roll a few segments.
+        let storage = roll(&mut manifest, None, 1)?;
+        append(&storage, 1)?;
+        let active = roll(&mut manifest, Some(&storage), 100)?;
+        append(&active, 100)?;
+        assert!(roll(&mut manifest, Some(&active), 100).is_err());
+
+        // Rolled segment is sealed, including its footer.
+        let footer = Footer::read(&storage)?.ok_or(anyhow!("Should be sealed"))?;
+        assert_eq!(Some(1), footer.first());
+        let expected = [
+            Segment {
+                base: 1,
+                offset: 0,
+                len: storage.len(),
+                state: State::Sealed,
+            },
+            Segment {
+                base: 100,
+                offset: storage.len(),
+                len: active.len(),
+                state: State::Active,
+            },
+        ];
+
+        // Size of active segment is refreshed on open.
+        let (manifest, repair) = Manifest::open(dir.path())?;
+        assert_eq!(Repair::default(), repair);
+        assert_eq!(&expected, manifest.segments());
+        assert_eq!(Some(&expected[1]), manifest.active());
+        Ok(())
+    }
+
+    #[test]
+    fn roll_requires_active_segment() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, _) = Manifest::open(dir.path())?;
+        let storage = roll(&mut manifest, None, 1)?;
+        let other = Storage::create(dir.path().join("other.storage"))?;
+
+        // Active segment must be sealed, not some other storage.
+        let error = manifest.roll(None, 100).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        let error = roll(&mut manifest, Some(&other), 100).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.downcast::<Error>()?.kind());
+        assert_eq!(1, manifest.segments().len());
+
+        // Segment sealed by an interrupted roll is not sealed again.
+        append(&storage, 1)?;
+        let mut cursor = Cursor::new(&storage, 0);
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => seal::seal(&mut cursor, &mut writer)?,
+        };
+
+        roll(&mut manifest, Some(&storage), 100)?;
+        assert_eq!(State::Sealed, manifest.segments()[0].state);
+        assert_eq!(storage.len(), manifest.segments()[0].len);
+        Ok(())
+    }
+
+    #[test]
+    fn list_returns_segment_files() -> Result<()> {
+        let dir = tempdir()?;
+        let (manifest, _) = Manifest::open(dir.path())?;
+        for base in [100, 1, 20] {
+            fs::write(manifest.path(base), b"")?;
+        }
+
+        fs::write(dir.path().join("audit.offset"), b"")?;
+        fs::write(dir.path().join("batman.segment"), b"")?;
+
+        let bases: Vec<_> = Manifest::list(dir.path())?
+            .into_iter()
+            .map(|(base, path)| {
+                assert_eq!(manifest.path(base), path);
+                base
+            })
+            .collect();
+
+        assert_eq!(vec![1, 20, 100], bases);
+        Ok(())
+    }
+
+    #[test]
+    fn reclaim_deletes_oldest_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, _) = Manifest::open(dir.path())?;
+        let mut active = None;
+        for base in [1, 100, 200] {
+            let storage = roll(&mut manifest, active.as_ref(), base)?;
+            append(&storage, base)?;
+            active = Some(storage);
+        }
+
+        // Active segment is never reclaimed, and keeps its position.
+        let offset = manifest.segments()[0].len + manifest.segments()[1].len;
+        assert_eq!(Some(offset), manifest.active().map(|active| active.offset));
+        manifest.reclaim(5)?;
+        assert!(!manifest.path(1).exists());
+        assert!(!manifest.path(100).exists());
+        assert!(manifest.path(200).exists());
+
+        let (manifest, _) = Manifest::open(dir.path())?;
+        assert_eq!(1, manifest.segments().len());
+        assert_eq!(Some(200), manifest.active().map(|active| active.base));
+        assert_eq!(Some(offset), manifest.active().map(|active| active.offset));
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_deletes_newer_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, _) = Manifest::open(dir.path())?;
+        let storage = roll(&mut manifest, None, 1)?;
+        append(&storage, 1)?;
+        let storage = roll(&mut manifest, Some(&storage), 100)?;
+        append(&storage, 100)?;
+        roll(&mut manifest, Some(&storage), 200)?;
+
+        // Truncated segment is active again.
+        manifest.truncate(100)?;
+        let bases: Vec<_> = manifest
+            .segments()
+            .iter()
+            .map(|segment| segment.base)
+            .collect();
+        assert_eq!(vec![1, 100], bases);
+        assert_eq!(
+            Some(State::Active),
+            manifest.active().map(|active| active.state)
+        );
+        assert!(!manifest.path(200).exists());
+
+        // Segments that are not in the manifest cannot be truncated at.
+        let error = manifest.truncate(200).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, error.kind());
+
+        let (manifest, repair) = Manifest::open(dir.path())?;
+        assert!(repair.removed.is_empty());
+        assert_eq!(2, manifest.segments().len());
+        Ok(())
+    }
+
+    #[test]
+    fn open_repairs_interrupted_changes() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, _) = Manifest::open(dir.path())?;
+        roll(&mut manifest, None, 100)?;
+
+        // Crash after reclaim updated manifest, but before deleting files.
+        fs::write(manifest.path(1), b"Batman")?;
+
+        // Crash after roll created segment, but before updating manifest.
+        fs::write(manifest.path(200), b"")?;
+
+        // Crash after truncate updated manifest, but before deleting files.
+        fs::write(manifest.path(300), b"Robin")?;
+
+        // Crash midway through updating manifest.
+        fs::write(dir.path().join(Manifest::TMP_NAME), b"Joker")?;
+
+        let (manifest, repair) = Manifest::open(dir.path())?;
+        assert_eq!(4, repair.removed.len());
+        assert_eq!(1, manifest.segments().len());
+        assert!(!manifest.path(1).exists());
+        assert!(!manifest.path(200).exists());
+        assert!(!manifest.path(300).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn open_without_manifest_rebuilds_it() -> Result<()> {
+        let dir = tempdir()?;
+        let (manifest, _) = Manifest::open(dir.path())?;
+        fs::write(manifest.path(1), b"Batman")?;
+        fs::write(manifest.path(100), b"Robin")?;
+        fs::remove_file(dir.path().join(Manifest::NAME))?;
+
+        let (manifest, repair) = Manifest::open(dir.path())?;
+        assert!(repair.rebuilt);
+        assert_eq!(State::Sealed, manifest.segments()[0].state);
+        assert_eq!(State::Active, manifest.segments()[1].state);
+        Ok(())
+    }
+
+    #[test]
+    fn open_inconsistent_directory_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let (mut manifest, _) = Manifest::open(dir.path())?;
+        let storage = roll(&mut manifest, None, 1)?;
+        append(&storage, 1)?;
+        roll(&mut manifest, Some(&storage), 100)?;
+
+        // Segments not in the manifest, in between segments that are, are not deleted.
+        fs::write(manifest.path(50), b"Robin")?;
+        let error = Manifest::open(dir.path()).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        fs::remove_file(manifest.path(50))?;
+
+        // Sealed segments must not change, even if made writable again.
+        fs::remove_file(manifest.path(1))?;
+        fs::write(manifest.path(1), b"Joker")?;
+        let error = Manifest::open(dir.path()).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        // Segments in the manifest must exist.
+        fs::remove_file(manifest.path(1))?;
+        let error = Manifest::open(dir.path()).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, error.kind());
+
+        // Manifest must not be corrupted.
+        let path = dir.path().join(Manifest::NAME);
+        let mut bytes = fs::read(&path)?;
+        bytes[10] ^= 1;
+        fs::write(&path, bytes)?;
+        let error = Manifest::open(dir.path()).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        Ok(())
+    }
+}
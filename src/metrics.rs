@@ -0,0 +1,586 @@
+//! Metrics about what storage is doing.
+//!
+//! Storage, manifests, consumers and locks report what they do to a process wide
+//! [`Recorder`], installed once with [`set_recorder`]. Until then reporting costs
+//! a single atomic load, and nothing is measured.
+//!
+//! Counters add up what every ring buffer in the process does. Lag of consumers is
+//! reported along with their directory, usually that of the ring buffer they consume,
+//! so consumers with the same name in different rings are told apart.
+//!
+//! Use [`Stats`] to keep metrics in memory and take a [`Snapshot`] of them, or
+//! `MetricsRecorder` with the `metrics` feature to forward them to the `metrics` crate.
+
+use crate::retention::Reason;
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering::*},
+    },
+    time::{Duration, Instant},
+};
+
+/// Recorder installed for the process, if any.
+static RECORDER: OnceLock<Box<dyn Recorder>> = OnceLock::new();
+
+/// Upper bounds of buckets of the sync latency histogram.
+const SYNC_BUCKETS: [Duration; 12] = [
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_micros(2500),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_secs(1),
+];
+
+/// Receives metrics as they happen.
+///
+/// Every method does nothing by default, implement the ones of interest. Methods are
+/// called inline, from whichever thread is doing the work, so they should be quick.
+pub trait Recorder: Send + Sync {
+    /// Bytes were appended into storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Number of bytes appended.
+    fn appended_bytes(&self, bytes: u64) {
+        let _ = bytes;
+    }
+
+    /// A block of log records was appended into storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `records` - Number of log records in the block.
+    fn appended_records(&self, records: u64) {
+        let _ = records;
+    }
+
+    /// Storage was synced to disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `latency` - Time it took to sync.
+    fn synced(&self, latency: Duration) {
+        let _ = latency;
+    }
+
+    /// A new segment was rolled.
+    fn rolled(&self) {}
+
+    /// Oldest segments were reclaimed.
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` - Number of segments reclaimed.
+    fn reclaimed(&self, segments: u64) {
+        let _ = segments;
+    }
+
+    /// Oldest segments were reclaimed by a [`Retention`](crate::retention::Retention) policy.
+    ///
+    /// These are also reported with [`Recorder::reclaimed`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Limit of the policy that was exceeded.
+    /// * `segments` - Number of segments reclaimed for the reason.
+    fn reclaimed_by(&self, reason: Reason, segments: u64) {
+        let _ = (reason, segments);
+    }
+
+    /// Lag of a consumer was measured.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory of the consumer, usually that of the ring buffer.
+    /// * `consumer` - Name of the consumer.
+    /// * `records` - Number of log records the consumer is behind by.
+    fn lag(&self, dir: &Path, consumer: &str, records: u64) {
+        let _ = (dir, consumer, records);
+    }
+
+    /// A corrupted region of storage was found during recovery.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - Number of bytes in the region.
+    fn corrupted(&self, bytes: u64) {
+        let _ = bytes;
+    }
+
+    /// An attempt to obtain a [`MutLock`](crate::lock::MutLock), or the writer of storage,
+    /// failed because it was held.
+    fn contended(&self) {}
+}
+
+impl<R: Recorder + ?Sized> Recorder for Arc<R> {
+    fn appended_bytes(&self, bytes: u64) {
+        (**self).appended_bytes(bytes)
+    }
+
+    fn appended_records(&self, records: u64) {
+        (**self).appended_records(records)
+    }
+
+    fn synced(&self, latency: Duration) {
+        (**self).synced(latency)
+    }
+
+    fn rolled(&self) {
+        (**self).rolled()
+    }
+
+    fn reclaimed(&self, segments: u64) {
+        (**self).reclaimed(segments)
+    }
+
+    fn reclaimed_by(&self, reason: Reason, segments: u64) {
+        (**self).reclaimed_by(reason, segments)
+    }
+
+    fn lag(&self, dir: &Path, consumer: &str, records: u64) {
+        (**self).lag(dir, consumer, records)
+    }
+
+    fn corrupted(&self, bytes: u64) {
+        (**self).corrupted(bytes)
+    }
+
+    fn contended(&self) {
+        (**self).contended()
+    }
+}
+
+/// Install the recorder for the process.
+///
+/// Returns an error if a recorder is already installed, it cannot be replaced.
+/// Share a recorder with [`Arc`] to keep access to it, for example to take
+/// snapshots of [`Stats`].
+///
+/// # Arguments
+///
+/// * `recorder` - Recorder to report metrics to.
+pub fn set_recorder<R: Recorder + 'static>(recorder: R) -> Result<()> {
+    RECORDER.set(Box::new(recorder)).map_err(|_| {
+        let kind = ErrorKind::AlreadyExists;
+        Error::new(kind, "Recorder is already installed")
+    })
+}
+
+/// Report to the installed recorder, if any.
+///
+/// # Arguments
+///
+/// * `report` - Reports metrics to the recorder.
+pub(crate) fn record<F: FnOnce(&dyn Recorder)>(report: F) {
+    if let Some(recorder) = RECORDER.get() {
+        report(recorder.as_ref());
+    }
+}
+
+/// Start measuring time, only if there is someone to report it to.
+pub(crate) fn start() -> Option<Instant> {
+    RECORDER.get().map(|_| Instant::now())
+}
+
+/// Metrics kept in memory.
+#[derive(Default)]
+pub struct Stats {
+    appended_bytes: AtomicU64,
+    appended_records: AtomicU64,
+    syncs: [AtomicU64; SYNC_BUCKETS.len() + 1],
+    sync_nanos: AtomicU64,
+    rolled: AtomicU64,
+    reclaimed: AtomicU64,
+    reclaimed_by: [AtomicU64; 3],
+    lag: Mutex<BTreeMap<(PathBuf, String), u64>>,
+    corruptions: AtomicU64,
+    corrupted_bytes: AtomicU64,
+    contended: AtomicU64,
+}
+
+/// Point in time copy of [`Stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of bytes appended into storage.
+    pub appended_bytes: u64,
+
+    /// Number of log records appended into storage.
+    pub appended_records: u64,
+
+    /// Latency of syncs to disk.
+    pub syncs: Histogram,
+
+    /// Number of segments rolled.
+    pub rolled: u64,
+
+    /// Number of segments reclaimed.
+    pub reclaimed: u64,
+
+    /// Number of segments reclaimed because of their age.
+    pub reclaimed_by_age: u64,
+
+    /// Number of segments reclaimed because of size of the ring buffer.
+    pub reclaimed_by_bytes: u64,
+
+    /// Number of segments reclaimed because of number of log records.
+    pub reclaimed_by_records: u64,
+
+    /// Last measured lag of consumers, in log records, by directory and name.
+    pub lag: BTreeMap<(PathBuf, String), u64>,
+
+    /// Number of corrupted regions found during recovery.
+    pub corruptions: u64,
+
+    /// Number of bytes in corrupted regions found during recovery.
+    pub corrupted_bytes: u64,
+
+    /// Number of failed attempts to obtain a lock or the writer of storage, because it was held.
+    pub contended: u64,
+}
+
+/// Distribution of latencies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Number of measurements.
+    pub count: u64,
+
+    /// Sum of all measurements.
+    pub sum: Duration,
+
+    /// Upper bound of every bucket, along with the number of measurements in it.
+    /// Buckets are not cumulative, the last one is unbounded with [`Duration::MAX`].
+    pub buckets: Vec<(Duration, u64)>,
+}
+
+impl Stats {
+    /// Create metrics that are all zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy current values of metrics.
+    ///
+    /// Metrics are read one by one, those updated concurrently might be slightly
+    /// out of sync with each other.
+    pub fn snapshot(&self) -> Snapshot {
+        let bounds = SYNC_BUCKETS.iter().copied().chain([Duration::MAX]);
+        let buckets: Vec<_> = bounds
+            .zip(&self.syncs)
+            .map(|(bound, count)| (bound, count.load(Relaxed)))
+            .collect();
+
+        Snapshot {
+            appended_bytes: self.appended_bytes.load(Relaxed),
+            appended_records: self.appended_records.load(Relaxed),
+            syncs: Histogram {
+                count: buckets.iter().map(|(_, count)| count).sum(),
+                sum: Duration::from_nanos(self.sync_nanos.load(Relaxed)),
+                buckets,
+            },
+            rolled: self.rolled.load(Relaxed),
+            reclaimed: self.reclaimed.load(Relaxed),
+            reclaimed_by_age: self.reclaimed_by[0].load(Relaxed),
+            reclaimed_by_bytes: self.reclaimed_by[1].load(Relaxed),
+            reclaimed_by_records: self.reclaimed_by[2].load(Relaxed),
+            lag: self.lag.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+            corruptions: self.corruptions.load(Relaxed),
+            corrupted_bytes: self.corrupted_bytes.load(Relaxed),
+            contended: self.contended.load(Relaxed),
+        }
+    }
+}
+
+impl Recorder for Stats {
+    fn appended_bytes(&self, bytes: u64) {
+        self.appended_bytes.fetch_add(bytes, Relaxed);
+    }
+
+    fn appended_records(&self, records: u64) {
+        self.appended_records.fetch_add(records, Relaxed);
+    }
+
+    fn synced(&self, latency: Duration) {
+        let bucket = SYNC_BUCKETS.partition_point(|bound| *bound < latency);
+        self.syncs[bucket].fetch_add(1, Relaxed);
+
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.sync_nanos.fetch_add(nanos, Relaxed);
+    }
+
+    fn rolled(&self) {
+        self.rolled.fetch_add(1, Relaxed);
+    }
+
+    fn reclaimed(&self, segments: u64) {
+        self.reclaimed.fetch_add(segments, Relaxed);
+    }
+
+    fn reclaimed_by(&self, reason: Reason, segments: u64) {
+        let index = match reason {
+            Reason::Age => 0,
+            Reason::Bytes => 1,
+            Reason::Records => 2,
+        };
+
+        self.reclaimed_by[index].fetch_add(segments, Relaxed);
+    }
+
+    fn lag(&self, dir: &Path, consumer: &str, records: u64) {
+        let mut lag = self.lag.lock().unwrap_or_else(|e| e.into_inner());
+        lag.insert((dir.to_path_buf(), consumer.to_string()), records);
+    }
+
+    fn corrupted(&self, bytes: u64) {
+        self.corruptions.fetch_add(1, Relaxed);
+        self.corrupted_bytes.fetch_add(bytes, Relaxed);
+    }
+
+    fn contended(&self) {
+        self.contended.fetch_add(1, Relaxed);
+    }
+}
+
+/// Forwards metrics to the `metrics` crate, under names prefixed with `arrow_`.
+///
+/// Lag is a gauge labelled with the directory and name of the consumer, sync latency
+/// a histogram in seconds, everything else is a counter.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsRecorder;
+
+#[cfg(feature = "metrics")]
+impl Recorder for MetricsRecorder {
+    fn appended_bytes(&self, bytes: u64) {
+        ::metrics::counter!("arrow_appended_bytes").increment(bytes);
+    }
+
+    fn appended_records(&self, records: u64) {
+        ::metrics::counter!("arrow_appended_records").increment(records);
+    }
+
+    fn synced(&self, latency: Duration) {
+        ::metrics::histogram!("arrow_sync_seconds").record(latency.as_secs_f64());
+    }
+
+    fn rolled(&self) {
+        ::metrics::counter!("arrow_segments_rolled").increment(1);
+    }
+
+    fn reclaimed(&self, segments: u64) {
+        ::metrics::counter!("arrow_segments_reclaimed").increment(segments);
+    }
+
+    fn reclaimed_by(&self, reason: Reason, segments: u64) {
+        let reason = match reason {
+            Reason::Age => "age",
+            Reason::Bytes => "bytes",
+            Reason::Records => "records",
+        };
+
+        ::metrics::counter!("arrow_segments_reclaimed_by", "reason" => reason).increment(segments);
+    }
+
+    fn lag(&self, dir: &Path, consumer: &str, records: u64) {
+        let labels = [
+            ("dir", dir.display().to_string()),
+            ("consumer", consumer.to_string()),
+        ];
+
+        ::metrics::gauge!("arrow_consumer_lag", &labels).set(records as f64);
+    }
+
+    fn corrupted(&self, bytes: u64) {
+        ::metrics::counter!("arrow_corruptions").increment(1);
+        ::metrics::counter!("arrow_corrupted_bytes").increment(bytes);
+    }
+
+    fn contended(&self) {
+        ::metrics::counter!("arrow_lock_contended").increment(1);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        buf::LogBuf,
+        log::Log,
+        offsets::Consumer,
+        storage::Storage,
+    };
+    use std::{
+        fs,
+        thread::{self, ThreadId},
+    };
+    use tempfile::tempdir;
+
+    /// Forwards metrics reported from a single thread, other tests report concurrently.
+    struct Only(ThreadId, Arc<Stats>);
+
+    impl Only {
+        fn stats(&self) -> Option<&Stats> {
+            (thread::current().id() == self.0).then_some(&*self.1)
+        }
+    }
+
+    impl Recorder for Only {
+        fn appended_bytes(&self, bytes: u64) {
+            self.stats().inspect(|stats| stats.appended_bytes(bytes));
+        }
+
+        fn appended_records(&self, records: u64) {
+            self.stats()
+                .inspect(|stats| stats.appended_records(records));
+        }
+
+        fn synced(&self, latency: Duration) {
+            self.stats().inspect(|stats| stats.synced(latency));
+        }
+
+        fn rolled(&self) {
+            self.stats().inspect(|stats| stats.rolled());
+        }
+
+        fn reclaimed(&self, segments: u64) {
+            self.stats().inspect(|stats| stats.reclaimed(segments));
+        }
+
+        fn reclaimed_by(&self, reason: Reason, segments: u64) {
+            self.stats()
+                .inspect(|stats| stats.reclaimed_by(reason, segments));
+        }
+
+        fn lag(&self, dir: &Path, consumer: &str, records: u64) {
+            self.stats()
+                .inspect(|stats| stats.lag(dir, consumer, records));
+        }
+
+        fn corrupted(&self, bytes: u64) {
+            self.stats().inspect(|stats| stats.corrupted(bytes));
+        }
+
+        fn contended(&self) {
+            self.stats().inspect(|stats| stats.contended());
+        }
+    }
+
+    #[test]
+    fn installed_recorder_receives_metrics() -> anyhow::Result<()> {
+        let stats = Arc::new(Stats::new());
+        set_recorder(Only(thread::current().id(), stats.clone()))?;
+        assert!(set_recorder(Stats::new()).is_err());
+
+        let dir = tempdir()?;
+        let storage = Storage::create(dir.path().join("test.storage"))?;
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in 1..=10 {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        block.encode(&logs)?;
+        let mut writer = storage.writer().expect("Should obtain writer");
+        storage.append_block(block.bytes(), logs.count(), &mut writer)?;
+        storage.sync()?;
+
+        // Writer is held, so obtaining it again is contended.
+        assert!(storage.writer().is_none());
+
+        // Consumers with the same name in different directories are kept apart.
+        let (batman, joker) = (dir.path().join("batman"), dir.path().join("joker"));
+        for (dir, lag) in [(&batman, 7), (&joker, 3)] {
+            fs::create_dir(dir)?;
+            Consumer::open(dir, "audit")?.lag(Some(1), Some(lag));
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(block.bytes().len() as u64, snapshot.appended_bytes);
+        assert_eq!(10, snapshot.appended_records);
+        assert_eq!(1, snapshot.syncs.count);
+        assert_eq!(1, snapshot.contended);
+        assert_eq!(
+            BTreeMap::from([
+                ((batman, "audit".to_string()), 7),
+                ((joker, "audit".to_string()), 3),
+            ]),
+            snapshot.lag
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_copies_metrics() {
+        let stats = Stats::new();
+        stats.appended_bytes(100);
+        stats.appended_bytes(20);
+        stats.appended_records(3);
+        stats.rolled();
+        stats.reclaimed(2);
+        stats.reclaimed_by(Reason::Bytes, 2);
+        stats.lag(Path::new("ring"), "batman", 10);
+        stats.lag(Path::new("ring"), "batman", 5);
+        stats.lag(Path::new("ring"), "joker", 7);
+        stats.lag(Path::new("other"), "joker", 2);
+        stats.corrupted(9);
+        stats.contended();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(120, snapshot.appended_bytes);
+        assert_eq!(3, snapshot.appended_records);
+        assert_eq!((1, 2), (snapshot.rolled, snapshot.reclaimed));
+        assert_eq!(
+            (0, 2, 0),
+            (
+                snapshot.reclaimed_by_age,
+                snapshot.reclaimed_by_bytes,
+                snapshot.reclaimed_by_records,
+            )
+        );
+        let lag =
+            |dir: &str, name: &str| snapshot.lag.get(&(dir.into(), name.to_string())).copied();
+        assert_eq!(Some(5), lag("ring", "batman"));
+        assert_eq!(Some(7), lag("ring", "joker"));
+        assert_eq!(Some(2), lag("other", "joker"));
+        assert_eq!((1, 9), (snapshot.corruptions, snapshot.corrupted_bytes));
+        assert_eq!(1, snapshot.contended);
+    }
+
+    #[test]
+    fn sync_latency_is_bucketed() {
+        let stats = Stats::new();
+        stats.synced(Duration::from_micros(50));
+        stats.synced(Duration::from_millis(1));
+        stats.synced(Duration::from_millis(3));
+        stats.synced(Duration::from_secs(10));
+
+        let syncs = stats.snapshot().syncs;
+        assert_eq!(4, syncs.count);
+        assert_eq!(Duration::from_micros(10_004_050), syncs.sum);
+
+        let count = |bound| {
+            syncs
+                .buckets
+                .iter()
+                .find(|(b, _)| *b == bound)
+                .map(|(_, c)| *c)
+        };
+        assert_eq!(Some(1), count(Duration::from_micros(100)));
+        assert_eq!(Some(1), count(Duration::from_millis(1)));
+        assert_eq!(Some(1), count(Duration::from_millis(5)));
+        assert_eq!(Some(1), count(Duration::MAX));
+        assert_eq!(SYNC_BUCKETS.len() + 1, syncs.buckets.len());
+    }
+}
@@ -0,0 +1,100 @@
+//! Building blocks for serving storage over TCP.
+
+use std::{
+    io::{Error, Result},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering::*},
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Background threads serving connections from a listener.
+///
+/// Dropping the server stops it, same as [`Server::close`].
+pub struct Server {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Serve connections from a listener, each from a thread of its own.
+    ///
+    /// Handlers are expected to return once the shutdown flag is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Listener to accept connections from.
+    /// * `name` - Name of the threads, suffixed with `-connection` for handlers.
+    /// * `handler` - Handles a connection, along with a flag set when the server stops.
+    pub(crate) fn spawn<F>(listener: TcpListener, name: &str, handler: F) -> Result<Self>
+    where
+        F: Fn(TcpStream, &AtomicBool) -> Result<()> + Send + Sync + 'static,
+    {
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let signal = shutdown.clone();
+        let handler = Arc::new(handler);
+        let connection = format!("{name}-connection");
+
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if signal.load(Acquire) {
+                        break;
+                    }
+
+                    // Failures to accept are about the peer, not the server.
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+
+                    // Peers are expected to go away, errors are not reported.
+                    let (handler, signal) = (handler.clone(), signal.clone());
+                    let _ = thread::Builder::new()
+                        .name(connection.clone())
+                        .spawn(move || handler(stream, &signal));
+                }
+            })?;
+
+        Ok(Self {
+            addr,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stop accepting connections and signal existing ones to stop.
+    pub fn close(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    /// Signal threads to stop and wait for the listener to exit.
+    fn stop(&mut self) -> Result<()> {
+        let Some(handle) = self.handle.take() else {
+            return Ok(());
+        };
+
+        // Wake up the listener with a connection of our own.
+        self.shutdown.store(true, Release);
+        let _ = TcpStream::connect(self.addr);
+
+        handle
+            .join()
+            .map_err(|_| Error::other("Server thread panicked"))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
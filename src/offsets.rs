@@ -0,0 +1,426 @@
+//! Durable positions of named consumers.
+
+use crate::metrics;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+};
+
+/// Durable position of a named consumer of log records.
+///
+/// Unless a ring buffer applies back-pressure, it does not wait for readers, so readers
+/// have to keep track of where they stopped. A consumer records the sequence number of
+/// the last log record it consumed in a file named after it, usually in the same
+/// directory as the ring buffer.
+///
+/// A [registered](Consumer::register) consumer holds back reclamation of a ring buffer
+/// with back-pressure, see [`BackPressure`](crate::ring::BackPressure).
+///
+/// # Durability
+///
+/// Commits are atomic. Position is written into a temporary file, synced to disk and
+/// then renamed over the previous position. A crash at any point leaves behind either
+/// the old or the new position, never a mix of both.
+///
+/// Like [`Storage`](crate::storage::Storage), there is no protection against the same
+/// consumer being opened concurrently, in this or any other process.
+pub struct Consumer {
+    name: String,
+    dir: PathBuf,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    committed: Option<u64>,
+}
+
+impl Consumer {
+    /// Extension of files holding positions of consumers.
+    const EXTENSION: &str = "offset";
+
+    /// Open a named consumer.
+    ///
+    /// If the consumer has never committed a position, it starts without one.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory holding positions of consumers.
+    /// * `name` - Name of the consumer, only ASCII alphanumerics, `-` and `_` are allowed.
+    pub fn open<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, format!("Invalid consumer name: {name:?}")));
+        }
+
+        // Paths to file holding position, and temporary file to update it.
+        let dir = dir.as_ref();
+        let path = dir.join(format!("{name}.{}", Self::EXTENSION));
+        let tmp_path = dir.join(format!("{name}.{}.tmp", Self::EXTENSION));
+
+        // Fetch last committed position, if any.
+        let committed = match fs::read(&path) {
+            Ok(bytes) if bytes.is_empty() => None,
+            Ok(bytes) => {
+                let Ok(bytes) = bytes.try_into() else {
+                    let kind = ErrorKind::InvalidData;
+                    return Err(Error::new(kind, "Corrupted consumer position"));
+                };
+
+                Some(u64::from_be_bytes(bytes))
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self {
+            path,
+            tmp_path,
+            committed,
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Open a named consumer, durably registering it if needed.
+    ///
+    /// Registered consumers hold back reclamation even before they commit a position,
+    /// until they are [removed](Consumer::remove).
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory holding positions of consumers.
+    /// * `name` - Name of the consumer, only ASCII alphanumerics, `-` and `_` are allowed.
+    pub fn register<P: AsRef<Path>>(dir: P, name: &str) -> Result<Self> {
+        let consumer = Self::open(dir, name)?;
+        if consumer.committed.is_some() {
+            return Ok(consumer);
+        }
+
+        // Registration is an empty position.
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&consumer.path)
+        {
+            Ok(file) => file.sync_all()?,
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => return Ok(consumer),
+            Err(error) => return Err(error),
+        }
+
+        if let Some(dir) = consumer.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(consumer)
+    }
+
+    /// Name of the consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sequence number of the last committed log record, if any.
+    pub fn committed(&self) -> Option<u64> {
+        self.committed
+    }
+
+    /// Durably record the position of the consumer.
+    ///
+    /// Position is not required to move forward, committing an older sequence
+    /// number rewinds the consumer.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_no` - Sequence number of the last consumed log record.
+    pub fn commit(&mut self, seq_no: u64) -> Result<()> {
+        // Write new position into a temporary file.
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.tmp_path)?;
+
+        file.write_all(&seq_no.to_be_bytes())?;
+        file.sync_all()?;
+
+        // Atomically replace the previous position.
+        fs::rename(&self.tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.committed = Some(seq_no);
+        Ok(())
+    }
+
+    /// Position to resume consuming log records from.
+    ///
+    /// # Arguments
+    ///
+    /// * `first` - Sequence number of the oldest log record still available, if any.
+    pub fn resume(&self, first: Option<u64>) -> Position {
+        match (self.committed, first) {
+            (None, _) => Position::New,
+            (Some(committed), Some(first)) if committed.saturating_add(1) < first => {
+                Position::Reclaimed { committed, first }
+            }
+            (Some(committed), _) => Position::Committed(committed),
+        }
+    }
+
+    /// Number of log records the consumer is behind by, also reported as metrics
+    /// along with the directory of the consumer.
+    ///
+    /// Log records reclaimed before the consumer got to them are not counted, and
+    /// sequence numbers are assumed to be contiguous.
+    ///
+    /// # Arguments
+    ///
+    /// * `first` - Sequence number of the oldest log record still available, if any.
+    /// * `last` - Sequence number of the newest log record, if any.
+    pub fn lag(&self, first: Option<u64>, last: Option<u64>) -> u64 {
+        let lag = match (first, last) {
+            (Some(first), Some(last)) => {
+                let next = self
+                    .committed
+                    .map_or(first, |committed| committed.saturating_add(1).max(first));
+
+                last.saturating_add(1).saturating_sub(next)
+            }
+            _ => 0,
+        };
+
+        metrics::record(|recorder| recorder.lag(&self.dir, &self.name, lag));
+        lag
+    }
+
+    /// Position of the slowest consumer in a directory.
+    ///
+    /// Returns the sequence number of the oldest log record that some consumer has not
+    /// consumed yet, None if there are no consumers. Consumers that are registered but
+    /// never committed a position need every log record, 0 is returned for them. This is
+    /// the furthest reclamation can go without losing log records a consumer needs.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory holding positions of consumers.
+    pub fn slowest<P: AsRef<Path>>(dir: P) -> Result<Option<u64>> {
+        let dir = dir.as_ref();
+        let mut slowest = None;
+        for entry in fs::read_dir(dir)? {
+            // Only files holding positions of consumers.
+            let path = entry?.path();
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+
+            if path.extension().is_none_or(|ext| ext != Self::EXTENSION) {
+                continue;
+            }
+
+            // Consumer removed concurrently simply has no position.
+            let consumer = match Self::open(dir, name) {
+                Err(error) if error.kind() == ErrorKind::InvalidInput => continue,
+                result => result?,
+            };
+
+            if !consumer.path.exists() {
+                continue;
+            }
+
+            let next = consumer
+                .committed
+                .map_or(0, |committed| committed.saturating_add(1));
+            slowest = Some(slowest.map_or(next, |slowest: u64| slowest.min(next)));
+        }
+
+        Ok(slowest)
+    }
+
+    /// Remove the consumer, along with its durable position.
+    pub fn remove(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Position to resume consuming log records from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// Consumer has never committed a position.
+    New,
+
+    /// Resume with the log record after this sequence number.
+    Committed(u64),
+
+    /// Log records after the committed position have already been reclaimed.
+    Reclaimed {
+        /// Sequence number of the last committed log record.
+        committed: u64,
+
+        /// Sequence number of the oldest log record still available.
+        first: u64,
+    },
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn open_new_consumer_has_no_position() -> Result<()> {
+        let dir = tempdir()?;
+        let consumer = Consumer::open(dir.path(), "audit")?;
+
+        assert_eq!("audit", consumer.name());
+        assert_eq!(None, consumer.committed());
+        assert_eq!(Position::New, consumer.resume(Some(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn open_invalid_name_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        for name in ["", "../audit", "audit.offset", "audit log"] {
+            let error = Consumer::open(dir.path(), name).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, error.kind());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_survives_reopen() -> Result<()> {
+        let dir = tempdir()?;
+
+        // Commit a few positions.
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        consumer.commit(10)?;
+        consumer.commit(20)?;
+        assert_eq!(Some(20), consumer.committed());
+
+        // Last commit should be visible after reopen.
+        let consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(Some(20), consumer.committed());
+        assert_eq!(Position::Committed(20), consumer.resume(Some(5)));
+        assert_eq!(Position::Committed(20), consumer.resume(Some(21)));
+        assert_eq!(Position::Committed(20), consumer.resume(None));
+
+        // Other consumers are independent.
+        let consumer = Consumer::open(dir.path(), "metrics")?;
+        assert_eq!(None, consumer.committed());
+        Ok(())
+    }
+
+    #[test]
+    fn resume_reports_reclaimed_position() -> Result<()> {
+        let dir = tempdir()?;
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        consumer.commit(20)?;
+
+        let position = consumer.resume(Some(30));
+        let expected = Position::Reclaimed {
+            committed: 20,
+            first: 30,
+        };
+
+        assert_eq!(expected, position);
+        Ok(())
+    }
+
+    #[test]
+    fn lag_counts_records_after_position() -> Result<()> {
+        let dir = tempdir()?;
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(0, consumer.lag(None, None));
+        assert_eq!(10, consumer.lag(Some(1), Some(10)));
+
+        consumer.commit(4)?;
+        assert_eq!(6, consumer.lag(Some(1), Some(10)));
+        assert_eq!(0, consumer.lag(Some(1), Some(4)));
+
+        // Reclaimed log records are not counted.
+        assert_eq!(3, consumer.lag(Some(8), Some(10)));
+        Ok(())
+    }
+
+    #[test]
+    fn open_ignores_incomplete_commit() -> Result<()> {
+        let dir = tempdir()?;
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        consumer.commit(20)?;
+
+        // Crash midway through a commit.
+        fs::write(dir.path().join("audit.offset.tmp"), [1, 2, 3])?;
+
+        // Previous commit should be intact, and next commit should succeed.
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(Some(20), consumer.committed());
+        consumer.commit(30)?;
+
+        let consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(Some(30), consumer.committed());
+        Ok(())
+    }
+
+    #[test]
+    fn slowest_returns_min_committed() -> Result<()> {
+        let dir = tempdir()?;
+        assert_eq!(None, Consumer::slowest(dir.path())?);
+
+        // Consumers that are not registered don't hold back anything.
+        let _idle = Consumer::open(dir.path(), "idle")?;
+        assert_eq!(None, Consumer::slowest(dir.path())?);
+
+        // Slowest of the consumers should be picked.
+        let mut audit = Consumer::register(dir.path(), "audit")?;
+        let mut metrics = Consumer::open(dir.path(), "metrics")?;
+        assert_eq!(Some(0), Consumer::slowest(dir.path())?);
+        audit.commit(20)?;
+        metrics.commit(30)?;
+        assert_eq!(Some(21), Consumer::slowest(dir.path())?);
+
+        // Incomplete commits and other files should be ignored.
+        fs::write(dir.path().join("audit.offset.tmp"), 5u64.to_be_bytes())?;
+        fs::write(dir.path().join("segment.storage"), 5u64.to_be_bytes())?;
+        assert_eq!(Some(21), Consumer::slowest(dir.path())?);
+
+        // Removed consumers no longer hold back reclamation.
+        audit.remove()?;
+        assert_eq!(Some(31), Consumer::slowest(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn register_keeps_committed_position() -> Result<()> {
+        let dir = tempdir()?;
+        let consumer = Consumer::register(dir.path(), "audit")?;
+        assert_eq!(None, consumer.committed());
+
+        // Registration survives reopen, without a position.
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(None, consumer.committed());
+        consumer.commit(20)?;
+
+        let consumer = Consumer::register(dir.path(), "audit")?;
+        assert_eq!(Some(20), consumer.committed());
+        Ok(consumer.remove()?)
+    }
+
+    #[test]
+    fn remove_deletes_position() -> Result<()> {
+        let dir = tempdir()?;
+        let mut consumer = Consumer::open(dir.path(), "audit")?;
+        consumer.commit(20)?;
+        consumer.remove()?;
+
+        let consumer = Consumer::open(dir.path(), "audit")?;
+        assert_eq!(None, consumer.committed());
+        Ok(consumer.remove()?)
+    }
+}
@@ -0,0 +1,514 @@
+//! Async front end for appends, backed by a bounded in-memory queue.
+
+use crate::{
+    buf::{Limits, LogBuf},
+    log::Log,
+    ring::{Ring, Writer},
+};
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// When a send is acknowledged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ack {
+    /// As soon as the log record is queued.
+    #[default]
+    Queued,
+
+    /// After the log record is appended into the ring buffer.
+    Written,
+
+    /// After the log record is durably synced to disk.
+    Synced,
+}
+
+/// A bounded queue of log records, drained by a single writer.
+///
+/// Producers on async runtimes should not block on file I/O, or share the [`Writer`]
+/// of a ring buffer between them. Instead they enqueue log records with a cloneable
+/// [`Sender`]. A dedicated writer thread owns the [`Writer`] of the ring, drains the
+/// queue into batches and appends every batch into the ring as a single block. So
+/// appends roll segments, enforce retention and wait on back-pressure as usual.
+/// Batches that exceed [`Limits`] are split into several blocks. Senders wait when
+/// the queue is full.
+///
+/// Log records must be sent with increasing sequence numbers, after the last one in
+/// the ring, those that are not are rejected. The writer stops after all senders are
+/// dropped and the queue is drained, or after the first error from the ring.
+pub struct Queue {
+    capacity: usize,
+    limits: Limits,
+    max_batch: usize,
+    ring: Arc<Ring>,
+}
+
+/// Handle to enqueue log records, cheap to clone.
+#[derive(Clone)]
+pub struct Sender(mpsc::Sender<Request>);
+
+/// Handle to wait for the writer to stop.
+pub struct Handle(oneshot::Receiver<Result<()>>);
+
+/// A log record waiting in the queue.
+struct Request {
+    log: Log<'static>,
+    ack: Ack,
+    done: Option<oneshot::Sender<Result<()>>>,
+}
+
+impl Queue {
+    /// Create a new queue with default capacity and batch size.
+    ///
+    /// # Arguments
+    ///
+    /// * `ring` - Ring buffer to append log records into.
+    pub fn new(ring: Arc<Ring>) -> Self {
+        Self {
+            ring,
+            capacity: 1024,
+            limits: Limits::default(),
+            max_batch: 1024,
+        }
+    }
+
+    /// Maximum number of log records waiting in the queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Number of log records, must be non-zero.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Maximum number of log records appended as a single block.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_batch` - Number of log records, must be non-zero.
+    pub fn with_max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch.max(1);
+        self
+    }
+
+    /// Limits of log records, and of the blocks they are appended as.
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - Limits of log records.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Start the writer in a background thread.
+    ///
+    /// Returns an error if the writer belongs to some other ring buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Writer of the ring buffer, held until the writer thread stops.
+    pub fn spawn(self, mut writer: Writer) -> Result<(Sender, Handle)> {
+        if !self.ring.owns(&writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different ring"));
+        }
+
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let (stopped, handle) = oneshot::channel();
+        thread::Builder::new()
+            .name("arrow-writer".to_string())
+            .spawn(move || {
+                // Release the writer before anyone waiting learns that it stopped.
+                let result = self.drain(receiver, &mut writer);
+                drop(writer);
+                let _ = stopped.send(result);
+            })?;
+
+        Ok((Sender(sender), Handle(handle)))
+    }
+
+    /// Append log records from the queue until all senders are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `receiver` - Receiving end of the queue.
+    /// * `writer` - Writer of the ring buffer.
+    fn drain(self, mut receiver: mpsc::Receiver<Request>, writer: &mut Writer) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(0).with_limits(self.limits);
+        let mut batch = Vec::with_capacity(self.max_batch);
+        let mut last = self.ring.last();
+
+        while let Some(request) = receiver.blocking_recv() {
+            batch.push(request);
+            while batch.len() < self.max_batch
+                && let Ok(request) = receiver.try_recv()
+            {
+                batch.push(request);
+            }
+
+            // Ring is left in an unknown state after errors, stop writing.
+            if let Err(error) = self.write(&mut batch, &mut logs, &mut last, writer) {
+                receiver.close();
+                for request in batch
+                    .drain(..)
+                    .chain(std::iter::from_fn(|| receiver.try_recv().ok()))
+                {
+                    request.reply(Err(Error::new(error.kind(), error.to_string())));
+                }
+
+                return Err(error);
+            }
+        }
+
+        self.ring.sync()
+    }
+
+    /// Append a batch of log records into the ring buffer, then acknowledge them.
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - Log records to append, drained on success.
+    /// * `logs` - Buffer to collect log records in.
+    /// * `last` - Sequence number of the last log record appended.
+    /// * `writer` - Writer of the ring buffer.
+    fn write(
+        &self,
+        batch: &mut Vec<Request>,
+        logs: &mut LogBuf,
+        last: &mut Option<u64>,
+        writer: &mut Writer,
+    ) -> Result<()> {
+        logs.clear();
+        let mut sync = false;
+        let mut accepted = Vec::with_capacity(batch.len());
+        let mut requests = std::mem::take(batch).into_iter();
+        while let Some(request) = requests.next() {
+            let in_order = last.is_none_or(|last| last < request.log.seq_no());
+            let mut appended = in_order && logs.append(&request.log);
+
+            // Block is full, append it and start the next one with the log record.
+            let fits = request.log.data().len() <= self.limits.max_record_size;
+            if in_order && fits && !appended && !logs.is_empty() {
+                if let Err(error) = self.flush(logs, sync, &mut accepted, writer) {
+                    // Put requests back, so that they are failed by the caller.
+                    batch.extend(accepted.into_iter().chain([request]).chain(requests));
+                    return Err(error);
+                }
+
+                sync = false;
+                appended = logs.append(&request.log);
+            }
+
+            if !appended {
+                let kind = ErrorKind::InvalidInput;
+                let error = "Log record is out of order or exceeds limits";
+                request.reply(Err(Error::new(kind, error)));
+                continue;
+            }
+
+            *last = Some(request.log.seq_no());
+            sync |= request.ack == Ack::Synced;
+            accepted.push(request);
+        }
+
+        let result = self.flush(logs, sync, &mut accepted, writer);
+        batch.extend(accepted);
+        result
+    }
+
+    /// Append collected log records into the ring buffer, then acknowledge them.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Log records to append, cleared on success.
+    /// * `sync` - True to sync the ring buffer after the append.
+    /// * `accepted` - Requests of the log records, drained on success.
+    /// * `writer` - Writer of the ring buffer.
+    fn flush(
+        &self,
+        logs: &mut LogBuf,
+        sync: bool,
+        accepted: &mut Vec<Request>,
+        writer: &mut Writer,
+    ) -> Result<()> {
+        self.append(logs, sync, writer)?;
+        logs.clear();
+        for request in accepted.drain(..) {
+            request.reply(Ok(()));
+        }
+
+        Ok(())
+    }
+
+    /// Append log records into the ring buffer as a single block.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Log records to append.
+    /// * `sync` - True to sync the ring buffer after the append.
+    /// * `writer` - Writer of the ring buffer.
+    fn append(&self, logs: &LogBuf, sync: bool, writer: &mut Writer) -> Result<()> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+
+        self.ring.append(logs, writer)?;
+        if sync {
+            self.ring.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Sender {
+    /// Enqueue a log record.
+    ///
+    /// Waits for space when the queue is full, then until the log record is
+    /// acknowledged. Returns an error if the writer has stopped or rejected
+    /// the log record.
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - Log record to append.
+    /// * `ack` - When to acknowledge the log record.
+    pub async fn send(&self, log: Log<'static>, ack: Ack) -> Result<()> {
+        let (done, acked) = match ack {
+            Ack::Queued => (None, None),
+            Ack::Written | Ack::Synced => {
+                let (done, acked) = oneshot::channel();
+                (Some(done), Some(acked))
+            }
+        };
+
+        let request = Request { log, ack, done };
+        if self.0.send(request).await.is_err() {
+            return Err(stopped());
+        }
+
+        match acked {
+            None => Ok(()),
+            Some(acked) => acked.await.unwrap_or_else(|_| Err(stopped())),
+        }
+    }
+}
+
+impl Handle {
+    /// Wait for the writer to stop.
+    ///
+    /// Returns the error that stopped the writer, if any.
+    pub async fn join(self) -> Result<()> {
+        self.0.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+impl Request {
+    /// Acknowledge the log record, if anyone is waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `result` - Outcome of appending the log record.
+    fn reply(self, result: Result<()>) {
+        if let Some(done) = self.done {
+            let _ = done.send(result);
+        }
+    }
+}
+
+/// Error returned when the writer is no longer running.
+fn stopped() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "Writer has stopped")
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        cursor::Event,
+    };
+    use anyhow::{Result, anyhow};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn ring(dir: &Path) -> Result<Arc<Ring>> {
+        Ok(Arc::new(Ring::open(dir, Block::new(Codec::default()))?))
+    }
+
+    fn writer(ring: &Ring) -> Result<Writer> {
+        ring.writer().ok_or(anyhow!("Should obtain writer"))
+    }
+
+    fn read_all(ring: &Ring) -> Result<(usize, Vec<u64>)> {
+        let (mut blocks, mut seq_nos) = (0, Vec::new());
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut reader = ring.reader(ring.first().unwrap_or(0));
+        while reader.poll(&mut logs)? == Event::Logs {
+            blocks += 1;
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        Ok((blocks, seq_nos))
+    }
+
+    #[tokio::test]
+    async fn queue_appends_and_acknowledges() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let queue = Queue::new(ring.clone());
+        let (sender, handle) = queue.with_max_batch(4).spawn(writer(&ring)?)?;
+
+        for seq_no in 1..=9 {
+            sender
+                .send(Log::new_owned(seq_no, b"Batman".to_vec()), Ack::Queued)
+                .await?;
+        }
+
+        // Acknowledged only after everything before it was written.
+        let log = Log::new_owned(10, b"Batman".to_vec());
+        sender.send(log, Ack::Synced).await?;
+
+        let (blocks, seq_nos) = read_all(&ring)?;
+        assert!(blocks >= 3);
+        assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos);
+
+        drop(sender);
+        handle.join().await?;
+        assert!(ring.writer().is_some());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queue_accepts_clones_of_sender() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let queue = Queue::new(ring.clone());
+        let (sender, handle) = queue.with_capacity(2).spawn(writer(&ring)?)?;
+
+        // Odd and even sequence numbers from two producers. Send alternately, so
+        // that sequence numbers always increase.
+        for seq_no in (1..=20).step_by(2) {
+            let odd = sender.clone();
+            let log = Log::new_owned(seq_no, b"Joker".to_vec());
+            tokio::spawn(async move { odd.send(log, Ack::Written).await }).await??;
+
+            let log = Log::new_owned(seq_no + 1, b"Joker".to_vec());
+            sender.send(log, Ack::Written).await?;
+        }
+
+        drop(sender);
+        handle.join().await?;
+        assert_eq!((1..=20).collect::<Vec<_>>(), read_all(&ring)?.1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_splits_batches_exceeding_limits() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let limits = Limits {
+            max_record_size: 16,
+            max_batch_size: 64,
+        };
+
+        let queue = Queue::new(ring.clone());
+        let (sender, handle) = queue.with_limits(limits).spawn(writer(&ring)?)?;
+        for seq_no in 1..=9 {
+            sender
+                .send(Log::new_owned(seq_no, b"Batman".to_vec()), Ack::Queued)
+                .await?;
+        }
+
+        let log = Log::new_owned(10, b"Batman".to_vec());
+        sender.send(log, Ack::Synced).await?;
+
+        // Nothing is rejected for not fitting into a single block.
+        let (blocks, seq_nos) = read_all(&ring)?;
+        assert!(blocks > 1);
+        assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos);
+
+        drop(sender);
+        handle.join().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_rejects_out_of_order_logs() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let queue = Queue::new(ring.clone());
+        let (sender, handle) = queue.spawn(writer(&ring)?)?;
+
+        sender
+            .send(Log::new_owned(5, b"Batman".to_vec()), Ack::Written)
+            .await?;
+        let error = sender
+            .send(Log::new_owned(5, b"Batman".to_vec()), Ack::Written)
+            .await
+            .expect_err("Should reject duplicate sequence number");
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+
+        drop(sender);
+        handle.join().await?;
+        assert_eq!(vec![5], read_all(&ring)?.1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queue_continues_after_last_log_in_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = ring(dir.path())?;
+        let (sender, handle) = Queue::new(ring.clone()).spawn(writer(&ring)?)?;
+        for seq_no in 1..=5 {
+            let log = Log::new_owned(seq_no, b"Batman".to_vec());
+            sender.send(log, Ack::Written).await?;
+        }
+
+        drop(sender);
+        handle.join().await?;
+        drop(ring);
+
+        // Restart on a ring that already has log records.
+        let ring = self::ring(dir.path())?;
+        let (sender, handle) = Queue::new(ring.clone()).spawn(writer(&ring)?)?;
+        let error = sender
+            .send(Log::new_owned(5, b"Batman".to_vec()), Ack::Written)
+            .await
+            .expect_err("Should reject log record already in the ring");
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        let log = Log::new_owned(6, b"Batman".to_vec());
+        sender.send(log, Ack::Written).await?;
+
+        drop(sender);
+        handle.join().await?;
+        assert_eq!((1..=6).collect::<Vec<_>>(), read_all(&ring)?.1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spawn_fails_with_writer_of_other_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let other = tempdir()?;
+        let ring = ring(dir.path())?;
+        let other = self::ring(other.path())?;
+
+        let queue = Queue::new(ring);
+        let error = queue
+            .spawn(writer(&other)?)
+            .err()
+            .expect("Should fail to spawn");
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        Ok(())
+    }
+}
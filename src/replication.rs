@@ -0,0 +1,830 @@
+//! Replication of a ring buffer from a leader to followers over TCP.
+//!
+//! A follower connects to the leader and asks for log records starting after the
+//! last one it has. The leader reads them from its ring, starting from the segment
+//! its index points at, and streams them in blocks. The follower appends them into
+//! its own ring, so log records keep their sequence numbers, while segments roll and
+//! retention is enforced on each side independently.
+//!
+//! # Protocol
+//!
+//! Follower starts with a handshake, `ARROWREP` followed by the sequence number of the
+//! first log record it needs (u64 BE). The leader then sends a stream of frames, each
+//! a tag (u8) and the sequence number after the last log record in the leader ring
+//! (u64 BE). Block frames are followed by the block, heartbeats by nothing.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    block::{Block, Codec},
+    buf::LogBuf,
+    cursor::{Decoder, Event},
+    net::Server,
+    ring::{Ring, Writer},
+};
+use std::{
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering::*},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Magic bytes that start the handshake.
+const MAGIC: &[u8; 8] = b"ARROWREP";
+
+/// Tag of a frame carrying a block.
+const BLOCK: u8 = 0;
+
+/// Tag of a frame sent when there is nothing to replicate.
+const HEARTBEAT: u8 = 1;
+
+/// Number of bytes in the header of a frame.
+const FRAME_HEADER_SIZE: usize = 1 + 8;
+
+/// Maximum time to wait on a peer for reads and writes.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Leader side of replication, serves a ring buffer to followers.
+pub struct Leader {
+    poll: Duration,
+    heartbeat: Duration,
+    codec: Codec,
+    ring: Arc<Ring>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+/// Follower side of replication, mirrors the ring buffer of a leader.
+pub struct Follower {
+    addr: String,
+    retry: Duration,
+    sync_interval: Duration,
+    sync_bytes: u64,
+    writer: Writer,
+    ring: Arc<Ring>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+/// Background thread replicating from a leader.
+///
+/// Dropping the replica stops replication, but errors from the final sync of
+/// the ring are lost. Use [`Replica::close`] to observe them.
+pub struct Replica {
+    status: Arc<Mutex<Status>>,
+    stream: Arc<Mutex<Option<TcpStream>>>,
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+/// Progress of replication.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    /// True if connected to the leader, false otherwise.
+    pub connected: bool,
+
+    /// Sequence number of the last log record in the follower ring, if any.
+    pub last: Option<u64>,
+
+    /// Sequence number of the last log record synced to disk, if any.
+    pub synced: Option<u64>,
+
+    /// Number of log records the follower is behind the leader, as of the last frame.
+    pub lag: u64,
+
+    /// Number of times the follower reconnected to the leader.
+    pub reconnects: u64,
+
+    /// The last error that broke the connection to the leader, if any.
+    pub error: Option<String>,
+}
+
+impl Leader {
+    /// Create a leader that serves log records from a ring buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `ring` - Ring buffer to replicate.
+    pub fn new(ring: Arc<Ring>) -> Self {
+        Self {
+            ring,
+            codec: Codec::default(),
+            poll: Duration::from_millis(50),
+            heartbeat: Duration::from_secs(1),
+            #[cfg(feature = "encryption")]
+            keyring: None,
+        }
+    }
+
+    /// Time to wait before checking the ring for new blocks, once followers are caught up.
+    ///
+    /// # Arguments
+    ///
+    /// * `poll` - Time between checks.
+    pub fn with_poll_interval(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Time between heartbeats to followers that are caught up.
+    ///
+    /// # Arguments
+    ///
+    /// * `heartbeat` - Time between heartbeats.
+    pub fn with_heartbeat_interval(mut self, heartbeat: Duration) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Codec to encode blocks sent to followers with.
+    ///
+    /// # Arguments
+    ///
+    /// * `codec` - Codec of blocks sent to followers.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Encrypt blocks sent to followers with the active key of a keyring.
+    ///
+    /// Log records are read from the ring with its own keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to encrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Serve followers that connect to a listener, each from a thread of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Listener to accept followers from.
+    pub fn serve(self, listener: TcpListener) -> Result<Server> {
+        Server::spawn(listener, "arrow-leader", move |stream, shutdown| {
+            self.replicate(stream, shutdown)
+        })
+    }
+
+    /// Stream blocks to a follower until it disconnects or the server stops.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the follower.
+    /// * `shutdown` - Set when the server stops.
+    fn replicate(&self, stream: TcpStream, shutdown: &AtomicBool) -> Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut handshake = [0; MAGIC.len() + 8];
+        (&stream).read_exact(&mut handshake)?;
+        let (magic, from) = handshake.split_at(MAGIC.len());
+        if magic != MAGIC {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Unexpected replication handshake"));
+        }
+
+        let from = u64::from_be_bytes(from.try_into().expect("Should be 8 bytes"));
+        let mut reader = self.ring.reader(from);
+        let (mut logs, mut newer) = (LogBuf::with_capacity(0), LogBuf::with_capacity(0));
+        let mut block = self.block();
+        let mut stream = BufWriter::new(stream);
+        let mut heartbeat = Instant::now();
+        while !shutdown.load(Acquire) {
+            let next = self.ring.last().map_or(0, |last| last.saturating_add(1));
+            match reader.poll(&mut logs)? {
+                Event::Logs => {
+                    // First block can start before what the follower asked for.
+                    let logs = match logs.first() {
+                        Some(first) if first < from => {
+                            newer.clear();
+                            let mut iter = logs.iter();
+                            while let Some(log) = iter.next() {
+                                if log.seq_no() >= from && !newer.append(&log) {
+                                    let kind = ErrorKind::InvalidData;
+                                    return Err(Error::new(kind, "Log record exceeds limits"));
+                                }
+                            }
+
+                            &newer
+                        }
+                        _ => &logs,
+                    };
+
+                    if logs.is_empty() {
+                        continue;
+                    }
+
+                    block.encode(logs)?;
+                    write_frame(&mut stream, BLOCK, next)?;
+                    stream.write_all(block.bytes())?;
+                    continue;
+                }
+
+                // Followers find out about the gap from the next block.
+                Event::Lost { .. } => continue,
+
+                Event::Pending => {}
+            }
+
+            // Caught up, send whatever is buffered and wait for new blocks.
+            if heartbeat.elapsed() >= self.heartbeat {
+                write_frame(&mut stream, HEARTBEAT, next)?;
+                heartbeat = Instant::now();
+            }
+
+            stream.flush()?;
+            thread::sleep(self.poll);
+        }
+
+        Ok(())
+    }
+
+    /// Block to encode log records sent to a follower with.
+    fn block(&self) -> Block {
+        let block = Block::new(self.codec);
+
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = &self.keyring {
+            return block.with_keyring(keyring.clone());
+        }
+
+        block
+    }
+}
+
+impl Follower {
+    /// Create a follower that appends log records from a leader into a ring buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address of the leader.
+    /// * `ring` - Ring buffer to append log records into.
+    /// * `writer` - Writer of the ring buffer.
+    pub fn new<A: Into<String>>(addr: A, ring: Arc<Ring>, writer: Writer) -> Self {
+        Self {
+            writer,
+            ring,
+            addr: addr.into(),
+            retry: Duration::from_secs(1),
+            sync_interval: Duration::from_secs(1),
+            sync_bytes: 1024 * 1024,
+            #[cfg(feature = "encryption")]
+            keyring: None,
+        }
+    }
+
+    /// Time to wait before reconnecting to the leader after failures.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry` - Time between attempts to connect.
+    pub fn with_retry_interval(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Maximum time appended blocks wait to be synced to disk.
+    ///
+    /// The ring is also synced whenever the leader is idle.
+    ///
+    /// # Arguments
+    ///
+    /// * `sync_interval` - Time between syncs while blocks are appended.
+    pub fn with_sync_interval(mut self, sync_interval: Duration) -> Self {
+        self.sync_interval = sync_interval;
+        self
+    }
+
+    /// Maximum number of bytes appended before the ring is synced to disk.
+    ///
+    /// The ring is also synced whenever the leader is idle.
+    ///
+    /// # Arguments
+    ///
+    /// * `sync_bytes` - Number of bytes appended between syncs.
+    pub fn with_sync_bytes(mut self, sync_bytes: u64) -> Self {
+        self.sync_bytes = sync_bytes;
+        self
+    }
+
+    /// Decrypt blocks sent by the leader with keys from a keyring.
+    ///
+    /// Log records are appended into the ring with its own block.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to decrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Replicate from the leader in a background thread.
+    ///
+    /// Returns an error if the writer belongs to some other ring buffer. The ring is
+    /// synced before replication starts.
+    pub fn spawn(self) -> Result<Replica> {
+        if !self.ring.owns(&self.writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different ring"));
+        }
+
+        let last = self.ring.last();
+        self.ring.sync()?;
+        let status = Arc::new(Mutex::new(Status {
+            last,
+            synced: last,
+            ..Status::default()
+        }));
+
+        let stream = Arc::new(Mutex::new(None));
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let (thread_status, thread_stream, signal) =
+            (status.clone(), stream.clone(), shutdown.clone());
+
+        let handle = thread::Builder::new()
+            .name("arrow-follower".to_string())
+            .spawn(move || self.run(&thread_status, &thread_stream, &signal))?;
+
+        Ok(Replica {
+            status,
+            stream,
+            shutdown,
+            handle: Some(handle),
+        })
+    }
+
+    /// Replicate from the leader, reconnecting after failures, until asked to stop.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - Progress of replication.
+    /// * `current` - Current connection to the leader, if any.
+    /// * `shutdown` - Set when asked to stop.
+    fn run(
+        mut self,
+        status: &Mutex<Status>,
+        current: &Mutex<Option<TcpStream>>,
+        shutdown: &(Mutex<bool>, Condvar),
+    ) -> Result<()> {
+        let (lock, condvar) = shutdown;
+        loop {
+            // Register the connection, so that it can be shutdown to stop.
+            let result = TcpStream::connect(&self.addr).and_then(|stream| {
+                let closed = lock.lock().unwrap_or_else(|e| e.into_inner());
+                if *closed {
+                    return Ok(());
+                }
+
+                *current.lock().unwrap_or_else(|e| e.into_inner()) = Some(stream.try_clone()?);
+                drop(closed);
+
+                update(status, |status| status.connected = true);
+                self.replicate(stream, status)
+            });
+
+            current.lock().unwrap_or_else(|e| e.into_inner()).take();
+            let error = result.err().map(|error| error.to_string());
+            if error.is_some() {
+                event!(warn, addr = %self.addr, error, "Lost connection to leader");
+            }
+
+            update(status, |status| {
+                status.connected = false;
+                status.error = error;
+            });
+
+            // Wait before reconnecting, unless asked to stop.
+            let closed = lock.lock().unwrap_or_else(|e| e.into_inner());
+            let (closed, _) = condvar
+                .wait_timeout_while(closed, self.retry, |closed| !*closed)
+                .unwrap_or_else(|e| e.into_inner());
+
+            if *closed {
+                break;
+            }
+
+            update(status, |status| status.reconnects += 1);
+        }
+
+        self.ring.sync()
+    }
+
+    /// Append log records from the leader until the connection breaks.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Connection to the leader.
+    /// * `status` - Progress of replication.
+    fn replicate(&mut self, stream: TcpStream, status: &Mutex<Status>) -> Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let (mut last, mut synced) = {
+            let status = status.lock().unwrap_or_else(|e| e.into_inner());
+            (status.last, status.synced)
+        };
+
+        let from = last.map_or(0, |last| last.saturating_add(1));
+        let mut handshake = Vec::with_capacity(MAGIC.len() + 8);
+        handshake.extend_from_slice(MAGIC);
+        handshake.extend_from_slice(&from.to_be_bytes());
+        (&stream).write_all(&handshake)?;
+
+        let mut logs = LogBuf::with_capacity(0);
+        let mut decoder = Decoder::default();
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = &self.keyring {
+            decoder = decoder.with_keyring(keyring);
+        }
+        let mut scratch = Vec::new();
+        let (mut unsynced, mut synced_at) = (0, Instant::now());
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut frame = [0; FRAME_HEADER_SIZE];
+            stream.read_exact(&mut frame)?;
+            let next = u64::from_be_bytes(frame[1..].try_into().expect("Should be 8 bytes"));
+
+            match frame[0] {
+                BLOCK => {
+                    // Make sure the block is valid and in order before appending.
+                    let mut header = [0; Block::HEADER_SIZE];
+                    stream.read_exact(&mut header)?;
+                    let parsed = Block::read_header(&header, logs.limits())?;
+                    scratch.resize(parsed.len, 0);
+                    stream.read_exact(&mut scratch)?;
+                    decoder.decode(&header, &parsed, &mut scratch, &mut logs)?;
+
+                    let Some(first) = logs.first() else {
+                        let kind = ErrorKind::InvalidData;
+                        return Err(Error::new(kind, "Leader sent a block without log records"));
+                    };
+
+                    if last.is_some_and(|last| last >= first) {
+                        let kind = ErrorKind::InvalidData;
+                        return Err(Error::new(kind, "Follower has diverged from leader"));
+                    }
+
+                    // Log records were reclaimed on the leader before they were sent.
+                    if last.is_some_and(|last| last.saturating_add(1) != first) {
+                        let kind = ErrorKind::InvalidData;
+                        return Err(Error::new(
+                            kind,
+                            "Leader no longer has log records the follower needs",
+                        ));
+                    }
+
+                    self.ring.append(&logs, &mut self.writer)?;
+                    unsynced += (Block::HEADER_SIZE + parsed.len) as u64;
+                    last = logs.last();
+                }
+
+                HEARTBEAT => {}
+
+                _ => {
+                    let kind = ErrorKind::InvalidData;
+                    return Err(Error::new(kind, "Unexpected replication frame"));
+                }
+            }
+
+            // Make appends durable when the leader is idle, or once enough piled up.
+            let due = unsynced >= self.sync_bytes || synced_at.elapsed() >= self.sync_interval;
+            if unsynced > 0 && (frame[0] == HEARTBEAT || due) {
+                self.ring.sync()?;
+                (unsynced, synced_at, synced) = (0, Instant::now(), last);
+            }
+
+            update(status, |status| {
+                status.last = last;
+                status.synced = synced;
+                status.lag = next.saturating_sub(last.map_or(0, |last| last.saturating_add(1)));
+            });
+        }
+    }
+}
+
+impl Replica {
+    /// Current progress of replication.
+    pub fn status(&self) -> Status {
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Stop replication.
+    ///
+    /// Waits for the thread to exit, and returns the error from syncing
+    /// the ring one last time, if any.
+    pub fn close(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    /// Signal the thread to stop and wait for it to exit.
+    fn stop(&mut self) -> Result<()> {
+        let Some(handle) = self.handle.take() else {
+            return Ok(());
+        };
+
+        let (lock, condvar) = &*self.shutdown;
+        *lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        condvar.notify_all();
+
+        // Unblock reads from the leader.
+        let stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(stream) = stream.as_ref() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        drop(stream);
+        handle
+            .join()
+            .unwrap_or_else(|_| Err(Error::other("Follower thread panicked")))
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Write the header of a frame.
+///
+/// # Arguments
+///
+/// * `stream` - Stream to write into.
+/// * `tag` - Type of the frame.
+/// * `next` - Sequence number after the last log record in the leader ring.
+fn write_frame<W: Write>(stream: &mut W, tag: u8, next: u64) -> Result<()> {
+    let mut frame = [0; FRAME_HEADER_SIZE];
+    frame[0] = tag;
+    frame[1..].copy_from_slice(&next.to_be_bytes());
+    stream.write_all(&frame)
+}
+
+/// Update status of replication.
+///
+/// # Arguments
+///
+/// * `status` - Status to update.
+/// * `f` - Function that updates status.
+fn update<F: FnOnce(&mut Status)>(status: &Mutex<Status>, f: F) {
+    f(&mut status
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()));
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::log::Log;
+    use anyhow::{Result, anyhow};
+    use std::{fs, net::SocketAddr, ops::RangeInclusive, path::Path};
+    use tempfile::tempdir;
+
+    fn append(ring: &Ring, writer: &mut Writer, seq_nos: RangeInclusive<u64>) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        ring.append(&logs, writer)?;
+        Ok(())
+    }
+
+    fn ring(dir: &Path) -> Result<(Arc<Ring>, Writer)> {
+        fs::create_dir_all(dir)?;
+        let ring = Ring::open(dir, Block::new(Codec::default()))?.with_segment_size(256);
+        let writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        Ok((Arc::new(ring), writer))
+    }
+
+    fn read_all(ring: &Ring) -> Result<Vec<u64>> {
+        let mut seq_nos = Vec::new();
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut reader = ring.reader(ring.first().unwrap_or(0));
+        while reader.poll(&mut logs)? == Event::Logs {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        Ok(seq_nos)
+    }
+
+    fn serve(ring: &Arc<Ring>, addr: &str) -> Result<Server> {
+        let leader = Leader::new(ring.clone())
+            .with_poll_interval(Duration::from_millis(5))
+            .with_heartbeat_interval(Duration::from_millis(20));
+
+        Ok(leader.serve(TcpListener::bind(addr)?)?)
+    }
+
+    fn follow(addr: SocketAddr, ring: Arc<Ring>, writer: Writer) -> Result<Replica> {
+        let follower = Follower::new(addr.to_string(), ring, writer);
+        let follower = follower.with_retry_interval(Duration::from_millis(20));
+        Ok(follower.spawn()?)
+    }
+
+    fn wait_for(replica: &Replica, last: u64) -> Result<Status> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let status = replica.status();
+            if status.last == Some(last) && status.lag == 0 {
+                return Ok(status);
+            }
+
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        Err(anyhow!(
+            "Should replicate up to {last}: {:?}",
+            replica.status()
+        ))
+    }
+
+    fn wait_for_error(replica: &Replica) -> Status {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while replica.status().error.is_none() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        replica.status()
+    }
+
+    #[test]
+    fn follower_mirrors_leader() -> Result<()> {
+        let dir = tempdir()?;
+        let (leader, mut writer) = ring(&dir.path().join("leader"))?;
+        append(&leader, &mut writer, 1..=10)?;
+        append(&leader, &mut writer, 11..=20)?;
+
+        let server = serve(&leader, "127.0.0.1:0")?;
+        let (follower, follower_writer) = ring(&dir.path().join("follower"))?;
+        let replica = follow(server.local_addr(), follower.clone(), follower_writer)?;
+        let status = wait_for(&replica, 20)?;
+        assert!(status.connected);
+
+        // New appends are replicated as they happen.
+        append(&leader, &mut writer, 21..=30)?;
+        wait_for(&replica, 30)?;
+
+        replica.close()?;
+        server.close()?;
+        assert_eq!((1..=30).collect::<Vec<_>>(), read_all(&follower)?);
+        assert!(follower.segments().len() > 1);
+        Ok(())
+    }
+
+    #[test]
+    fn follower_resumes_after_last_seq_no() -> Result<()> {
+        let dir = tempdir()?;
+        let (leader, mut writer) = ring(&dir.path().join("leader"))?;
+        let (follower, mut follower_writer) = ring(&dir.path().join("follower"))?;
+        for seq_no in (1..=100).step_by(10) {
+            append(&leader, &mut writer, seq_no..=seq_no + 9)?;
+        }
+
+        // Resumes from the middle of a block, in a segment other than the first.
+        append(&follower, &mut follower_writer, 1..=55)?;
+        let server = serve(&leader, "127.0.0.1:0")?;
+        let replica = follow(server.local_addr(), follower.clone(), follower_writer)?;
+        wait_for(&replica, 100)?;
+
+        replica.close()?;
+        server.close()?;
+        assert_eq!((1..=100).collect::<Vec<_>>(), read_all(&follower)?);
+        Ok(())
+    }
+
+    #[test]
+    fn follower_reconnects_after_leader_restarts() -> Result<()> {
+        let dir = tempdir()?;
+        let (leader, mut writer) = ring(&dir.path().join("leader"))?;
+        append(&leader, &mut writer, 1..=10)?;
+
+        let server = serve(&leader, "127.0.0.1:0")?;
+        let addr = server.local_addr();
+        let (follower, follower_writer) = ring(&dir.path().join("follower"))?;
+        let replica = follow(addr, follower.clone(), follower_writer)?;
+        wait_for(&replica, 10)?;
+
+        // Appends while the leader is down are replicated after it's back.
+        server.close()?;
+        append(&leader, &mut writer, 11..=20)?;
+        let server = serve(&leader, &addr.to_string())?;
+
+        let status = wait_for(&replica, 20)?;
+        assert!(status.reconnects >= 1);
+
+        replica.close()?;
+        server.close()?;
+        assert_eq!((1..=20).collect::<Vec<_>>(), read_all(&follower)?);
+        Ok(())
+    }
+
+    #[test]
+    fn follower_rejects_gap() -> Result<()> {
+        let dir = tempdir()?;
+        let (leader, mut writer) = ring(&dir.path().join("leader"))?;
+        let (follower, mut follower_writer) = ring(&dir.path().join("follower"))?;
+        append(&leader, &mut writer, 11..=20)?;
+        append(&follower, &mut follower_writer, 1..=5)?;
+
+        let server = serve(&leader, "127.0.0.1:0")?;
+        let replica = follow(server.local_addr(), follower.clone(), follower_writer)?;
+
+        let status = wait_for_error(&replica);
+        assert!(
+            status
+                .error
+                .is_some_and(|error| error.contains("no longer has"))
+        );
+        assert_eq!(Some(5), status.last);
+
+        replica.close()?;
+        server.close()?;
+        assert_eq!((1..=5).collect::<Vec<_>>(), read_all(&follower)?);
+        Ok(())
+    }
+
+    #[test]
+    fn follower_syncs_by_bytes_without_heartbeats() -> Result<()> {
+        let dir = tempdir()?;
+        let (leader, mut writer) = ring(&dir.path().join("leader"))?;
+        append(&leader, &mut writer, 1..=10)?;
+
+        let leader = Leader::new(leader).with_heartbeat_interval(Duration::from_secs(3600));
+        let server = leader.serve(TcpListener::bind("127.0.0.1:0")?)?;
+        let (follower, follower_writer) = ring(&dir.path().join("follower"))?;
+        let replica = Follower::new(server.local_addr().to_string(), follower, follower_writer)
+            .with_sync_interval(Duration::from_secs(3600))
+            .with_sync_bytes(1)
+            .spawn()?;
+
+        let status = wait_for(&replica, 10)?;
+        assert_eq!(Some(10), status.synced);
+
+        replica.close()?;
+        server.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn follower_stops_at_block_without_log_records() -> Result<()> {
+        let dir = tempdir()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+
+        // Leader that sends a block without log records.
+        let leader = thread::spawn(move || -> Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut handshake = [0; MAGIC.len() + 8];
+            stream.read_exact(&mut handshake)?;
+            write_frame(&mut stream, BLOCK, 1)?;
+            stream.write_all(&[0; Block::HEADER_SIZE])?;
+            Ok(())
+        });
+
+        let (follower, follower_writer) = ring(dir.path())?;
+        let replica = follow(addr, follower.clone(), follower_writer)?;
+        let status = wait_for_error(&replica);
+        assert!(status.error.is_some());
+        assert_eq!(None, status.last);
+
+        replica.close()?;
+        leader.join().map_err(|_| anyhow!("Leader panicked"))??;
+        assert_eq!(None, follower.last());
+        Ok(())
+    }
+
+    #[test]
+    fn spawn_fails_with_writer_of_other_ring() -> Result<()> {
+        let dir = tempdir()?;
+        let (follower, _) = ring(&dir.path().join("follower"))?;
+        let (_, writer) = ring(&dir.path().join("other"))?;
+
+        let follower = Follower::new("127.0.0.1:1", follower, writer);
+        let error = follower
+            .spawn()
+            .err()
+            .ok_or(anyhow!("Should fail to spawn"))?;
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        Ok(())
+    }
+}
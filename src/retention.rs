@@ -0,0 +1,248 @@
+//! Policies deciding how much of the ring buffer is retained.
+
+use std::time::{Duration, SystemTime};
+
+/// Summary of a segment of the ring buffer, as seen by retention policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentStats {
+    /// Number of bytes occupied by the segment.
+    pub len: u64,
+
+    /// Number of log records in the segment.
+    pub records: u64,
+
+    /// When the newest log record in the segment was written.
+    pub modified: SystemTime,
+}
+
+/// Composable limits on how much the ring buffer retains.
+///
+/// Every limit is optional, a segment is reclaimed as soon as any one of them is
+/// exceeded. Segments are always reclaimed oldest first, and the newest segment,
+/// the one being appended into, is never reclaimed.
+///
+/// Policies are enforced by a [`Ring`](crate::ring::Ring) every time a segment rolls.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Maximum number of bytes retained on disk.
+    pub max_bytes: Option<u64>,
+
+    /// Maximum age of retained log records.
+    pub max_age: Option<Duration>,
+
+    /// Maximum number of retained log records.
+    pub max_records: Option<u64>,
+}
+
+impl Retention {
+    /// Decide which segments should be reclaimed.
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` - Segments of the ring buffer, oldest first.
+    /// * `now` - Current time, to figure out age of segments.
+    pub fn evaluate(&self, segments: &[SegmentStats], now: SystemTime) -> Reclaim {
+        let mut reclaim = Reclaim::default();
+        for (segment, reason) in segments.iter().zip(self.reasons(segments, now)) {
+            reclaim.add(segment, reason);
+        }
+
+        reclaim
+    }
+
+    /// Reasons for reclaiming each of the oldest segments that should be reclaimed.
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` - Segments of the ring buffer, oldest first.
+    /// * `now` - Current time, to figure out age of segments.
+    pub(crate) fn reasons(&self, segments: &[SegmentStats], now: SystemTime) -> Vec<Reason> {
+        let mut bytes: u64 = segments.iter().map(|segment| segment.len).sum();
+        let mut records: u64 = segments.iter().map(|segment| segment.records).sum();
+
+        // Newest segment is never reclaimed.
+        let mut reasons = Vec::new();
+        let Some((_, segments)) = segments.split_last() else {
+            return reasons;
+        };
+
+        for segment in segments {
+            let age = now.duration_since(segment.modified).unwrap_or_default();
+            let reason = if self.max_age.is_some_and(|max_age| age > max_age) {
+                Reason::Age
+            } else if self.max_bytes.is_some_and(|max_bytes| bytes > max_bytes) {
+                Reason::Bytes
+            } else if self
+                .max_records
+                .is_some_and(|max_records| records > max_records)
+            {
+                Reason::Records
+            } else {
+                break;
+            };
+
+            // Segment will be reclaimed.
+            bytes -= segment.len;
+            records -= segment.records;
+            reasons.push(reason);
+        }
+
+        reasons
+    }
+}
+
+/// Reason for reclaiming a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Log records are older than [`Retention::max_age`].
+    Age,
+
+    /// Ring buffer occupies more than [`Retention::max_bytes`].
+    Bytes,
+
+    /// Ring buffer holds more than [`Retention::max_records`].
+    Records,
+}
+
+/// Segments a retention policy decided to reclaim, and why.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reclaim {
+    /// Number of oldest segments to reclaim.
+    pub segments: usize,
+
+    /// Number of bytes reclaimed.
+    pub bytes: u64,
+
+    /// Number of log records reclaimed.
+    pub records: u64,
+
+    /// Number of segments reclaimed because of their age.
+    pub by_age: usize,
+
+    /// Number of segments reclaimed because of size of the ring buffer.
+    pub by_bytes: usize,
+
+    /// Number of segments reclaimed because of number of log records.
+    pub by_records: usize,
+}
+
+impl Reclaim {
+    /// Returns true if nothing needs to be reclaimed, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.segments == 0
+    }
+
+    /// Account for a segment being reclaimed.
+    ///
+    /// # Arguments
+    ///
+    /// * `segment` - Segment being reclaimed.
+    /// * `reason` - Reason for reclaiming the segment.
+    pub(crate) fn add(&mut self, segment: &SegmentStats, reason: Reason) {
+        self.segments += 1;
+        self.bytes += segment.len;
+        self.records += segment.records;
+        match reason {
+            Reason::Age => self.by_age += 1,
+            Reason::Bytes => self.by_bytes += 1,
+            Reason::Records => self.by_records += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn segments(now: SystemTime) -> Vec<SegmentStats> {
+        // Segments of 100 bytes and 10 records, one minute apart.
+        (0..5)
+            .rev()
+            .map(|age| SegmentStats {
+                len: 100,
+                records: 10,
+                modified: now - MINUTE * age,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn evaluate_no_limits_reclaims_nothing() {
+        let now = SystemTime::now();
+        let reclaim = Retention::default().evaluate(&segments(now), now);
+        assert!(reclaim.is_empty());
+    }
+
+    #[test]
+    fn evaluate_no_segments_reclaims_nothing() {
+        let retention = Retention {
+            max_bytes: Some(0),
+            ..Retention::default()
+        };
+
+        let reclaim = retention.evaluate(&[], SystemTime::now());
+        assert!(reclaim.is_empty());
+    }
+
+    #[test]
+    fn evaluate_max_bytes_reclaims_oldest() {
+        let now = SystemTime::now();
+        let retention = Retention {
+            max_bytes: Some(250),
+            ..Retention::default()
+        };
+
+        let reclaim = retention.evaluate(&segments(now), now);
+        assert_eq!(3, reclaim.segments);
+        assert_eq!(300, reclaim.bytes);
+        assert_eq!(30, reclaim.records);
+        assert_eq!(3, reclaim.by_bytes);
+    }
+
+    #[test]
+    fn evaluate_max_records_reclaims_oldest() {
+        let now = SystemTime::now();
+        let retention = Retention {
+            max_records: Some(40),
+            ..Retention::default()
+        };
+
+        let reclaim = retention.evaluate(&segments(now), now);
+        assert_eq!(1, reclaim.segments);
+        assert_eq!(1, reclaim.by_records);
+    }
+
+    #[test]
+    fn evaluate_max_age_reclaims_oldest() {
+        let now = SystemTime::now();
+        let retention = Retention {
+            max_age: Some(MINUTE * 2),
+            ..Retention::default()
+        };
+
+        let reclaim = retention.evaluate(&segments(now), now);
+        assert_eq!(2, reclaim.segments);
+        assert_eq!(2, reclaim.by_age);
+    }
+
+    #[test]
+    fn evaluate_combined_limits_reports_reasons() {
+        let now = SystemTime::now();
+        let retention = Retention {
+            max_age: Some(MINUTE * 3),
+            max_bytes: Some(300),
+            max_records: Some(10),
+        };
+
+        // Oldest by age, next by bytes, rest by records.
+        // Newest segment is retained regardless of limits.
+        let reclaim = retention.evaluate(&segments(now), now);
+        assert_eq!(4, reclaim.segments);
+        assert_eq!(1, reclaim.by_age);
+        assert_eq!(1, reclaim.by_bytes);
+        assert_eq!(2, reclaim.by_records);
+    }
+}
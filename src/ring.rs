@@ -0,0 +1,1814 @@
+//! Ring buffer of log records, spread across segments in a directory.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    block::Block,
+    buf::LogBuf,
+    cursor::{Cursor, Event},
+    lock::{MutLock, OwnedMutGuard},
+    maintenance::{Maintenance, Worker},
+    manifest::{Manifest, Segment, State},
+    metrics,
+    offsets::Consumer,
+    retention::{Reason, Reclaim, Retention, SegmentStats},
+    seal::{Footer, INDEX_INTERVAL},
+    storage::{self, Storage},
+    truncate,
+};
+use crc32fast::Hasher;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    path::Path,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering::*},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A ring buffer of log records, spread across segments in a directory.
+///
+/// Log records are appended into the active segment until it grows past the segment
+/// size. It is then sealed, and a new segment is rolled. Every time a segment rolls,
+/// oldest segments are reclaimed as decided by the [`Retention`] policy. Reclamation
+/// can also be triggered explicitly with [`Ring::reclaim`]. Log records are read
+/// back across segments with a [`Reader`].
+///
+/// # Recovery
+///
+/// Opening the ring repairs its [`Manifest`], and removes an incomplete block at the
+/// end of the active segment, if any. A roll interrupted after the active segment was
+/// sealed is completed on the next append.
+///
+/// # Back-pressure
+///
+/// By default, segments are reclaimed without waiting for anyone to catch up. With
+/// [`Ring::with_back_pressure`], reclamation does not go past log records that some
+/// [registered](Consumer::register) consumer in the directory of the ring has not
+/// consumed yet. Once the ring exceeds its retention limits on bytes or log records
+/// because of that, it is full, and appends either wait or fail until consumers
+/// catch up. Limits on age are not enforced until consumers catch up either, but
+/// they never make the ring full.
+///
+/// # Concurrency
+///
+/// Like [`Storage`], a ring can be shared across threads. A single writer can append
+/// at a time, appends require the [`Writer`] of the ring, obtained with [`Ring::writer`],
+/// or by waiting for it with [`Ring::writer_blocking`] and [`Ring::writer_timeout`].
+/// Any number of readers can read while log records are appended.
+///
+/// # Maintenance
+///
+/// Syncing the active segment, enforcing retention and persisting the sparse index of
+/// the active segment can be done off the append path by [`Ring::maintenance`]. The
+/// index lets opening the ring skip most of the active segment during recovery.
+pub struct Ring {
+    segment_size: u64,
+    retention: Retention,
+    back_pressure: BackPressure,
+    writer: Arc<MutLock>,
+    appender: Mutex<Appender>,
+    segments: Arc<Mutex<Segments>>,
+    worker: Option<Worker>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+/// Exclusive right to append into a specific ring buffer.
+///
+/// Just like the writer of [`Storage`], it is owned and can be sent to other threads.
+pub struct Writer(OwnedMutGuard);
+
+/// What appends need, only ever used by the writer of the ring.
+struct Appender {
+    block: Block,
+    full: bool,
+    active: Option<(Arc<Active>, storage::Writer)>,
+}
+
+/// What appends do when reclamation is held back by consumers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackPressure {
+    /// Reclaim regardless of consumers, they find out about lost log records instead.
+    #[default]
+    Overwrite,
+
+    /// Wait for consumers to catch up, up to a timeout if any.
+    Block(Option<Duration>),
+
+    /// Fail appends with [`ErrorKind::StorageFull`].
+    Fail,
+}
+
+/// Segments of the ring, along with what retention policies need to know about them.
+struct Segments {
+    manifest: Manifest,
+    sealed: Vec<SegmentStats>,
+    active: Option<Arc<Active>>,
+    last: Option<u64>,
+    truncated: Vec<u64>,
+}
+
+/// Intervals of maintenance tasks of a ring buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Intervals {
+    /// Time between syncs of the active segment to disk.
+    pub sync: Duration,
+
+    /// Time between enforcing the retention policy.
+    pub reclaim: Duration,
+
+    /// Time between persisting the sparse index of the active segment.
+    pub index: Duration,
+}
+
+/// Reads log records from a ring buffer, following it across segments.
+///
+/// Unless the ring has [back-pressure](Ring#back-pressure), segments are reclaimed
+/// without waiting for readers. When segments a reader has not read yet are
+/// reclaimed, it reports [`Event::Lost`] with the sequence numbers and bytes it
+/// skipped, and continues from the oldest log record still available. A segment
+/// stays readable while it is being read, even if it is reclaimed in the meantime.
+///
+/// When the ring is [truncated](Ring::truncate_after), readers that have not read
+/// past the sequence number continue as if nothing happened. The rest fail with
+/// [`ErrorKind::InvalidData`], since log records they read are gone.
+pub struct Reader {
+    segments: Arc<Mutex<Segments>>,
+    next_seq_no: u64,
+    truncations: usize,
+    current: Option<Current>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+/// Segment a reader is reading.
+struct Current {
+    segment: Segment,
+    source: Source,
+    offset: u64,
+    end: Option<u64>,
+}
+
+/// Storage of the segment a reader is reading.
+enum Source {
+    /// Segment was active when the reader got to it, and might still be.
+    Active(Arc<Active>),
+
+    /// Segment was sealed when the reader got to it.
+    Sealed(Storage),
+}
+
+/// Segment being appended into.
+struct Active {
+    storage: Storage,
+    records: AtomicU64,
+    sealed: bool,
+    next_index: AtomicU64,
+    index: Mutex<Index>,
+}
+
+/// Sparse index of the active segment, persisted next to it.
+#[derive(Default)]
+struct Index {
+    entries: Vec<Entry>,
+    dirty: bool,
+}
+
+/// Entry in the sparse index of the active segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    offset: u64,
+    seq_no: u64,
+    records: u64,
+}
+
+impl Ring {
+    /// Default number of bytes after which segments roll.
+    pub const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Time between checks for consumers catching up, while the ring is full.
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// Extension of files holding the sparse index of the active segment.
+    const INDEX_EXTENSION: &str = "index";
+
+    /// Open a ring buffer in a directory, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Directory of the ring buffer, must already exist.
+    /// * `block` - Block to encode log records with, keys are also used to read blocks.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip_all, fields(dir = %dir.as_ref().display()), err)
+    )]
+    pub fn open<P: AsRef<Path>>(dir: P, block: Block) -> Result<Self> {
+        let (manifest, _) = Manifest::open(dir)?;
+        let mut last = None;
+
+        // Sealed segments are summarized by their footer.
+        let mut sealed = Vec::new();
+        for segment in manifest.segments() {
+            if segment.state != State::Sealed {
+                continue;
+            }
+
+            let path = manifest.path(segment.base);
+            let storage = Storage::open_read_only(&path)?;
+            let Some(footer) = Footer::read(&storage)? else {
+                let error = format!("Sealed segment has no footer: {}", path.display());
+                return Err(Error::new(ErrorKind::InvalidData, error));
+            };
+
+            last = footer.last().or(last);
+            sealed.push(SegmentStats {
+                len: segment.len,
+                records: footer.count(),
+                modified: fs::metadata(&path)?.modified()?,
+            });
+        }
+
+        let active = manifest
+            .active()
+            .filter(|active| active.state == State::Active);
+        Self::remove_stale_indexes(&manifest, active.map(|active| active.base))?;
+        let active = match active {
+            None => None,
+            Some(segment) => {
+                let (active, writer, active_last) = Self::recover(&manifest, segment, &block)?;
+                last = active_last.or(last);
+                Some((Arc::new(active), writer))
+            }
+        };
+
+        let segments = Segments {
+            last,
+            manifest,
+            sealed,
+            truncated: Vec::new(),
+            active: active.as_ref().map(|(active, _)| active.clone()),
+        };
+
+        Ok(Self {
+            worker: None,
+            retention: Retention::default(),
+            back_pressure: BackPressure::default(),
+            segment_size: Self::SEGMENT_SIZE,
+            writer: Arc::new(MutLock::fair()),
+            segments: Arc::new(Mutex::new(segments)),
+            #[cfg(feature = "encryption")]
+            keyring: block.keyring().cloned(),
+            appender: Mutex::new(Appender {
+                block,
+                active,
+                full: false,
+            }),
+        })
+    }
+
+    /// Roll segments once they grow past a number of bytes.
+    ///
+    /// A segment can grow past this by up to a block, segments always have at least
+    /// one block.
+    ///
+    /// # Arguments
+    ///
+    /// * `segment_size` - Number of bytes after which segments roll.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Reclaim oldest segments according to a retention policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - Limits on how much the ring buffer retains.
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Hold back reclamation for consumers that have not caught up.
+    ///
+    /// See [back-pressure](Ring#back-pressure) for details.
+    ///
+    /// # Arguments
+    ///
+    /// * `back_pressure` - What appends do when the ring is full.
+    pub fn with_back_pressure(mut self, back_pressure: BackPressure) -> Self {
+        self.back_pressure = back_pressure;
+        let appender = self.appender.get_mut().unwrap_or_else(|e| e.into_inner());
+        appender.full = back_pressure != BackPressure::Overwrite;
+        self
+    }
+
+    /// Sequence number of the oldest log record retained, if any.
+    pub fn first(&self) -> Option<u64> {
+        let segments = self.lock();
+        segments.last?;
+        segments
+            .manifest
+            .segments()
+            .first()
+            .map(|segment| segment.base)
+    }
+
+    /// Sequence number of the newest log record, if any.
+    pub fn last(&self) -> Option<u64> {
+        self.lock().last
+    }
+
+    /// Number of bytes in segments of the ring buffer, including the active one.
+    pub fn size(&self) -> u64 {
+        let segments = self.lock();
+        let sealed = segments.sealed.iter().map(|stats| stats.len).sum::<u64>();
+        let active = segments.active.as_ref();
+        sealed + active.map_or(0, |active| active.storage.len())
+    }
+
+    /// Segments of the ring buffer, oldest first.
+    pub fn segments(&self) -> Vec<Segment> {
+        self.lock().manifest.segments().to_vec()
+    }
+
+    /// Obtain the writer of the ring buffer.
+    ///
+    /// Returns None if the writer is already taken. It can be taken again once dropped.
+    pub fn writer(&self) -> Option<Writer> {
+        self.writer.try_lock_owned().map(Writer)
+    }
+
+    /// Obtain the writer of the ring buffer, waiting until it is dropped if already taken.
+    pub fn writer_blocking(&self) -> Writer {
+        Writer(self.writer.lock_owned())
+    }
+
+    /// Obtain the writer of the ring buffer, waiting at most for a timeout if already taken.
+    ///
+    /// Returns None if the writer was not dropped in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait for the writer.
+    pub fn writer_timeout(&self, timeout: Duration) -> Option<Writer> {
+        self.writer.lock_timeout_owned(timeout).map(Writer)
+    }
+
+    /// Append a batch of log records.
+    ///
+    /// Returns an error if log records are not newer than the last one appended, or if
+    /// the writer belongs to some other ring. With back-pressure, this either waits for
+    /// consumers to catch up when the ring is full, or fails with [`ErrorKind::StorageFull`].
+    /// Waiting fails with [`ErrorKind::TimedOut`] if consumers do not catch up in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Batch of log records to append.
+    /// * `writer` - Writer of the ring buffer, for exclusive appends.
+    pub fn append(&self, logs: &LogBuf, writer: &mut Writer) -> Result<()> {
+        if !self.owns(writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different ring"));
+        }
+
+        let Some(first) = logs.first() else {
+            return Ok(());
+        };
+
+        if self.last().is_some_and(|last| first <= last) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(
+                kind,
+                "Log records must be newer than the last one",
+            ));
+        }
+
+        let mut appender = self.appender.lock().unwrap_or_else(|e| e.into_inner());
+        let appender = &mut *appender;
+        appender.block.encode(logs)?;
+        let len = appender.block.len() as u64;
+        let roll = match &appender.active {
+            None => true,
+            Some((active, _)) => {
+                let size = active.storage.len();
+                active.sealed || (size > 0 && size + len > self.segment_size)
+            }
+        };
+
+        if roll {
+            self.roll(appender, first)?;
+        }
+
+        if roll || appender.full {
+            self.make_room(appender)?;
+        }
+
+        let (active, writer) = appender
+            .active
+            .as_mut()
+            .expect("Should have active segment");
+        let (offset, records) = (active.storage.len(), active.records.load(Relaxed));
+        if offset >= active.next_index.load(Relaxed) {
+            active.index(Entry {
+                offset,
+                records,
+                seq_no: first,
+            });
+        }
+
+        active
+            .storage
+            .append_block(appender.block.bytes(), logs.count(), writer)?;
+        active.records.fetch_add(logs.count() as u64, Relaxed);
+        self.lock().last = logs.last();
+        Ok(())
+    }
+
+    /// Returns true if the writer belongs to this ring buffer, false otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Writer to check.
+    pub(crate) fn owns(&self, writer: &Writer) -> bool {
+        Arc::ptr_eq(&self.writer, &(writer.0).0)
+    }
+
+    /// Read log records, starting from a sequence number.
+    ///
+    /// # Arguments
+    ///
+    /// * `next_seq_no` - Sequence number of the first log record to read.
+    pub fn reader(&self, next_seq_no: u64) -> Reader {
+        Reader {
+            next_seq_no,
+            current: None,
+            truncations: self.lock().truncated.len(),
+            segments: self.segments.clone(),
+            #[cfg(feature = "encryption")]
+            keyring: self.keyring.clone(),
+        }
+    }
+
+    /// Remove every log record after a sequence number.
+    ///
+    /// Segment with the sequence number becomes the active one again, and newer
+    /// segments are deleted. Log records after the sequence number are removed from
+    /// the segment with [`truncate::truncate_after`], starting from the block its
+    /// sparse index points at. Returns the sequence number of the last log record
+    /// that remains, appends continue after it.
+    ///
+    /// Returns an error if the sequence number is older than the oldest segment, or if
+    /// the writer belongs to some other ring. If truncation fails midway, reopen the
+    /// ring before appending again. A crash can leave behind log records up to some
+    /// point after the sequence number, truncate again after reopening.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_no` - Sequence number of the last log record to keep.
+    /// * `writer` - Writer of the ring buffer, for exclusive appends.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip(self, writer), err)
+    )]
+    pub fn truncate_after(&self, seq_no: u64, writer: &mut Writer) -> Result<Option<u64>> {
+        if !self.owns(writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different ring"));
+        }
+
+        let mut appender = self.appender.lock().unwrap_or_else(|e| e.into_inner());
+        let appender = &mut *appender;
+        let mut segments = self.lock();
+        if segments.last.is_none_or(|last| last <= seq_no) {
+            return Ok(segments.last);
+        }
+
+        let all = segments.manifest.segments();
+        let index = all.partition_point(|segment| segment.base <= seq_no);
+        let Some(segment) = index.checked_sub(1).map(|index| all[index]) else {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(
+                kind,
+                "Sequence number is older than the oldest segment",
+            ));
+        };
+
+        // Readers find out before segments change, and they wait for the lock on
+        // segments to find out where to continue from.
+        segments.truncated.push(seq_no);
+        segments.active = None;
+        let mut offset = 0;
+        if let Some((active, _)) = appender.active.take() {
+            if segment.state == State::Active {
+                let index = active.index.lock().unwrap_or_else(|e| e.into_inner());
+                let entries = &index.entries;
+                let i = entries.partition_point(|entry| entry.seq_no <= seq_no);
+                offset = i.checked_sub(1).map_or(0, |i| entries[i].offset);
+            }
+
+            remove(&active.storage.path().with_extension(Self::INDEX_EXTENSION))?;
+        }
+
+        segments.manifest.truncate(segment.base)?;
+        segments.sealed.truncate(index - 1);
+
+        // Footer of a sealed segment goes first, blocks after it are appended into.
+        let mut storage = Storage::open(segments.manifest.path(segment.base))?;
+        let mut storage_writer = storage.writer().expect("Should obtain writer");
+        if let Some(footer) = Footer::read(&storage)? {
+            offset = footer.offset(seq_no);
+            storage.truncate(footer.len())?;
+        }
+
+        let block = &mut appender.block;
+        truncate::truncate_after(&mut storage, offset, seq_no, block, &mut storage_writer)?;
+        drop(storage_writer);
+        storage.close()?;
+
+        let (active, storage_writer, last) = Self::recover(&segments.manifest, &segment, block)?;
+        let active = Arc::new(active);
+        segments.active = Some(active.clone());
+        segments.last = last;
+        appender.active = Some((active, storage_writer));
+        event!(info, seq_no, base = segment.base, "Truncated ring");
+        Ok(last)
+    }
+
+    /// Sync the active segment to disk.
+    ///
+    /// Sealed segments are synced when they roll.
+    pub fn sync(&self) -> Result<()> {
+        let active = self.lock().active.clone();
+        active.map_or(Ok(()), |active| active.storage.sync())
+    }
+
+    /// Reclaim oldest segments that the retention policy no longer retains.
+    ///
+    /// Returns what was reclaimed, and why. With back-pressure, segments with log
+    /// records that consumers have not consumed yet are not reclaimed.
+    pub fn reclaim(&self) -> Result<Reclaim> {
+        let (reclaim, _) = self.enforce()?;
+        Ok(reclaim)
+    }
+
+    /// Maintenance tasks of the ring buffer.
+    ///
+    /// Tasks sync the active segment, enforce the retention policy and persist the
+    /// sparse index of the active segment. Drive them explicitly with
+    /// [`Maintenance::tick`], or use [`Ring::spawn_maintenance`] instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `intervals` - Intervals of the tasks.
+    pub fn maintenance(&self, intervals: Intervals) -> Maintenance {
+        let (retention, back_pressure) = (self.retention, self.back_pressure);
+        let (sync, reclaim, index) = (
+            self.segments.clone(),
+            self.segments.clone(),
+            self.segments.clone(),
+        );
+
+        Maintenance::new()
+            .with_task("sync", intervals.sync, move || {
+                let active = lock(&sync).active.clone();
+                active.map_or(Ok(()), |active| active.storage.sync())
+            })
+            .with_task("reclaim", intervals.reclaim, move || {
+                enforce(&reclaim, &retention, back_pressure).map(|_| ())
+            })
+            .with_task("index", intervals.index, move || flush_index(&index))
+    }
+
+    /// Run maintenance tasks of the ring buffer in a background thread.
+    ///
+    /// The thread is owned by the ring buffer, and stops when it is closed. Replaces
+    /// the thread started before, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `intervals` - Intervals of the tasks.
+    /// * `on_error` - Callback invoked with errors from tasks.
+    pub fn spawn_maintenance<F>(&mut self, intervals: Intervals, on_error: F) -> Result<()>
+    where
+        F: FnMut(Error) + Send + 'static,
+    {
+        if let Some(worker) = self.worker.take() {
+            worker.close()?;
+        }
+
+        self.worker = Some(self.maintenance(intervals).spawn(on_error)?);
+        Ok(())
+    }
+
+    /// Stop maintenance, sync the active segment, and close the ring buffer.
+    ///
+    /// Returns the first error from the final run of maintenance tasks, if any.
+    pub fn close(mut self) -> Result<()> {
+        if let Some(worker) = self.worker.take() {
+            worker.close()?;
+        }
+
+        self.sync()
+    }
+
+    /// Seal the active segment, and start a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `appender` - What appends need.
+    /// * `base` - Sequence number of the first log record in the new segment.
+    fn roll(&self, appender: &mut Appender, base: u64) -> Result<()> {
+        let mut segments = self.lock();
+        let storage = match appender.active.as_mut() {
+            None => segments.manifest.roll(None, base)?,
+            Some((active, writer)) => {
+                let mut cursor = Cursor::new(&active.storage, 0);
+                #[cfg(feature = "encryption")]
+                if let Some(keyring) = &self.keyring {
+                    cursor = cursor.with_keyring(keyring);
+                }
+
+                let storage = segments.manifest.roll(Some((&mut cursor, writer)), base)?;
+                let path = active.storage.path();
+                remove(&path.with_extension(Self::INDEX_EXTENSION))?;
+                segments.sealed.push(SegmentStats {
+                    len: active.storage.len(),
+                    records: active.records.load(Relaxed),
+                    modified: fs::metadata(path)?.modified()?,
+                });
+
+                storage
+            }
+        };
+
+        let writer = storage
+            .writer()
+            .expect("Should obtain writer of new segment");
+        let active = Arc::new(Active::new(storage, 0, false, Vec::new()));
+
+        segments.active = Some(active.clone());
+        appender.active = Some((active, writer));
+        Ok(())
+    }
+
+    /// Reclaim segments until the ring is no longer full, as back-pressure allows.
+    ///
+    /// # Arguments
+    ///
+    /// * `appender` - What appends need.
+    fn make_room(&self, appender: &mut Appender) -> Result<()> {
+        let deadline = match self.back_pressure {
+            BackPressure::Block(Some(timeout)) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+
+        loop {
+            let (_, full) = self.enforce()?;
+            appender.full = full;
+            if !full {
+                return Ok(());
+            }
+
+            match self.back_pressure {
+                BackPressure::Overwrite => return Ok(()),
+                BackPressure::Fail => {
+                    let kind = ErrorKind::StorageFull;
+                    return Err(Error::new(kind, "Ring is full, consumers must catch up"));
+                }
+                BackPressure::Block(_) if deadline.is_some_and(|at| Instant::now() >= at) => {
+                    let kind = ErrorKind::TimedOut;
+                    return Err(Error::new(kind, "Ring is full, consumers did not catch up"));
+                }
+                BackPressure::Block(_) => thread::sleep(Self::POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Reclaim segments as retention policy and back-pressure allow.
+    ///
+    /// Returns what was reclaimed, and true if the ring is full.
+    fn enforce(&self) -> Result<(Reclaim, bool)> {
+        enforce(&self.segments, &self.retention, self.back_pressure)
+    }
+
+    /// Open the active segment, removing an incomplete block at its end.
+    ///
+    /// Blocks are scanned from the newest intact block in the persisted index, if any.
+    /// Returns the segment, its writer and the sequence number of its last log record.
+    ///
+    /// # Arguments
+    ///
+    /// * `manifest` - Manifest of the ring buffer.
+    /// * `segment` - Active segment in the manifest.
+    /// * `block` - Block with keys to read blocks with.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    fn recover(
+        manifest: &Manifest,
+        segment: &Segment,
+        block: &Block,
+    ) -> Result<(Active, storage::Writer, Option<u64>)> {
+        let path = manifest.path(segment.base);
+
+        // Sealed by a roll that was interrupted before updating the manifest.
+        let storage = Storage::open_read_only(&path)?;
+        if let Some(footer) = Footer::read(&storage)? {
+            let writer = storage.writer().expect("Should obtain writer");
+            let active = Active::new(storage, footer.count(), true, Vec::new());
+            return Ok((active, writer, footer.last()));
+        }
+
+        let mut storage = Storage::open(&path)?;
+        let writer = storage.writer().expect("Should obtain writer");
+        let cursor = |offset| {
+            let cursor = Cursor::new(&storage, offset);
+            #[cfg(feature = "encryption")]
+            if let Some(keyring) = block.keyring() {
+                return cursor.with_keyring(keyring);
+            }
+
+            cursor
+        };
+
+        // Resume from the newest indexed block that is still intact.
+        let mut logs = LogBuf::with_capacity(0);
+        let mut entries = read_index(&path.with_extension(Self::INDEX_EXTENSION))?;
+        let persisted = entries.len();
+        while let Some(entry) = entries.last() {
+            let intact = cursor(entry.offset).next(&mut logs).unwrap_or(false);
+            if intact && logs.first() == Some(entry.seq_no) {
+                break;
+            }
+
+            entries.pop();
+        }
+
+        let dirty = entries.len() != persisted;
+        let (offset, mut records) = entries
+            .last()
+            .map_or((0, 0), |entry| (entry.offset, entry.records));
+
+        // Scan stops at the first block that is incomplete, or zeroed out by a crash.
+        let mut last = None;
+        let mut cursor = cursor(offset);
+        loop {
+            let offset = cursor.offset();
+            match cursor.next(&mut logs) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(error) if torn(&error) => break,
+                Err(error) => return Err(error),
+            }
+
+            let indexed = entries.last().map(|entry| entry.offset);
+            if indexed.is_none_or(|indexed| offset - indexed >= INDEX_INTERVAL) {
+                entries.push(Entry {
+                    offset,
+                    records,
+                    seq_no: logs.first().expect("Should have logs"),
+                });
+            }
+
+            records += logs.count() as u64;
+            last = logs.last();
+        }
+
+        // Whatever is left was torn by a crash.
+        let end = cursor.offset();
+        if end < storage.len() {
+            let removed = storage.len() - end;
+            metrics::record(|recorder| recorder.corrupted(removed));
+            event!(
+                info,
+                offset = end,
+                removed,
+                reason = "incomplete block",
+                "Removing end of storage"
+            );
+
+            storage.truncate(end)?;
+            storage.sync()?;
+        }
+
+        let dirty = dirty || entries.len() != persisted;
+        let active = Active::new(storage, records, false, entries);
+        active.index.lock().unwrap_or_else(|e| e.into_inner()).dirty = dirty;
+        Ok((active, writer, last))
+    }
+
+    /// Remove indexes of segments other than the active one.
+    ///
+    /// These are leftovers from rolls interrupted before the index was removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `manifest` - Manifest of the ring buffer.
+    /// * `active` - Base of the active segment, if any.
+    fn remove_stale_indexes(manifest: &Manifest, active: Option<u64>) -> Result<()> {
+        let index = active.map(|base| manifest.path(base).with_extension(Self::INDEX_EXTENSION));
+        for entry in fs::read_dir(manifest.dir())? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            let stale =
+                name.is_some_and(|name| name.ends_with(".index") || name.ends_with(".index.tmp"));
+
+            if stale && index.as_ref().is_none_or(|index| *index != path) {
+                remove(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lock segments of the ring buffer.
+    fn lock(&self) -> MutexGuard<'_, Segments> {
+        lock(&self.segments)
+    }
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Self {
+            sync: Duration::from_secs(1),
+            reclaim: Duration::from_secs(10),
+            index: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Active {
+    /// Create the active segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage of the segment.
+    /// * `records` - Number of log records in the segment.
+    /// * `sealed` - True if the segment was sealed by an interrupted roll.
+    /// * `entries` - Sparse index of the segment.
+    fn new(storage: Storage, records: u64, sealed: bool, entries: Vec<Entry>) -> Self {
+        let next_index = entries
+            .last()
+            .map_or(0, |entry| entry.offset + INDEX_INTERVAL);
+
+        Self {
+            storage,
+            sealed,
+            records: AtomicU64::new(records),
+            next_index: AtomicU64::new(next_index),
+            index: Mutex::new(Index {
+                entries,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Add a block to the sparse index.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - Entry of the block.
+    fn index(&self, entry: Entry) {
+        let mut index = self.index.lock().unwrap_or_else(|e| e.into_inner());
+        index.entries.push(entry);
+        index.dirty = true;
+        self.next_index
+            .store(entry.offset + INDEX_INTERVAL, Relaxed);
+    }
+}
+
+impl Segments {
+    /// Reclaim oldest segments that a retention policy no longer retains.
+    ///
+    /// Returns what was reclaimed, and true if segments had to be retained for
+    /// consumers even though they exceed limits on bytes or log records.
+    ///
+    /// # Arguments
+    ///
+    /// * `retention` - Limits on how much the ring buffer retains.
+    /// * `now` - Current time, to figure out age of segments.
+    /// * `needed` - Sequence number of the oldest log record consumers still need, if any.
+    fn reclaim(
+        &mut self,
+        retention: &Retention,
+        now: SystemTime,
+        needed: Option<u64>,
+    ) -> Result<(Reclaim, bool)> {
+        let mut stats = self.sealed.clone();
+        if let Some(active) = &self.active {
+            stats.push(SegmentStats {
+                len: active.storage.len(),
+                records: active.records.load(Relaxed),
+                modified: now,
+            });
+        }
+
+        // Segments can be reclaimed once every log record in them is consumed.
+        let mut reasons = retention.reasons(&stats, now);
+        if let Some(needed) = needed {
+            let segments = self.manifest.segments().iter().skip(1);
+            let consumed = segments
+                .take_while(|segment| segment.base <= needed)
+                .count();
+            reasons.truncate(consumed);
+        }
+
+        let retained = retention.reasons(&stats[reasons.len()..], now);
+        let full = retained.iter().any(|reason| *reason != Reason::Age);
+        let mut reclaim = Reclaim::default();
+        for (segment, reason) in stats.iter().zip(reasons) {
+            reclaim.add(segment, reason);
+        }
+
+        if reclaim.is_empty() {
+            return Ok((reclaim, full));
+        }
+
+        self.manifest.reclaim(reclaim.segments)?;
+        self.sealed.drain(..reclaim.segments);
+        metrics::record(|recorder| {
+            for (reason, segments) in [
+                (Reason::Age, reclaim.by_age),
+                (Reason::Bytes, reclaim.by_bytes),
+                (Reason::Records, reclaim.by_records),
+            ] {
+                if segments > 0 {
+                    recorder.reclaimed_by(reason, segments as u64);
+                }
+            }
+        });
+
+        Ok((reclaim, full))
+    }
+}
+
+impl Reader {
+    /// Read the next block of log records, reporting log records that were lost.
+    ///
+    /// Returns [`Event::Pending`] once the reader caught up with appends. The first
+    /// block read can have log records before the sequence number the reader started
+    /// from, just like with [`Cursor::poll`].
+    ///
+    /// Number of bytes lost is the number of bytes in reclaimed segments the reader
+    /// skipped. It is unknown if log records were reclaimed before the reader started.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Buffer to read log records into.
+    pub fn poll(&mut self, logs: &mut LogBuf) -> Result<Event> {
+        loop {
+            // Whatever was read while the ring was truncated is read again.
+            let (truncations, next_seq_no) = (self.follow_truncation()?, self.next_seq_no);
+            let event = self.read(logs);
+            if lock(&self.segments).truncated.len() == truncations {
+                return event;
+            }
+
+            logs.clear();
+            self.next_seq_no = next_seq_no;
+        }
+    }
+
+    /// Start over if the ring was truncated since the last read.
+    ///
+    /// Returns the number of truncations so far, or an error if log records that
+    /// were read are gone.
+    fn follow_truncation(&mut self) -> Result<usize> {
+        let segments = lock(&self.segments);
+        let truncated = &segments.truncated;
+        if self.truncations == truncated.len() {
+            return Ok(self.truncations);
+        }
+
+        let seq_no = truncated[self.truncations..].iter().min();
+        if seq_no.is_some_and(|seq_no| self.next_seq_no > seq_no.saturating_add(1)) {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Log records that were read are truncated"));
+        }
+
+        self.truncations = truncated.len();
+        self.current = None;
+        Ok(self.truncations)
+    }
+
+    /// Read the next block of log records, reporting log records that were lost.
+    ///
+    /// # Arguments
+    ///
+    /// * `logs` - Buffer to read log records into.
+    fn read(&mut self, logs: &mut LogBuf) -> Result<Event> {
+        loop {
+            let Some(current) = &mut self.current else {
+                // Start from the segment with the log record, or the oldest one.
+                let segments = lock(&self.segments);
+                let all = segments.manifest.segments();
+                let Some(first) = all.first().map(|segment| segment.base) else {
+                    return Ok(Event::Pending);
+                };
+
+                let index = all.partition_point(|segment| segment.base <= self.next_seq_no);
+                let segment = all[index.saturating_sub(1)];
+                self.current = Some(Current::open(&segments, segment, self.next_seq_no)?);
+                drop(segments);
+                if self.next_seq_no < first {
+                    return Ok(self.lost(first, None));
+                }
+
+                continue;
+            };
+
+            // Active segment can roll anytime, which seals it with a footer after
+            // the last block. Only the blocks are read.
+            let end = match (&current.source, current.end) {
+                (Source::Active(active), None) => {
+                    let segments = lock(&self.segments);
+                    let rolled = segments
+                        .active
+                        .as_ref()
+                        .is_none_or(|newest| !Arc::ptr_eq(newest, active));
+
+                    if rolled || active.sealed {
+                        let end = Current::footer(&active.storage)?.len();
+                        current.end = Some(end);
+                        end
+                    } else {
+                        active.storage.len()
+                    }
+                }
+                (_, end) => end.expect("Sealed segment should have an end"),
+            };
+
+            let cursor = Cursor::new(current.storage(), current.offset);
+            #[cfg(feature = "encryption")]
+            let cursor = match &self.keyring {
+                None => cursor,
+                Some(keyring) => cursor.with_keyring(keyring),
+            };
+
+            let mut cursor = cursor.with_end(end);
+            if cursor.next(logs)? {
+                current.offset = cursor.offset();
+                let Some(last) = logs.last() else {
+                    let kind = ErrorKind::InvalidData;
+                    return Err(Error::new(kind, "Block has no log records"));
+                };
+
+                if last < self.next_seq_no {
+                    continue;
+                }
+
+                self.next_seq_no = last.saturating_add(1);
+                return Ok(Event::Logs);
+            }
+
+            // Caught up with the active segment.
+            if current.end.is_none() {
+                return Ok(Event::Pending);
+            }
+
+            // Continue with the next segment that was not reclaimed.
+            let segments = lock(&self.segments);
+            let base = current.segment.base;
+            let next = segments
+                .manifest
+                .segments()
+                .iter()
+                .find(|segment| segment.base > base);
+
+            let Some(next) = next.copied() else {
+                return Ok(Event::Pending);
+            };
+
+            let end = current.segment.offset + current.storage().len();
+            self.current = Some(Current::open(&segments, next, next.base)?);
+            drop(segments);
+            if next.offset > end {
+                return Ok(self.lost(next.base, Some(next.offset - end)));
+            }
+        }
+    }
+
+    /// Skip lost log records, and report them.
+    ///
+    /// # Arguments
+    ///
+    /// * `first` - Sequence number of the oldest log record still available.
+    /// * `bytes` - Number of bytes lost, if known.
+    fn lost(&mut self, first: u64, bytes: Option<u64>) -> Event {
+        let from = self.next_seq_no;
+        self.next_seq_no = first;
+        event!(
+            warn,
+            from,
+            to = first - 1,
+            bytes,
+            "Log records were lost before they could be read"
+        );
+
+        Event::Lost {
+            from,
+            to: first - 1,
+            bytes,
+        }
+    }
+}
+
+impl Current {
+    /// Start reading a segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `segments` - Segments of the ring buffer.
+    /// * `segment` - Segment to read.
+    /// * `seq_no` - Sequence number of the first log record to read.
+    fn open(segments: &Segments, segment: Segment, seq_no: u64) -> Result<Self> {
+        let active = segments
+            .active
+            .as_ref()
+            .filter(|active| segment.state == State::Active && !active.sealed);
+
+        if let Some(active) = active {
+            let index = active.index.lock().unwrap_or_else(|e| e.into_inner());
+            let entries = &index.entries;
+            let offset = match entries.partition_point(|entry| entry.seq_no <= seq_no) {
+                0 => 0,
+                i => entries[i - 1].offset,
+            };
+
+            return Ok(Self {
+                segment,
+                offset,
+                end: None,
+                source: Source::Active(active.clone()),
+            });
+        }
+
+        let storage = Storage::open_read_only(segments.manifest.path(segment.base))?;
+        let footer = Self::footer(&storage)?;
+        Ok(Self {
+            segment,
+            offset: footer.offset(seq_no),
+            end: Some(footer.len()),
+            source: Source::Sealed(storage),
+        })
+    }
+
+    /// Storage of the segment.
+    fn storage(&self) -> &Storage {
+        match &self.source {
+            Source::Active(active) => &active.storage,
+            Source::Sealed(storage) => storage,
+        }
+    }
+
+    /// Footer of a sealed segment.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage of the segment.
+    fn footer(storage: &Storage) -> Result<Footer> {
+        Footer::read(storage)?.ok_or_else(|| {
+            let error = format!("Sealed segment has no footer: {}", storage.path().display());
+            Error::new(ErrorKind::InvalidData, error)
+        })
+    }
+}
+
+/// Lock segments of a ring buffer.
+///
+/// # Arguments
+///
+/// * `segments` - Segments of the ring buffer.
+fn lock(segments: &Mutex<Segments>) -> MutexGuard<'_, Segments> {
+    segments.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reclaim segments as retention policy and back-pressure allow.
+///
+/// Returns what was reclaimed, and true if the ring is full.
+///
+/// # Arguments
+///
+/// * `segments` - Segments of the ring buffer.
+/// * `retention` - Limits on how much the ring buffer retains.
+/// * `back_pressure` - Whether consumers hold back reclamation.
+fn enforce(
+    segments: &Mutex<Segments>,
+    retention: &Retention,
+    back_pressure: BackPressure,
+) -> Result<(Reclaim, bool)> {
+    let mut segments = lock(segments);
+    let needed = match back_pressure {
+        BackPressure::Overwrite => None,
+        _ => Consumer::slowest(segments.manifest.dir())?,
+    };
+
+    segments.reclaim(retention, SystemTime::now(), needed)
+}
+
+/// Persist the sparse index of the active segment, if it changed.
+///
+/// Index is replaced atomically, the same way as the manifest.
+///
+/// # Arguments
+///
+/// * `segments` - Segments of the ring buffer.
+fn flush_index(segments: &Mutex<Segments>) -> Result<()> {
+    // Segments stay locked, so that the segment does not roll in the meantime.
+    let segments = lock(segments);
+    let Some(active) = segments.active.as_ref().filter(|active| !active.sealed) else {
+        return Ok(());
+    };
+
+    // Appends are not held back while the index is written.
+    let entries = {
+        let index = active.index.lock().unwrap_or_else(|e| e.into_inner());
+        if !index.dirty {
+            return Ok(());
+        }
+
+        index.entries.clone()
+    };
+
+    let mut bytes = Vec::with_capacity(entries.len() * 24 + 4);
+    for entry in &entries {
+        bytes.extend_from_slice(&entry.offset.to_be_bytes());
+        bytes.extend_from_slice(&entry.seq_no.to_be_bytes());
+        bytes.extend_from_slice(&entry.records.to_be_bytes());
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(&bytes);
+    bytes.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+    // Write new index into a temporary file, then replace the previous one.
+    let path = active.storage.path().with_extension(Ring::INDEX_EXTENSION);
+    let tmp_path = path.with_extension("index.tmp");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(segments.manifest.dir())?.sync_all()?;
+
+    let mut index = active.index.lock().unwrap_or_else(|e| e.into_inner());
+    index.dirty = index.entries.len() != entries.len();
+    Ok(())
+}
+
+/// Read a persisted sparse index.
+///
+/// Returns an empty index if there is none, or if it is corrupted.
+///
+/// # Arguments
+///
+/// * `path` - Path to the index.
+fn read_index(path: &Path) -> Result<Vec<Entry>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let Some((bytes, checksum)) = bytes.split_last_chunk::<4>() else {
+        return Ok(Vec::new());
+    };
+
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    if hasher.finalize() != u32::from_be_bytes(*checksum) || bytes.len() % 24 != 0 {
+        return Ok(Vec::new());
+    }
+
+    let read_u64 = |bytes: &[u8]| u64::from_be_bytes(bytes.try_into().expect("Should never fail"));
+    let entries = bytes
+        .chunks_exact(24)
+        .map(|entry| Entry {
+            offset: read_u64(&entry[..8]),
+            seq_no: read_u64(&entry[8..16]),
+            records: read_u64(&entry[16..]),
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/// Whether an error reading a block is due to a crash in the middle of writing it.
+///
+/// Blocks that are zeroed out or otherwise invalid count, tampered blocks do not.
+///
+/// # Arguments
+///
+/// * `error` - Error reading the block.
+fn torn(error: &Error) -> bool {
+    #[cfg(feature = "encryption")]
+    if crate::crypto::TamperedError::matches(error) {
+        return false;
+    }
+
+    error.kind() == ErrorKind::InvalidData
+}
+
+/// Remove a file, if it exists.
+///
+/// # Arguments
+///
+/// * `path` - Path to the file.
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{block::Codec, log::Log, seal};
+    use anyhow::{Result, anyhow};
+    use tempfile::tempdir;
+
+    fn append(ring: &Ring, seq_nos: std::ops::RangeInclusive<u64>) -> Result<()> {
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        let mut writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        Ok(ring.append(&logs, &mut writer)?)
+    }
+
+    fn open(dir: &Path) -> Result<Ring> {
+        Ok(Ring::open(dir, Block::new(Codec::default()))?.with_segment_size(1))
+    }
+
+    #[test]
+    fn append_rolls_segments() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        assert_eq!((None, None), (ring.first(), ring.last()));
+
+        // Every block rolls a new segment, sealing the previous one.
+        for seq_no in (1..=30).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        let segments = ring.segments();
+        let bases: Vec<_> = segments.iter().map(|segment| segment.base).collect();
+        assert_eq!(vec![1, 11, 21], bases);
+        assert_eq!(State::Sealed, segments[1].state);
+        assert_eq!(State::Active, segments[2].state);
+        assert_eq!((Some(1), Some(30)), (ring.first(), ring.last()));
+
+        // Log records must be newer than the last one.
+        let error = append(&ring, 30..=31).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.downcast::<Error>()?.kind());
+        ring.close()?;
+
+        // Everything survives reopening.
+        let ring = open(dir.path())?;
+        assert_eq!((Some(1), Some(30)), (ring.first(), ring.last()));
+        append(&ring, 31..=40)?;
+        assert_eq!(4, ring.segments().len());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn writer_is_exclusive_and_shared_across_threads() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        let writer = ring.writer_blocking();
+        assert!(ring.writer().is_none());
+        assert!(ring.writer_timeout(Duration::from_millis(10)).is_none());
+
+        // Writers of other rings are rejected.
+        let other = tempdir()?;
+        let other = open(other.path())?;
+        let mut logs = LogBuf::with_capacity(1024);
+        assert!(logs.append(&Log::new_borrowed(1, b"Batman")));
+        let mut other_writer = other.writer_blocking();
+        let error = ring.append(&logs, &mut other_writer).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!(None, ring.last());
+
+        // Waiters obtain the writer once dropped, and append from their own thread.
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let mut writer = ring.writer_timeout(Duration::from_secs(5))?;
+                Some(ring.append(&logs, &mut writer))
+            });
+
+            thread::sleep(Duration::from_millis(20));
+            drop(writer);
+            waiter.join().expect("Should join")
+        })
+        .ok_or(anyhow!("Should obtain writer"))??;
+
+        assert_eq!(Some(1), ring.last());
+        other.close()?;
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn append_reclaims_segments_on_roll() -> Result<()> {
+        let dir = tempdir()?;
+        let retention = Retention {
+            max_records: Some(20),
+            ..Retention::default()
+        };
+
+        let ring = open(dir.path())?.with_retention(retention);
+        for seq_no in (1..=50).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        // Segments are reclaimed on roll, before the new segment has log records.
+        let bases: Vec<_> = ring.segments().iter().map(|segment| segment.base).collect();
+        assert_eq!(vec![21, 31, 41], bases);
+        assert_eq!(Some(21), ring.first());
+        for base in [1, 11] {
+            assert!(!dir.path().join(format!("{base:020}.segment")).exists());
+        }
+
+        // Explicit reclamation catches up with appends since.
+        let reclaim = ring.reclaim()?;
+        assert_eq!((1, 1), (reclaim.segments, reclaim.by_records));
+        assert_eq!(Some(31), ring.first());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn reclaim_reports_reasons() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        for seq_no in (1..=30).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        let len = ring.segments()[0].len;
+        let ring = ring.with_retention(Retention {
+            max_bytes: Some(len),
+            ..Retention::default()
+        });
+
+        let reclaim = ring.reclaim()?;
+        assert_eq!(2, reclaim.segments);
+        assert_eq!(2, reclaim.by_bytes);
+        assert_eq!(20, reclaim.records);
+        assert_eq!(1, ring.segments().len());
+
+        // Reclaimed segments stay gone after reopening.
+        append(&ring, 31..=40)?;
+        ring.close()?;
+        let ring = open(dir.path())?;
+        assert_eq!(Some(21), ring.first());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn open_removes_incomplete_block() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        append(&ring, 1..=10)?;
+        ring.close()?;
+
+        // Partially written block at the end of the active segment.
+        let (manifest, _) = Manifest::open(dir.path())?;
+        let len = fs::metadata(manifest.path(1))?.len();
+        let storage = Storage::open(manifest.path(1))?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0, 0, 0, 0, 100], &mut writer)?,
+        };
+
+        storage.close()?;
+        let ring = open(dir.path())?;
+        assert_eq!(Some(10), ring.last());
+        assert_eq!(len, fs::metadata(manifest.path(1))?.len());
+        append(&ring, 11..=20)?;
+        assert_eq!(State::Sealed, ring.segments()[0].state);
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn open_removes_zeroed_tail() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        append(&ring, 1..=10)?;
+        ring.close()?;
+
+        // Crash left zeroes after the last block of the active segment.
+        let (manifest, _) = Manifest::open(dir.path())?;
+        let len = fs::metadata(manifest.path(1))?.len();
+        let storage = Storage::open(manifest.path(1))?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0; 4096], &mut writer)?,
+        };
+
+        storage.close()?;
+        let ring = open(dir.path())?;
+        assert_eq!(Some(10), ring.last());
+        assert_eq!(len, fs::metadata(manifest.path(1))?.len());
+
+        // Appends continue after the last block that was intact.
+        let error = append(&ring, 10..=10).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.downcast::<Error>()?.kind());
+        append(&ring, 11..=20)?;
+        assert_eq!(Some(20), ring.last());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn open_completes_interrupted_roll() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        append(&ring, 1..=10)?;
+        ring.close()?;
+
+        // Crash after sealing the active segment, but before updating manifest.
+        let (manifest, _) = Manifest::open(dir.path())?;
+        let storage = Storage::open(manifest.path(1))?;
+        let mut cursor = Cursor::new(&storage, 0);
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => seal::seal(&mut cursor, &mut writer)?,
+        };
+
+        storage.close()?;
+        let ring = open(dir.path())?;
+        assert_eq!(Some(10), ring.last());
+        append(&ring, 11..=20)?;
+
+        let segments = ring.segments();
+        assert_eq!(2, segments.len());
+        assert_eq!(State::Sealed, segments[0].state);
+        Ok(ring.close()?)
+    }
+
+    fn full_ring(dir: &Path, back_pressure: BackPressure) -> Result<Ring> {
+        let retention = Retention {
+            max_records: Some(10),
+            ..Retention::default()
+        };
+
+        let ring = open(dir)?
+            .with_retention(retention)
+            .with_back_pressure(back_pressure);
+
+        append(&ring, 1..=10)?;
+        append(&ring, 11..=20)?;
+        Ok(ring)
+    }
+
+    #[test]
+    fn back_pressure_fail_rejects_appends_until_consumers_catch_up() -> Result<()> {
+        let dir = tempdir()?;
+        let mut audit = Consumer::register(dir.path(), "audit")?;
+        let ring = full_ring(dir.path(), BackPressure::Fail)?;
+
+        // Consumer has not consumed anything yet, even without a position.
+        let error = append(&ring, 21..=30).unwrap_err();
+        assert_eq!(ErrorKind::StorageFull, error.downcast::<Error>()?.kind());
+        assert_eq!(Some(1), ring.first());
+        assert_eq!(Some(20), ring.last());
+
+        // Consuming part of the oldest segment is not enough.
+        audit.commit(5)?;
+        assert!(append(&ring, 21..=30).is_err());
+
+        // Once it is consumed, it can be reclaimed.
+        audit.commit(10)?;
+        append(&ring, 21..=30)?;
+        assert_eq!(Some(11), ring.first());
+        assert_eq!(Some(30), ring.last());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn back_pressure_block_waits_for_consumers() -> Result<()> {
+        let dir = tempdir()?;
+        let mut audit = Consumer::register(dir.path(), "audit")?;
+        let timeout = Some(Duration::from_millis(20));
+        let ring = full_ring(dir.path(), BackPressure::Block(timeout))?;
+
+        // Consumer does not catch up in time.
+        let error = append(&ring, 21..=30).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.downcast::<Error>()?.kind());
+
+        // Writer is stalled until consumer catches up.
+        let ring = ring.with_back_pressure(BackPressure::Block(None));
+        let start = Instant::now();
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            audit.commit(10)
+        });
+
+        append(&ring, 21..=30)?;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        consumer.join().expect("Should join")?;
+        assert_eq!(Some(11), ring.first());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn overwrite_ignores_consumers() -> Result<()> {
+        let dir = tempdir()?;
+        let audit = Consumer::register(dir.path(), "audit")?;
+        let ring = full_ring(dir.path(), BackPressure::Overwrite)?;
+
+        append(&ring, 21..=30)?;
+        assert_eq!(Some(11), ring.first());
+        assert_eq!(None, audit.committed());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn reader_follows_segments_and_reports_loss() -> Result<()> {
+        let dir = tempdir()?;
+        let retention = Retention {
+            max_records: Some(10),
+            ..Retention::default()
+        };
+
+        let ring = open(dir.path())?.with_retention(retention);
+        append(&ring, 1..=10)?;
+        let mut reader = ring.reader(1);
+        let mut logs = LogBuf::with_capacity(1024);
+        assert_eq!(Event::Logs, reader.poll(&mut logs)?);
+        assert_eq!(Some(10), logs.last());
+        assert_eq!(Event::Pending, reader.poll(&mut logs)?);
+
+        // Reader falls behind, while segments it has not read are reclaimed.
+        append(&ring, 11..=20)?;
+        append(&ring, 21..=30)?;
+        let len = ring.segments()[0].len;
+        append(&ring, 31..=40)?;
+        assert_eq!(Some(21), ring.first());
+
+        let lost = Event::Lost {
+            from: 11,
+            to: 20,
+            bytes: Some(len),
+        };
+
+        assert_eq!(lost, reader.poll(&mut logs)?);
+        for last in [30, 40] {
+            assert_eq!(Event::Logs, reader.poll(&mut logs)?);
+            assert_eq!(Some(last), logs.last());
+        }
+
+        // Reader keeps up with rolls.
+        assert_eq!(Event::Pending, reader.poll(&mut logs)?);
+        append(&ring, 41..=50)?;
+        assert_eq!(Event::Logs, reader.poll(&mut logs)?);
+        assert_eq!(Some(50), logs.last());
+
+        // Readers starting after reclamation cannot tell how many bytes were lost.
+        let mut reader = ring.reader(1);
+        let lost = Event::Lost {
+            from: 1,
+            to: 30,
+            bytes: None,
+        };
+
+        assert_eq!(lost, reader.poll(&mut logs)?);
+        assert_eq!(Event::Logs, reader.poll(&mut logs)?);
+        assert_eq!(Some(31), logs.first());
+
+        // Readers start from the block with the log record.
+        let mut reader = ring.reader(45);
+        assert_eq!(Event::Logs, reader.poll(&mut logs)?);
+        assert_eq!(Some(41), logs.first());
+        assert_eq!(Event::Pending, reader.poll(&mut logs)?);
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn truncate_after_rolls_back_segments_and_readers() -> Result<()> {
+        let dir = tempdir()?;
+        let ring = open(dir.path())?;
+        for seq_no in (1..=30).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        // One reader is behind the sequence number, the other one is past it.
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut behind = ring.reader(1);
+        assert_eq!(Event::Logs, behind.poll(&mut logs)?);
+        let mut ahead = ring.reader(1);
+        while ahead.poll(&mut logs)? == Event::Logs {}
+
+        let mut writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        assert_eq!(Some(15), ring.truncate_after(15, &mut writer)?);
+        assert_eq!(Some(15), ring.last());
+
+        let segments = ring.segments();
+        let bases: Vec<_> = segments.iter().map(|segment| segment.base).collect();
+        assert_eq!(vec![1, 11], bases);
+        assert_eq!(State::Active, segments[1].state);
+        assert!(!dir.path().join(format!("{:020}.segment", 21)).exists());
+
+        // Readers behind continue, readers past it cannot.
+        assert_eq!(Event::Logs, behind.poll(&mut logs)?);
+        assert_eq!((Some(11), Some(15)), (logs.first(), logs.last()));
+        for _ in 0..2 {
+            let error = ahead.poll(&mut logs).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, error.kind());
+        }
+
+        // Appends continue after the sequence number.
+        drop(writer);
+        append(&ring, 16..=25)?;
+        assert_eq!(Event::Logs, behind.poll(&mut logs)?);
+        assert_eq!((Some(16), Some(25)), (logs.first(), logs.last()));
+
+        // Truncate within the active segment, or after the last log record.
+        let mut writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        assert_eq!(Some(17), ring.truncate_after(17, &mut writer)?);
+        assert_eq!(Some(17), ring.truncate_after(100, &mut writer)?);
+
+        let error = ring.truncate_after(0, &mut writer).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        drop(writer);
+        ring.close()?;
+
+        // Everything survives reopening.
+        let ring = open(dir.path())?;
+        assert_eq!(Some(17), ring.last());
+        let mut reader = ring.reader(1);
+        let mut seq_nos = Vec::new();
+        while reader.poll(&mut logs)? == Event::Logs {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        assert_eq!((1..=17).collect::<Vec<_>>(), seq_nos);
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn maintenance_enforces_retention() -> Result<()> {
+        let dir = tempdir()?;
+        let retention = Retention {
+            max_records: Some(10),
+            ..Retention::default()
+        };
+
+        let ring = open(dir.path())?.with_retention(retention);
+        for seq_no in (1..=30).step_by(10) {
+            append(&ring, seq_no..=seq_no + 9)?;
+        }
+
+        assert_eq!(Some(11), ring.first());
+        ring.maintenance(Intervals::default()).flush()?;
+        assert_eq!(Some(21), ring.first());
+        Ok(ring.close()?)
+    }
+
+    #[test]
+    fn maintenance_persists_index_for_recovery() -> Result<()> {
+        let dir = tempdir()?;
+        let mut ring = open(dir.path())?.with_segment_size(Ring::SEGMENT_SIZE);
+        ring.spawn_maintenance(Intervals::default(), |_| {})?;
+        append(&ring, 1..=10)?;
+        append(&ring, 11..=20)?;
+
+        // Closing runs maintenance one last time.
+        ring.close()?;
+        let index = dir.path().join(format!("{:020}.index", 1));
+        let entry = Entry {
+            offset: 0,
+            seq_no: 1,
+            records: 0,
+        };
+
+        assert_eq!(vec![entry], read_index(&index)?);
+
+        // Recovery resumes from the index.
+        let ring = open(dir.path())?.with_segment_size(Ring::SEGMENT_SIZE);
+        assert_eq!(Some(20), ring.last());
+        append(&ring, 21..=30)?;
+        ring.close()?;
+
+        // Corrupted index is ignored, and segment is scanned from the start instead.
+        fs::write(&index, b"Joker")?;
+        let ring = open(dir.path())?;
+        assert_eq!(Some(30), ring.last());
+
+        // Rolling removes the index of the sealed segment.
+        append(&ring, 31..=40)?;
+        assert!(!index.exists());
+        Ok(ring.close()?)
+    }
+}
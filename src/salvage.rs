@@ -0,0 +1,445 @@
+//! Offline recovery of log records from corrupted storage.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    buf::LogBuf,
+    cursor::Cursor,
+    metrics,
+    seal::Footer,
+    storage::{Storage, Writer},
+};
+use std::{
+    io::{ErrorKind, Result},
+    ops::{Range, RangeInclusive},
+};
+
+/// Recover log records from storage with corrupted regions.
+///
+/// [`Storage::truncate`] can only throw away everything after corruption. Salvage
+/// instead resyncs past corrupted regions. Starting from every byte in the region,
+/// it looks for the next position where a block decodes successfully, with log
+/// records after the ones already recovered. Recovered blocks are copied as is into
+/// fresh storage, and every region that was skipped is reported.
+///
+/// Bytes in a corrupted region can happen to decode as a block. So a block found
+/// right after a corrupted region is only trusted if the block after it decodes
+/// too, with later log records, or if it ends storage. This bounds how far ahead
+/// sequence numbers can jump on resync.
+///
+/// Blocks are validated by decoding them, and by authenticating them when encrypted.
+/// Unencrypted blocks have no checksum, so corruption within log records of a block
+/// that still decodes cannot be detected. Blocks that cannot be read because a feature
+/// is not enabled are not corruption, salvage fails instead of skipping them.
+///
+/// Sealed segments are only salvaged up to their footer, if it is intact.
+pub struct Salvage<'a> {
+    source: &'a Storage,
+    #[cfg(feature = "encryption")]
+    keyring: Option<&'a Keyring>,
+}
+
+/// Outcome of salvaging storage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Number of recovered blocks.
+    pub blocks: u64,
+
+    /// Number of recovered log records.
+    pub records: u64,
+
+    /// Sequence number of the first recovered log record, if any.
+    pub first: Option<u64>,
+
+    /// Sequence number of the last recovered log record, if any.
+    pub last: Option<u64>,
+
+    /// Corrupted regions that were skipped, in order.
+    pub lost: Vec<Lost>,
+}
+
+/// A corrupted region of storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lost {
+    /// Offsets of bytes that were skipped.
+    pub bytes: Range<u64>,
+
+    /// Sequence numbers of log records lost along with the bytes. None if nothing was
+    /// lost, or if it cannot be known because the region is at the start or end of storage.
+    pub records: Option<RangeInclusive<u64>>,
+}
+
+impl<'a> Salvage<'a> {
+    /// Salvage log records from storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Corrupted storage to recover log records from.
+    pub fn new(source: &'a Storage) -> Self {
+        Self {
+            source,
+            #[cfg(feature = "encryption")]
+            keyring: None,
+        }
+    }
+
+    /// Decrypt and authenticate encrypted blocks with keys from a keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to decrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: &'a Keyring) -> Self {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Copy every recoverable block into fresh storage.
+    ///
+    /// Target storage is synced before returning.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Storage to append recovered blocks into.
+    /// * `writer` - Writer of target storage.
+    pub fn run(&self, target: &Storage, writer: &mut Writer) -> Result<Report> {
+        let mut report = Report::default();
+        let mut logs = LogBuf::with_capacity(0);
+        let mut next = LogBuf::with_capacity(0);
+        let mut bytes = Vec::new();
+
+        // Blocks of sealed segments end where their footer starts.
+        let end = match Footer::read(self.source)? {
+            Some(footer) => footer.len(),
+            None => self.source.len(),
+        };
+
+        // Start of the corrupted region being skipped, if any.
+        let mut corrupted = None;
+        let mut offset = 0;
+        while offset < end {
+            let mut cursor = self.cursor(offset).with_end(end);
+            let valid = match cursor.next(&mut logs) {
+                Ok(valid) => valid,
+                Err(error) if error.kind() == ErrorKind::InvalidData => false,
+
+                // Garbage in a corrupted region can look like such blocks too.
+                Err(error) if error.kind() == ErrorKind::Unsupported && corrupted.is_some() => {
+                    false
+                }
+
+                Err(error) => return Err(error),
+            };
+
+            // Sequence numbers must keep increasing, same as LogBuf::append.
+            let plausible = |first: &u64| report.last.is_none_or(|last| last < *first);
+            let first = logs.first().filter(|_| valid).filter(plausible);
+            let Some(first) = first.filter(|_| {
+                corrupted.is_none() || self.followed(&cursor, logs.last(), &mut next, end)
+            }) else {
+                corrupted.get_or_insert(offset);
+                offset += 1;
+                continue;
+            };
+
+            // Found the end of a corrupted region.
+            if let Some(start) = corrupted.take() {
+                metrics::record(|recorder| recorder.corrupted(offset - start));
+                event!(warn, start, end = offset, "Skipped corrupted region");
+                let records = report.last.map(|last| last + 1..=first - 1);
+                report.lost.push(Lost {
+                    bytes: start..offset,
+                    records: records.filter(|records| !records.is_empty()),
+                });
+            }
+
+            // Copy the block as is.
+            bytes.resize((cursor.offset() - offset) as usize, 0);
+            self.source.read_exact_at(offset, &mut bytes)?;
+            target.append(&bytes, writer)?;
+
+            report.blocks += 1;
+            report.records += logs.count() as u64;
+            report.first = report.first.or(Some(first));
+            report.last = logs.last();
+            offset = cursor.offset();
+        }
+
+        if let Some(start) = corrupted {
+            metrics::record(|recorder| recorder.corrupted(end - start));
+            event!(
+                warn,
+                start,
+                end,
+                "Skipped corrupted region at end of storage"
+            );
+            report.lost.push(Lost {
+                bytes: start..end,
+                records: None,
+            });
+        }
+
+        target.sync()?;
+        Ok(report)
+    }
+
+    /// Returns true if a block is followed by another one with later log records, or
+    /// if it ends storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - Cursor right after the block.
+    /// * `last` - Sequence number of the last log record in the block.
+    /// * `next` - Buffer to read the next block into.
+    /// * `end` - End of source storage.
+    fn followed(
+        &self,
+        cursor: &Cursor<'_>,
+        last: Option<u64>,
+        next: &mut LogBuf,
+        end: u64,
+    ) -> bool {
+        if cursor.offset() == end {
+            return true;
+        }
+
+        // Errors other than corruption surface when the next block is read for real.
+        let valid = self
+            .cursor(cursor.offset())
+            .with_end(end)
+            .next(next)
+            .unwrap_or(false);
+        valid
+            && next
+                .first()
+                .is_some_and(|first| last.is_some_and(|last| last < first))
+    }
+
+    /// Cursor to read a block at an offset in source storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the block.
+    fn cursor(&self, offset: u64) -> Cursor<'a> {
+        let cursor = Cursor::new(self.source, offset);
+
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = self.keyring {
+            return cursor.with_keyring(keyring);
+        }
+
+        cursor
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        log::Log,
+    };
+    use anyhow::{Result, anyhow};
+    use std::{fs::OpenOptions, os::unix::fs::FileExt, path::Path};
+    use tempfile::tempdir;
+
+    /// Storage with blocks of 10 log records, sequence numbers 1 to 50.
+    /// Returns storage along with size of every block.
+    fn storage_with_logs(path: &Path) -> Result<(Storage, u64)> {
+        let storage = Storage::create(path)?;
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in (1..=50).step_by(10) {
+            logs.clear();
+            for seq_no in seq_no..seq_no + 10 {
+                assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        Ok((storage, block.len() as u64))
+    }
+
+    fn salvage(source: &Storage, path: &Path) -> Result<(Storage, Report)> {
+        let target = Storage::create(path)?;
+        let report = match target.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => Salvage::new(source).run(&target, &mut writer)?,
+        };
+
+        Ok((target, report))
+    }
+
+    fn read_all(storage: &Storage) -> Result<Vec<u64>> {
+        let mut seq_nos = Vec::new();
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut cursor = Cursor::new(storage, 0);
+        while cursor.next(&mut logs)? {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        Ok(seq_nos)
+    }
+
+    #[test]
+    fn salvage_healthy_storage_copies_everything() -> Result<()> {
+        let dir = tempdir()?;
+        let (source, _) = storage_with_logs(&dir.path().join("source.storage"))?;
+        let (target, report) = salvage(&source, &dir.path().join("target.storage"))?;
+
+        assert_eq!(5, report.blocks);
+        assert_eq!(50, report.records);
+        assert_eq!((Some(1), Some(50)), (report.first, report.last));
+        assert!(report.lost.is_empty());
+        assert_eq!(source.len(), target.len());
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_sealed_segment_stops_at_footer() -> Result<()> {
+        let dir = tempdir()?;
+        let (source, _) = storage_with_logs(&dir.path().join("source.storage"))?;
+        let footer = match source.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => crate::seal::seal(&mut Cursor::new(&source, 0), &mut writer)?,
+        };
+
+        // Footer is neither copied, nor reported as corrupted.
+        let (target, report) = salvage(&source, &dir.path().join("target.storage"))?;
+        assert_eq!(50, report.records);
+        assert!(report.lost.is_empty());
+        assert_eq!(footer.len(), target.len());
+        assert_eq!((1..=50).collect::<Vec<_>>(), read_all(&target)?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "zstd"))]
+    fn salvage_unsupported_block_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("source.storage");
+        let (source, _) = storage_with_logs(&path)?;
+
+        // First block claims to be compressed with zstd.
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[1], 0)?;
+
+        let target = Storage::create(dir.path().join("target.storage"))?;
+        let mut writer = target.writer().ok_or(anyhow!("Should obtain writer"))?;
+        let error = Salvage::new(&source).run(&target, &mut writer).unwrap_err();
+        assert_eq!(ErrorKind::Unsupported, error.kind());
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_resyncs_past_corruption() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("source.storage");
+        let (source, block_len) = storage_with_logs(&path)?;
+
+        // Garbage over the header of the third block.
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[0xFF; 4], block_len * 2)?;
+
+        let (target, report) = salvage(&source, &dir.path().join("target.storage"))?;
+        assert_eq!(4, report.blocks);
+        assert_eq!(40, report.records);
+
+        let expected = Lost {
+            bytes: block_len * 2..block_len * 3,
+            records: Some(21..=30),
+        };
+
+        assert_eq!(vec![expected], report.lost);
+
+        let mut seq_nos: Vec<_> = (1..=20).collect();
+        seq_nos.extend(31..=50);
+        assert_eq!(seq_nos, read_all(&target)?);
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_reports_trailing_garbage() -> Result<()> {
+        let dir = tempdir()?;
+        let (source, _) = storage_with_logs(&dir.path().join("source.storage"))?;
+        let len = source.len();
+        match source.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => source.append(b"Joker was here", &mut writer)?,
+        };
+
+        let (target, report) = salvage(&source, &dir.path().join("target.storage"))?;
+        assert_eq!(50, report.records);
+        assert_eq!(len, target.len());
+
+        let expected = Lost {
+            bytes: len..source.len(),
+            records: None,
+        };
+
+        assert_eq!(vec![expected], report.lost);
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_does_not_jump_past_next_block() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("source.storage");
+        let (source, block_len) = storage_with_logs(&path)?;
+
+        // Garbage over the third block, and the fourth one jumps far ahead.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in 1000..1010 {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        block.encode(&logs)?;
+        assert_eq!(block_len, block.len() as u64);
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[0xFF; 4], block_len * 2)?;
+        file.write_all_at(block.bytes(), block_len * 3)?;
+
+        // Block after the corrupted region is not followed by later log records.
+        let (target, report) = salvage(&source, &dir.path().join("target.storage"))?;
+        let expected = Lost {
+            bytes: block_len * 2..block_len * 4,
+            records: Some(21..=40),
+        };
+
+        assert_eq!(vec![expected], report.lost);
+
+        let mut seq_nos: Vec<_> = (1..=20).collect();
+        seq_nos.extend(41..=50);
+        assert_eq!(seq_nos, read_all(&target)?);
+        Ok(())
+    }
+
+    #[test]
+    fn salvage_skips_out_of_order_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("source.storage");
+        let (source, block_len) = storage_with_logs(&path)?;
+
+        // Second block duplicated over the fourth block.
+        let mut block = vec![0; block_len as usize];
+        source.read_exact_at(block_len, &mut block)?;
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&block, block_len * 3)?;
+
+        let (_, report) = salvage(&source, &dir.path().join("target.storage"))?;
+        assert_eq!(40, report.records);
+        assert_eq!(block_len * 3..block_len * 4, report.lost[0].bytes);
+        assert_eq!(Some(31..=40), report.lost[0].records);
+        Ok(())
+    }
+}
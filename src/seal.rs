@@ -0,0 +1,494 @@
+//! Sealed segments that never change again.
+//!
+//! When a segment rolls, it is sealed by appending a footer after its last block.
+//! Layout of the footer is:
+//!
+//! * Sparse index, offset (8 bytes) and first sequence number (8 bytes) of some blocks.
+//! * Number of log records (8 bytes).
+//! * Sequence numbers of the first and last log records (8 bytes each).
+//! * Number of entries in the sparse index (8 bytes).
+//! * Number of bytes of blocks before the footer (8 bytes).
+//! * CRC32 checksum of blocks (4 bytes).
+//! * CRC32 checksum of the footer up to this point (4 bytes).
+//! * Magic bytes identifying the segment as sealed (8 bytes).
+//!
+//! Footer is read from the end of the segment, so opening a sealed segment does
+//! not require reading any of its blocks.
+
+use crate::{
+    buf::LogBuf,
+    cursor::Cursor,
+    storage::{Storage, Writer},
+};
+use crc32fast::Hasher;
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+};
+
+/// Magic bytes at the end of every sealed segment.
+const MAGIC: [u8; 8] = *b"ARROWSEL";
+
+/// Number of bytes in the footer after the sparse index.
+const TAIL_SIZE: usize = 8 * 5 + 4 + 4 + MAGIC.len();
+
+/// Number of bytes in an entry of the sparse index.
+const ENTRY_SIZE: usize = 8 + 8;
+
+/// Minimum number of bytes between blocks in the sparse index.
+pub(crate) const INDEX_INTERVAL: u64 = 64 * 1024;
+
+/// Footer of a sealed segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Footer {
+    count: u64,
+    first: Option<u64>,
+    last: Option<u64>,
+    len: u64,
+    checksum: u32,
+    index: Vec<Entry>,
+}
+
+/// Entry in the sparse index of a sealed segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    offset: u64,
+    seq_no: u64,
+}
+
+/// Seal a segment.
+///
+/// Every block in the segment is read to build the footer. Footer is appended and
+/// synced to disk, then the segment file is made read only.
+///
+/// Returns an error if the segment is already sealed, or if it ends with an incomplete
+/// block. Use [`truncate_after`](crate::truncate::truncate_after) to remove it first.
+///
+/// # Arguments
+///
+/// * `cursor` - Cursor at the start of the segment, with keys to read its blocks if any.
+/// * `writer` - Writer of storage the cursor reads from.
+pub fn seal(cursor: &mut Cursor<'_>, writer: &mut Writer) -> Result<Footer> {
+    let storage = cursor.storage();
+    if cursor.offset() != 0 {
+        let kind = ErrorKind::InvalidInput;
+        return Err(Error::new(kind, "Cursor must be at the start of segment"));
+    }
+
+    if Footer::read(storage)?.is_some() {
+        let kind = ErrorKind::InvalidInput;
+        return Err(Error::new(kind, "Segment is already sealed"));
+    }
+
+    let mut footer = Footer {
+        count: 0,
+        first: None,
+        last: None,
+        len: 0,
+        checksum: 0,
+        index: Vec::new(),
+    };
+
+    // Read every block in the segment.
+    let mut logs = LogBuf::with_capacity(0);
+    loop {
+        let offset = cursor.offset();
+        if !cursor.next(&mut logs)? {
+            break;
+        }
+
+        // Blocks always have at least one log record.
+        let first = logs.first().expect("Should have logs");
+        let indexed = footer.index.last().map(|entry| entry.offset);
+        if indexed.is_none_or(|indexed| offset - indexed >= INDEX_INTERVAL) {
+            footer.index.push(Entry {
+                offset,
+                seq_no: first,
+            });
+        }
+
+        footer.count += logs.count() as u64;
+        footer.first = footer.first.or(Some(first));
+        footer.last = logs.last();
+    }
+
+    footer.len = cursor.offset();
+    if footer.len != storage.len() {
+        let kind = ErrorKind::InvalidData;
+        let error = "Segment has an incomplete block at the end";
+        return Err(Error::new(kind, error));
+    }
+
+    // Append footer, and make sure segment is never modified again.
+    footer.checksum = checksum(storage, footer.len)?;
+    storage.append(&footer.encode(), writer)?;
+    storage.sync()?;
+
+    let mut permissions = fs::metadata(storage.path())?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(storage.path(), permissions)?;
+    event!(info, path = %storage.path().display(), count = footer.count(), "Sealed segment");
+    Ok(footer)
+}
+
+impl Footer {
+    /// Read footer of a sealed segment.
+    ///
+    /// Returns None if the segment is not sealed. Magic bytes alone could just as well
+    /// be the end of a log record, so a segment is only sealed if its footer fits exactly
+    /// after blocks and matches its checksum. Only the footer is read and verified, use
+    /// [`Footer::verify`] to verify blocks in the segment as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage of the segment.
+    pub fn read(storage: &Storage) -> Result<Option<Self>> {
+        let mut tail = [0; TAIL_SIZE];
+        let Some(tail_offset) = storage.len().checked_sub(tail.len() as u64) else {
+            return Ok(None);
+        };
+
+        storage.read_exact_at(tail_offset, &mut tail)?;
+        if tail[TAIL_SIZE - MAGIC.len()..] != MAGIC {
+            return Ok(None);
+        }
+
+        let read_u64 =
+            |i: usize| u64::from_be_bytes(tail[i..i + 8].try_into().expect("Should never fail"));
+        let read_u32 =
+            |i: usize| u32::from_be_bytes(tail[i..i + 4].try_into().expect("Should never fail"));
+        let (count, first, last, entries, len) = (
+            read_u64(0),
+            read_u64(8),
+            read_u64(16),
+            read_u64(24),
+            read_u64(32),
+        );
+
+        // Footer should fit exactly after blocks.
+        let index_len = entries.checked_mul(ENTRY_SIZE as u64);
+        let Some(index_len) =
+            index_len.filter(|index_len| len.checked_add(*index_len) == Some(tail_offset))
+        else {
+            return Ok(None);
+        };
+
+        if count > 0 && first > last {
+            return Ok(None);
+        }
+
+        let mut index = vec![0; index_len as usize];
+        storage.read_exact_at(len, &mut index)?;
+
+        let mut hasher = Hasher::new();
+        hasher.update(&index);
+        hasher.update(&tail[..44]);
+        if hasher.finalize() != read_u32(44) {
+            return Ok(None);
+        }
+
+        let index = index
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| Entry {
+                offset: u64::from_be_bytes(entry[..8].try_into().expect("Should never fail")),
+                seq_no: u64::from_be_bytes(entry[8..].try_into().expect("Should never fail")),
+            })
+            .collect();
+
+        Ok(Some(Self {
+            len,
+            count,
+            index,
+            checksum: read_u32(40),
+            first: (count > 0).then_some(first),
+            last: (count > 0).then_some(last),
+        }))
+    }
+
+    /// Verify blocks in the segment against the checksum in the footer.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage of the segment.
+    pub fn verify(&self, storage: &Storage) -> Result<()> {
+        if checksum(storage, self.len)? != self.checksum {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Sealed segment is corrupted"));
+        }
+
+        Ok(())
+    }
+
+    /// Number of log records in the segment.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sequence number of the first log record in the segment, if any.
+    pub fn first(&self) -> Option<u64> {
+        self.first
+    }
+
+    /// Sequence number of the last log record in the segment, if any.
+    pub fn last(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Number of bytes of blocks in the segment.
+    ///
+    /// Cursors reading the segment should stop here, see [`Cursor::with_end`].
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if there are no blocks in the segment, false otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Offset of a block to start reading from, to find a log record.
+    ///
+    /// This is the offset of the last indexed block with log records before or at the
+    /// sequence number. Log record itself might be in a later block.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_no` - Sequence number of the log record.
+    pub fn offset(&self, seq_no: u64) -> u64 {
+        match self.index.partition_point(|entry| entry.seq_no <= seq_no) {
+            0 => 0,
+            i => self.index[i - 1].offset,
+        }
+    }
+
+    /// Encode footer into bytes.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ENTRY_SIZE * self.index.len() + TAIL_SIZE);
+        for entry in &self.index {
+            bytes.extend_from_slice(&entry.offset.to_be_bytes());
+            bytes.extend_from_slice(&entry.seq_no.to_be_bytes());
+        }
+
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend_from_slice(&self.first.unwrap_or_default().to_be_bytes());
+        bytes.extend_from_slice(&self.last.unwrap_or_default().to_be_bytes());
+        bytes.extend_from_slice(&(self.index.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+
+        let mut hasher = Hasher::new();
+        hasher.update(&bytes);
+        bytes.extend_from_slice(&hasher.finalize().to_be_bytes());
+        bytes.extend_from_slice(&MAGIC);
+        bytes
+    }
+}
+
+/// Checksum of bytes at the start of storage.
+///
+/// # Arguments
+///
+/// * `storage` - Storage to read bytes from.
+/// * `len` - Number of bytes to checksum.
+fn checksum(storage: &Storage, len: u64) -> Result<u32> {
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let size = buf.len().min((len - offset) as usize);
+        storage.read_exact_at(offset, &mut buf[..size])?;
+        hasher.update(&buf[..size]);
+        offset += size as u64;
+    }
+
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{Block, Codec},
+        log::Log,
+    };
+    use anyhow::{Result, anyhow};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn storage_with_logs(path: &Path, blocks: u64, data: &[u8]) -> Result<Storage> {
+        let storage = Storage::create(path)?;
+
+        // Blocks of 10 log records, with sequence numbers starting from 1.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in (1..=blocks * 10).step_by(10) {
+            logs.clear();
+            for seq_no in seq_no..seq_no + 10 {
+                assert!(logs.append(&Log::new_borrowed(seq_no, data)));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        Ok(storage)
+    }
+
+    fn seal_storage(storage: &Storage) -> Result<Footer> {
+        let mut cursor = Cursor::new(storage, 0);
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => Ok(seal(&mut cursor, &mut writer)?),
+        }
+    }
+
+    #[test]
+    fn seal_writes_footer() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.segment");
+        let storage = storage_with_logs(&path, 3, b"Batman")?;
+        assert_eq!(None, Footer::read(&storage)?);
+
+        let len = storage.len();
+        let footer = seal_storage(&storage)?;
+        assert_eq!(30, footer.count());
+        assert_eq!(Some(1), footer.first());
+        assert_eq!(Some(30), footer.last());
+        assert_eq!(len, footer.len());
+        assert!(fs::metadata(&path)?.permissions().readonly());
+
+        // Footer is loaded without reading blocks.
+        storage.close()?;
+        let storage = Storage::open_read_only(&path)?;
+        assert_eq!(Some(&footer), Footer::read(&storage)?.as_ref());
+        footer.verify(&storage)?;
+
+        // Cursors stop at the end of blocks.
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut cursor = Cursor::new(&storage, 0).with_end(footer.len());
+        let mut count = 0;
+        while cursor.next(&mut logs)? {
+            count += logs.count();
+        }
+
+        assert_eq!(30, count);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn seal_empty_segment() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Storage::create(dir.path().join("test.segment"))?;
+
+        let footer = seal_storage(&storage)?;
+        assert_eq!(0, footer.count());
+        assert_eq!(None, footer.first());
+        assert!(footer.is_empty());
+        assert_eq!(Some(footer), Footer::read(&storage)?);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn seal_twice_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = storage_with_logs(&dir.path().join("test.segment"), 1, b"Batman")?;
+
+        seal_storage(&storage)?;
+        assert!(seal_storage(&storage).is_err());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn seal_incomplete_block_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = storage_with_logs(&dir.path().join("test.segment"), 1, b"Batman")?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0, 0, 0, 0, 100], &mut writer)?,
+        };
+
+        let error = seal_storage(&storage).unwrap_err();
+        let error = error.downcast_ref::<Error>().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn offset_finds_indexed_block() -> Result<()> {
+        let dir = tempdir()?;
+        let data = vec![7; 1024];
+        let storage = storage_with_logs(&dir.path().join("test.segment"), 30, &data)?;
+        let footer = seal_storage(&storage)?;
+        assert!(footer.index.len() > 1);
+
+        // Log records can be found starting from the indexed block.
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in [1, 95, 150, 300] {
+            let offset = footer.offset(seq_no);
+            let mut cursor = Cursor::new(&storage, offset).with_end(footer.len());
+            assert!(cursor.next(&mut logs)?);
+            assert!(logs.first().is_some_and(|first| first <= seq_no));
+            while logs.last().is_some_and(|last| last < seq_no) {
+                assert!(cursor.next(&mut logs)?);
+            }
+        }
+
+        assert!(footer.offset(150) > 0);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn verify_detects_corruption() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.segment");
+        let storage = storage_with_logs(&path, 3, b"Batman")?;
+        let footer = seal_storage(&storage)?;
+        storage.close()?;
+
+        // Flip a byte in blocks.
+        let mut bytes = fs::read(&path)?;
+        let mut permissions = fs::metadata(&path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions)?;
+        bytes[20] ^= 1;
+        fs::write(&path, &bytes)?;
+
+        let storage = Storage::open_read_only(&path)?;
+        assert_eq!(
+            ErrorKind::InvalidData,
+            footer.verify(&storage).unwrap_err().kind()
+        );
+
+        // Flip a byte in footer.
+        bytes[20] ^= 1;
+        let index = footer.len() as usize;
+        bytes[index] ^= 1;
+        fs::write(&path, &bytes)?;
+
+        let storage = Storage::open_read_only(&path)?;
+        assert_eq!(None, Footer::read(&storage)?);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn read_log_record_ending_with_magic_is_not_sealed() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.segment");
+        let storage = storage_with_logs(&path, 1, &MAGIC)?;
+        let mut magic = [0; MAGIC.len()];
+        storage.read_exact_at(storage.len() - MAGIC.len() as u64, &mut magic)?;
+        assert!(storage.len() >= TAIL_SIZE as u64);
+        assert_eq!(MAGIC, magic);
+
+        // Segment just happens to end with magic bytes, it can still be sealed.
+        assert_eq!(None, Footer::read(&storage)?);
+        let footer = seal_storage(&storage)?;
+        assert_eq!(Some(footer), Footer::read(&storage)?);
+        Ok(storage.close()?)
+    }
+}
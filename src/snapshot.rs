@@ -0,0 +1,503 @@
+//! Standalone snapshots of a range of log records.
+//!
+//! A snapshot is a self-contained file holding a contiguous range of log records,
+//! for example to hand over to someone for debugging or to backfill another ring
+//! buffer. Layout of the file is:
+//!
+//! * Magic bytes identifying the file as a snapshot (8 bytes).
+//! * Blocks of log records, same as they are appended into [`Storage`].
+//! * Sequence number of the first log record (8 bytes).
+//! * Sequence number of the last log record (8 bytes).
+//! * Number of log records (8 bytes).
+//! * CRC32 checksum of everything before it (4 bytes).
+//!
+//! Blocks in a snapshot are never encrypted, even if they were in the source. They are
+//! encoded again when imported, the same way as anything else appended into the ring.
+
+use crate::{
+    block::{Block, Codec},
+    buf::LogBuf,
+    cursor::Cursor,
+    ring::{Ring, Writer},
+};
+use crc32fast::Hasher;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+/// Magic bytes at the start of every snapshot.
+const MAGIC: [u8; 8] = *b"ARROWSNP";
+
+/// Number of bytes at the end of every snapshot.
+const FOOTER_SIZE: usize = 8 + 8 + 8 + 4;
+
+/// Range of log records held in a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Sequence number of the first log record.
+    pub first: u64,
+
+    /// Sequence number of the last log record.
+    pub last: u64,
+
+    /// Number of log records.
+    pub count: u64,
+}
+
+impl Summary {
+    /// Account for a block of log records.
+    ///
+    /// Returns None if log records in the block do not come after the ones
+    /// already accounted for.
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - Log records accounted for so far, if any.
+    /// * `logs` - Block of log records.
+    fn add(summary: Option<Self>, logs: &LogBuf) -> Option<Self> {
+        let (first, last) = (logs.first()?, logs.last()?);
+        let count = logs.count() as u64;
+        match summary {
+            None => Some(Self { first, last, count }),
+            Some(summary) if summary.last < first => Some(Self {
+                last,
+                count: summary.count + count,
+                ..summary
+            }),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Export a range of log records into a snapshot.
+///
+/// Log records are read with the cursor, starting from its current position. Log
+/// records before the start of the range are skipped, reading stops after the end
+/// of the range or when the cursor runs out of log records, whichever comes first.
+/// Returned summary describes what was actually exported.
+///
+/// Returns an error if the first log record of the range no longer exists, or if
+/// there are no log records in the range at all. Snapshot file is not left behind
+/// when an error is returned.
+///
+/// # Arguments
+///
+/// * `cursor` - Cursor to read log records with.
+/// * `range` - Sequence numbers of log records to export.
+/// * `codec` - Codec to encode blocks in the snapshot with.
+/// * `path` - Path to the snapshot, it must not already exist.
+pub fn export<P: AsRef<Path>>(
+    cursor: &mut Cursor<'_>,
+    range: RangeInclusive<u64>,
+    codec: Codec,
+    path: P,
+) -> Result<Summary> {
+    let path = path.as_ref();
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+
+    let result = write(cursor, range, codec, file);
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+
+    result
+}
+
+/// Verify integrity of a snapshot.
+///
+/// Every block of log records is decoded, and the checksum is verified.
+///
+/// # Arguments
+///
+/// * `path` - Path to the snapshot.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Summary> {
+    scan(path.as_ref(), |_| Ok(()))
+}
+
+/// Import log records from a snapshot into a ring buffer.
+///
+/// Snapshot is verified in full before anything is appended. Like
+/// [`LogBuf::append`], sequence numbers must keep increasing, so the first log
+/// record in the snapshot must come after the last one in the ring.
+///
+/// Blocks are appended one at a time with [`Ring::append`], encoded with the block
+/// settings of the ring. If appending one of them fails, blocks before it remain in
+/// the ring.
+///
+/// # Arguments
+///
+/// * `path` - Path to the snapshot.
+/// * `ring` - Ring buffer to append log records into.
+/// * `writer` - Writer of the ring buffer.
+pub fn import<P: AsRef<Path>>(path: P, ring: &Ring, writer: &mut Writer) -> Result<Summary> {
+    let path = path.as_ref();
+    let summary = verify(path)?;
+    if ring.last().is_some_and(|last| last >= summary.first) {
+        let kind = ErrorKind::InvalidInput;
+        let error = "Snapshot does not follow the last log record in the ring";
+        return Err(Error::new(kind, error));
+    }
+
+    scan(path, |logs| ring.append(logs, writer))
+}
+
+/// Write a range of log records into a snapshot file.
+///
+/// # Arguments
+///
+/// * `cursor` - Cursor to read log records with.
+/// * `range` - Sequence numbers of log records to export.
+/// * `codec` - Codec to encode blocks in the snapshot with.
+/// * `file` - Snapshot file to write into.
+fn write(
+    cursor: &mut Cursor<'_>,
+    range: RangeInclusive<u64>,
+    codec: Codec,
+    file: File,
+) -> Result<Summary> {
+    let mut writer = BufWriter::new(file);
+    let mut hasher = Hasher::new();
+    let mut write = |bytes: &[u8]| {
+        hasher.update(bytes);
+        writer.write_all(bytes)
+    };
+
+    write(&MAGIC)?;
+
+    let mut summary = None;
+    let mut block = Block::new(codec);
+    let mut logs = LogBuf::with_capacity(0);
+    let mut batch = LogBuf::with_capacity(0).with_limits(logs.limits());
+    while summary.is_none_or(|summary: Summary| summary.last < *range.end())
+        && cursor.next(&mut logs)?
+    {
+        // Only log records within the range.
+        batch.clear();
+        let mut iter = logs.iter();
+        while let Some(log) = iter.next() {
+            if log.seq_no() > *range.end() {
+                break;
+            }
+
+            if log.seq_no() < *range.start() {
+                continue;
+            }
+
+            // Start of the range must be present.
+            if summary.is_none() && batch.is_empty() && log.seq_no() != *range.start() {
+                let kind = ErrorKind::NotFound;
+                let error = "Log records at the start of range no longer exist";
+                return Err(Error::new(kind, error));
+            }
+
+            // Limits are the same as the source, so this cannot fail.
+            assert!(batch.append(&log), "Should append log record");
+        }
+
+        if batch.is_empty() {
+            continue;
+        }
+
+        let Some(next) = Summary::add(summary, &batch) else {
+            let kind = ErrorKind::InvalidData;
+            return Err(Error::new(kind, "Log records are out of order"));
+        };
+
+        summary = Some(next);
+        block.encode(&batch)?;
+        write(block.bytes())?;
+    }
+
+    let Some(summary) = summary else {
+        return Err(Error::new(ErrorKind::NotFound, "No log records in range"));
+    };
+
+    // Footer with the range of log records and checksum.
+    write(&summary.first.to_be_bytes())?;
+    write(&summary.last.to_be_bytes())?;
+    write(&summary.count.to_be_bytes())?;
+    writer.write_all(&hasher.finalize().to_be_bytes())?;
+
+    // Make sure the snapshot is durable.
+    writer
+        .into_inner()
+        .map_err(|error| error.into_error())?
+        .sync_all()?;
+    Ok(summary)
+}
+
+/// Read every block of log records in a snapshot.
+///
+/// Blocks are decoded as they are read, but checksum is only verified after
+/// the last block is read.
+///
+/// # Arguments
+///
+/// * `path` - Path to the snapshot.
+/// * `f` - Callback invoked with log records of every block.
+fn scan<F>(path: &Path, mut f: F) -> Result<Summary>
+where
+    F: FnMut(&LogBuf) -> Result<()>,
+{
+    let corrupted = || Error::new(ErrorKind::InvalidData, "Corrupted snapshot");
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let Some(mut remaining) = len.checked_sub((MAGIC.len() + FOOTER_SIZE) as u64) else {
+        return Err(corrupted());
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut hasher = Hasher::new();
+
+    let mut magic = [0; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a snapshot"));
+    }
+
+    hasher.update(&magic);
+
+    let mut summary = None;
+    let mut bytes = Vec::new();
+    let mut scratch = Vec::new();
+    let mut logs = LogBuf::with_capacity(0);
+    while remaining > 0 {
+        // Fetch header of the next block.
+        let mut header = [0; Block::HEADER_SIZE];
+        if remaining < header.len() as u64 {
+            return Err(corrupted());
+        }
+
+        reader.read_exact(&mut header)?;
+        let block = Block::read_header(&header, logs.limits())?;
+        let size = header.len() + block.len;
+        if block.encrypted || remaining < size as u64 {
+            return Err(corrupted());
+        }
+
+        // Fetch and decode payload of the block.
+        bytes.clear();
+        bytes.extend_from_slice(&header);
+        bytes.resize(size, 0);
+        reader.read_exact(&mut bytes[header.len()..])?;
+        Block::decode(&block, &bytes[header.len()..], &mut scratch, &mut logs)?;
+        summary = Some(Summary::add(summary, &logs).ok_or_else(corrupted)?);
+
+        hasher.update(&bytes);
+        f(&logs)?;
+        remaining -= size as u64;
+    }
+
+    // Footer should match log records and checksum.
+    let mut footer = [0; FOOTER_SIZE];
+    reader.read_exact(&mut footer)?;
+    hasher.update(&footer[..24]);
+
+    let read_u64 =
+        |i: usize| u64::from_be_bytes(footer[i..i + 8].try_into().expect("Should never fail"));
+    let expected = Summary {
+        first: read_u64(0),
+        last: read_u64(8),
+        count: read_u64(16),
+    };
+
+    let checksum = u32::from_be_bytes(footer[24..].try_into().expect("Should never fail"));
+    if checksum != hasher.finalize() || summary != Some(expected) {
+        return Err(corrupted());
+    }
+
+    Ok(expected)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{log::Log, storage::Storage};
+    use anyhow::{Result, anyhow};
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn storage_with_logs(path: PathBuf) -> Result<Storage> {
+        let storage = Storage::create(path)?;
+
+        // Blocks of 10 log records, with sequence numbers 1 to 50.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in (1..=50).step_by(10) {
+            logs.clear();
+            for seq_no in seq_no..seq_no + 10 {
+                assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        Ok(storage)
+    }
+
+    fn read_all(ring: &Ring) -> Result<Vec<u64>> {
+        let mut seq_nos = Vec::new();
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut reader = ring.reader(1);
+        while reader.poll(&mut logs)? == crate::cursor::Event::Logs {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                assert_eq!(b"Batman", log.data());
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        Ok(seq_nos)
+    }
+
+    fn ring_with_logs(dir: &Path, seq_nos: RangeInclusive<u64>) -> Result<Ring> {
+        let ring = Ring::open(dir, Block::new(Codec::default()))?;
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        let mut writer = ring.writer().ok_or(anyhow!("Should obtain writer"))?;
+        ring.append(&logs, &mut writer)?;
+        Ok(ring)
+    }
+
+    #[test]
+    fn export_import_round_trip() -> Result<()> {
+        let dir = tempdir()?;
+        let source = storage_with_logs(dir.path().join("source.storage"))?;
+
+        // Export a range that spans multiple blocks.
+        let path = dir.path().join("range.snapshot");
+        let mut cursor = Cursor::new(&source, 0);
+        let summary = export(&mut cursor, 15..=35, Codec::default(), &path)?;
+        let expected = Summary {
+            first: 15,
+            last: 35,
+            count: 21,
+        };
+
+        assert_eq!(expected, summary);
+        assert_eq!(expected, verify(&path)?);
+
+        // Import into a ring, after the log records it already has.
+        let target = tempdir()?;
+        let target = ring_with_logs(target.path(), 1..=14)?;
+        let summary = match target.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => import(&path, &target, &mut writer)?,
+        };
+
+        assert_eq!(expected, summary);
+        assert_eq!(Some(35), target.last());
+        assert_eq!((1..=35).collect::<Vec<_>>(), read_all(&target)?);
+
+        source.close()?;
+        Ok(target.close()?)
+    }
+
+    #[test]
+    fn export_stops_at_end_of_storage() -> Result<()> {
+        let dir = tempdir()?;
+        let source = storage_with_logs(dir.path().join("source.storage"))?;
+
+        let path = dir.path().join("range.snapshot");
+        let mut cursor = Cursor::new(&source, 0);
+        let summary = export(&mut cursor, 41..=100, Codec::default(), &path)?;
+        assert_eq!(41, summary.first);
+        assert_eq!(50, summary.last);
+        assert_eq!(10, summary.count);
+
+        Ok(source.close()?)
+    }
+
+    #[test]
+    fn export_missing_range_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let source = storage_with_logs(dir.path().join("source.storage"))?;
+        let path = dir.path().join("range.snapshot");
+
+        // Start of the range no longer exists.
+        let mut cursor = Cursor::new(&source, 0);
+        let error = export(&mut cursor, 0..=10, Codec::default(), &path).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, error.kind());
+        assert!(!path.exists());
+
+        // Range is beyond the last log record.
+        let mut cursor = Cursor::new(&source, 0);
+        let error = export(&mut cursor, 60..=70, Codec::default(), &path).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, error.kind());
+        assert!(!path.exists());
+
+        // Existing files are not overwritten.
+        fs::write(&path, b"Joker")?;
+        let mut cursor = Cursor::new(&source, 0);
+        let error = export(&mut cursor, 1..=10, Codec::default(), &path).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, error.kind());
+        assert_eq!(b"Joker", fs::read(&path)?.as_slice());
+
+        Ok(source.close()?)
+    }
+
+    #[test]
+    fn import_out_of_sequence_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let source = storage_with_logs(dir.path().join("source.storage"))?;
+
+        let path = dir.path().join("range.snapshot");
+        let mut cursor = Cursor::new(&source, 0);
+        export(&mut cursor, 1..=50, Codec::default(), &path)?;
+
+        // Snapshot must come after log records already in the ring.
+        let target = tempdir()?;
+        let target = ring_with_logs(target.path(), 1..=50)?;
+        let error = match target.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => import(&path, &target, &mut writer).unwrap_err(),
+        };
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!((1..=50).collect::<Vec<_>>(), read_all(&target)?);
+
+        source.close()?;
+        Ok(target.close()?)
+    }
+
+    #[test]
+    fn verify_detects_corruption() -> Result<()> {
+        let dir = tempdir()?;
+        let source = storage_with_logs(dir.path().join("source.storage"))?;
+
+        let path = dir.path().join("range.snapshot");
+        let mut cursor = Cursor::new(&source, 0);
+        export(&mut cursor, 1..=50, Codec::default(), &path)?;
+        let bytes = fs::read(&path)?;
+
+        // Flip a byte in log records, footer and checksum.
+        let data = MAGIC.len() + Block::HEADER_SIZE + 12;
+        for i in [data, bytes.len() - 10, bytes.len() - 1] {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 1;
+            fs::write(&path, &corrupted)?;
+
+            let error = verify(&path).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, error.kind());
+        }
+
+        // Truncated snapshot.
+        fs::write(&path, &bytes[..bytes.len() / 2])?;
+        let error = verify(&path).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        Ok(source.close()?)
+    }
+}
@@ -1,24 +1,34 @@
 //! Append only storage backed by file on disk.
 
-use crate::lock::MutGuard;
+use crate::{
+    lock::{MutLock, OwnedMutGuard},
+    metrics,
+};
 use std::{
     cmp::min,
     fs::{self, File, OpenOptions},
     io::{Error, ErrorKind, Result},
     os::unix::fs::FileExt,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicU64, Ordering::*},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering::*},
+    },
+    time::Duration,
 };
+#[cfg(feature = "tokio")]
+use tokio::sync::watch;
 
 /// An append only storage of bytes.
 ///
 /// # Concurrency
 ///
 /// A single writer can append bytes to storage at a time. To enforce this, append
-/// operation requires caller to have a reference to a [`MutGuard`]. This is not fool
-/// proof, because this data-structure does not own the lock for performance reasons.
-/// The idea is to obtain a write lock for the entire ring buffer, but individual
-/// components of the ring buffer.
+/// operation requires caller to have a mutable reference to the [`Writer`] of storage.
+/// There is at most one writer per storage at a time, obtained with [`Storage::writer`],
+/// or by waiting for it with [`Storage::writer_blocking`] and [`Storage::writer_timeout`].
+/// Waiters obtain the writer in the order they started waiting. Appends with a writer
+/// of some other storage are rejected.
 ///
 /// Any number of readers can concurrently read from storage without any form of
 /// synchronization or locking. Readers view storage as it was when a read operation
@@ -50,6 +60,9 @@ pub struct Storage {
     file: File,
     path: PathBuf,
     len: AtomicU64,
+    writer: Arc<MutLock>,
+    #[cfg(feature = "tokio")]
+    appended: watch::Sender<u64>,
 }
 
 impl Storage {
@@ -60,6 +73,10 @@ impl Storage {
     /// # Arguments
     ///
     /// * `path` - Path to the file on disk.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()), err)
+    )]
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = OpenOptions::new()
             .create_new(true)
@@ -71,6 +88,9 @@ impl Storage {
             file,
             len: AtomicU64::new(0),
             path: path.as_ref().to_path_buf(),
+            writer: Arc::new(MutLock::fair()),
+            #[cfg(feature = "tokio")]
+            appended: watch::Sender::new(0),
         })
     }
 
@@ -81,6 +101,10 @@ impl Storage {
     /// # Arguments
     ///
     /// * `path` - Path to the file on disk.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()), err)
+    )]
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let file = OpenOptions::new()
             .create(false)
@@ -91,31 +115,105 @@ impl Storage {
         // Fetch current size of the file.
         // This is not a file we just created, so don't know the size.
         let len = file.metadata()?.len();
+        event!(debug, len, "Opened storage");
+
+        Ok(Self {
+            file,
+            len: AtomicU64::new(len),
+            path: path.as_ref().to_path_buf(),
+            writer: Arc::new(MutLock::fair()),
+            #[cfg(feature = "tokio")]
+            appended: watch::Sender::new(len),
+        })
+    }
+
+    /// Open storage file in read only mode.
+    ///
+    /// Appends and truncation of storage opened this way return an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the file on disk.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(path = %path.as_ref().display()), err)
+    )]
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
+        event!(debug, len, "Opened storage in read only mode");
 
         Ok(Self {
             file,
             len: AtomicU64::new(len),
             path: path.as_ref().to_path_buf(),
+            writer: Arc::new(MutLock::fair()),
+            #[cfg(feature = "tokio")]
+            appended: watch::Sender::new(len),
         })
     }
 
+    /// Path to the file backing storage.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Returns the current size (in bytes) of storage.
     pub fn len(&self) -> u64 {
         self.len.load(Relaxed)
     }
 
+    /// Subscribe to changes in size of storage.
+    ///
+    /// The receiver is notified with the new size after every append.
+    #[cfg(feature = "tokio")]
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.appended.subscribe()
+    }
+
     /// Returns true if storage has no bytes, false otherwise.
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// Obtain the writer of storage.
+    ///
+    /// Returns None if the writer is already taken, which is reported as contention.
+    /// It can be taken again once dropped. This does not involve waiting or syscalls.
+    pub fn writer(&self) -> Option<Writer> {
+        self.writer.try_lock_owned().map(Writer)
+    }
+
+    /// Obtain the writer of storage, waiting until it is dropped if already taken.
+    pub fn writer_blocking(&self) -> Writer {
+        Writer(self.writer.lock_owned())
+    }
+
+    /// Obtain the writer of storage, waiting at most for a timeout if already taken.
+    ///
+    /// Returns None if the writer was not dropped in time.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait for the writer.
+    pub fn writer_timeout(&self, timeout: Duration) -> Option<Writer> {
+        self.writer.lock_timeout_owned(timeout).map(Writer)
+    }
+
     /// Append some bytes into storage.
     ///
+    /// Returns an error if the writer belongs to some other storage.
+    ///
     /// # Arguments
     ///
     /// * `buf` - Bytes to write into storage.
-    /// * `_guard` - Lock guard for exclusive mutable appends.
-    pub fn append(&self, buf: &[u8], _guard: &MutGuard) -> Result<()> {
+    /// * `writer` - Writer of storage, for exclusive mutable appends.
+    pub fn append(&self, buf: &[u8], writer: &mut Writer) -> Result<()> {
+        if !self.owns(writer) {
+            let kind = ErrorKind::InvalidInput;
+            return Err(Error::new(kind, "Writer belongs to different storage"));
+        }
+
         // If there is nothing to append, return early.
         if buf.is_empty() {
             return Ok(());
@@ -128,9 +226,37 @@ impl Storage {
         // Update length of the file.
         let new_len = len + buf.len() as u64;
         self.len.store(new_len, Release);
+
+        #[cfg(feature = "tokio")]
+        self.appended.send_replace(new_len);
+        metrics::record(|recorder| recorder.appended_bytes(buf.len() as u64));
+        Ok(())
+    }
+
+    /// Append an encoded block of log records into storage.
+    ///
+    /// Same as [`Storage::append`], but also counts log records once they are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Encoded block to write into storage.
+    /// * `records` - Number of log records in the block.
+    /// * `writer` - Writer of storage, for exclusive mutable appends.
+    pub fn append_block(&self, buf: &[u8], records: usize, writer: &mut Writer) -> Result<()> {
+        self.append(buf, writer)?;
+        metrics::record(|recorder| recorder.appended_records(records as u64));
         Ok(())
     }
 
+    /// Returns true if the writer belongs to this storage, false otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Writer to check.
+    pub(crate) fn owns(&self, writer: &Writer) -> bool {
+        Arc::ptr_eq(&self.writer, &(writer.0).0)
+    }
+
     /// Read next set of bytes from storage.
     ///
     /// May return lesser than requested, if any bytes are written, they are
@@ -186,8 +312,19 @@ impl Storage {
 
     /// Flushes any intermediate buffers in between the disk,
     /// guaranteeing that writes have made it to disk.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(path = %self.path.display()), err)
+    )]
     pub fn sync(&self) -> Result<()> {
-        self.file.sync_data()
+        let start = metrics::start();
+        self.file.sync_data()?;
+
+        if let Some(start) = start {
+            metrics::record(|recorder| recorder.synced(start.elapsed()));
+        }
+
+        Ok(())
     }
 
     /// Truncate storage to new length.
@@ -197,6 +334,10 @@ impl Storage {
     /// # Arguments
     ///
     /// * `len` - New length of storage.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "info", skip(self), fields(path = %self.path.display()), err)
+    )]
     pub fn truncate(&mut self, len: u64) -> Result<()> {
         // Only truncate if there are some bytes to truncate.
         let storage_len = self.len.load(Acquire);
@@ -208,6 +349,37 @@ impl Storage {
         // Because of the check above, guaranteed to only truncate.
         self.file.set_len(len)?;
         self.len.store(len, Release);
+        event!(info, removed = storage_len - len, "Truncated storage");
+
+        #[cfg(feature = "tokio")]
+        self.appended.send_replace(len);
+        Ok(())
+    }
+
+    /// Atomically replace contents of storage with another file.
+    ///
+    /// File is renamed over storage, and the rename is synced. Like truncation, this
+    /// requires mutable reference to storage. Writer of storage remains valid.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the file with new contents, in the same directory.
+    pub(crate) fn replace<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        fs::rename(path, &self.path)?;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        File::open(dir)?.sync_all()?;
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let len = file.metadata()?.len();
+        self.file = file;
+        self.len.store(len, Release);
+        event!(info, len, "Replaced storage");
+
+        #[cfg(feature = "tokio")]
+        self.appended.send_replace(len);
         Ok(())
     }
 
@@ -256,17 +428,20 @@ impl Storage {
     }
 }
 
+/// Exclusive right to append into a specific storage.
+///
+/// A writer is owned, so it can be moved across threads and held across `.await`.
+/// Storage is free to hand out a new writer once this one is dropped.
+pub struct Writer(OwnedMutGuard);
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
     use super::*;
-    use crate::lock::MutLock;
     use anyhow::{Result, anyhow};
+    use std::thread;
     use tempfile::tempdir;
 
-    // Exclusive lock for storage mutations.
-    const LOCK: MutLock = MutLock::new();
-
     // Some random test data.
     const TEST_BUF: &[u8] = b"Batman is better than superman!";
 
@@ -307,9 +482,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append empty slice of bytes
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(b"", &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(b"", &mut writer)?,
         };
 
         // No bytes should exist in storage.
@@ -325,9 +500,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Size of storage should reflect the append.
@@ -348,9 +523,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Empty buffer should not be resized.
@@ -368,9 +543,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Read after exact end of file.
@@ -392,9 +567,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Should shrink buffer to fit remaining bytes.
@@ -412,9 +587,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Request equal to remaining.
@@ -437,9 +612,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Read buffer is empty.
@@ -457,9 +632,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Buffer size is exactly equal to the number of bytes available.
@@ -470,7 +645,7 @@ mod tests {
         let mut buf = read_buf.as_mut_slice();
         while !buf.is_empty() {
             // Read as many bytes as storage returns.
-            let read = storage.read_at(offset, &mut buf)?;
+            let read = storage.read_at(offset, buf)?;
 
             // Consume all the bytes read from storage.
             offset += read as u64;
@@ -490,9 +665,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Read buffer is empty.
@@ -510,9 +685,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Buffer size is greater than total number of bytes available.
@@ -531,9 +706,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Buffer size is exactly equal to the number of bytes available.
@@ -551,9 +726,9 @@ mod tests {
         let mut storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Truncation length >= storage length should be no-op.
@@ -573,9 +748,9 @@ mod tests {
         let mut storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Truncation length < storage length should truncate storage.
@@ -610,9 +785,9 @@ mod tests {
         let storage = Storage::create(&path)?;
 
         // Append some bytes to storage.
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(TEST_BUF, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
         };
 
         // Close storage and reopen.
@@ -626,9 +801,9 @@ mod tests {
 
         // Add more bytes and make sure they are visible too.
         let more_buf = b"blah";
-        match LOCK.try_lock() {
-            None => Err(anyhow!("Should obtain write lock"))?,
-            Some(guard) => storage.append(more_buf, &guard)?,
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(more_buf, &mut writer)?,
         };
 
         // Close storage and reopen.
@@ -643,6 +818,85 @@ mod tests {
         Ok(storage.close()?)
     }
 
+    #[test]
+    fn writer_is_exclusive() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Storage::create(dir.path().join("test.storage"))?;
+
+        // Only one writer at a time, even across threads.
+        let writer = storage.writer().ok_or(anyhow!("Should obtain writer"))?;
+        assert!(storage.writer().is_none());
+        let mut writer = thread::spawn(move || writer).join().expect("Should join");
+        storage.append(TEST_BUF, &mut writer)?;
+        assert!(storage.writer().is_none());
+
+        // Writer can be obtained again once dropped.
+        drop(writer);
+        assert!(storage.writer().is_some());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn writer_can_be_waited_for() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Storage::create(dir.path().join("test.storage"))?;
+        let writer = storage.writer_blocking();
+        assert!(storage.writer_timeout(Duration::from_millis(10)).is_none());
+
+        // Waiters obtain the writer once it is dropped.
+        let mut writer = thread::scope(|scope| {
+            let waiter = scope.spawn(|| storage.writer_timeout(Duration::from_secs(5)));
+            thread::sleep(Duration::from_millis(20));
+            drop(writer);
+            waiter.join().expect("Should join")
+        })
+        .ok_or(anyhow!("Should obtain writer"))?;
+
+        storage.append(TEST_BUF, &mut writer)?;
+        assert!(storage.writer().is_none());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn append_with_writer_of_other_storage_returns_error() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Storage::create(dir.path().join("test.storage"))?;
+        let other = Storage::create(dir.path().join("other.storage"))?;
+
+        let mut writer = other.writer().ok_or(anyhow!("Should obtain writer"))?;
+        let error = storage.append(TEST_BUF, &mut writer).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(storage.is_empty());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn open_read_only_rejects_appends() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let storage = Storage::create(&path)?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(TEST_BUF, &mut writer)?,
+        };
+
+        // Close storage and reopen as read only.
+        storage.close()?;
+        let storage = Storage::open_read_only(&path)?;
+        assert_eq!(path, storage.path());
+
+        // Bytes should be visible, but cannot be modified.
+        let mut read_buf = vec![0; TEST_BUF.len()];
+        storage.read_exact_at(0, &mut read_buf)?;
+        assert_eq!(TEST_BUF, read_buf.as_slice());
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => assert!(storage.append(TEST_BUF, &mut writer).is_err()),
+        };
+
+        Ok(storage.close()?)
+    }
+
     #[test]
     fn destroy_nukes_storage() -> Result<()> {
         let dir = tempdir()?;
@@ -664,7 +918,6 @@ mod tests {
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod concurrency {
     use super::*;
-    use crate::lock::MutLock;
     use anyhow::{Error, Result};
     use std::{sync::atomic::AtomicUsize, thread};
     use tempfile::tempdir;
@@ -678,7 +931,6 @@ mod concurrency {
         let dir = tempdir()?;
 
         // Create storage at a specified path.
-        let lock = MutLock::new();
         let path = dir.path().join("test.storage");
         let storage = Storage::create(&path)?;
 
@@ -692,10 +944,8 @@ mod concurrency {
             for _ in 0..WRITERS {
                 scope.spawn(|| {
                     loop {
-                        // Obtain an exclusive write lock first.
-                        let Some(guard) = lock.try_lock() else {
-                            continue;
-                        };
+                        // Wait for the writer first.
+                        let mut writer = storage.writer_blocking();
 
                         // Figure out next index to append into storage.
                         let next_index = index.fetch_add(1, Relaxed);
@@ -705,7 +955,7 @@ mod concurrency {
 
                         // Append data into storage.
                         let buf = &data[next_index];
-                        storage.append(buf, &guard)?;
+                        storage.append(buf, &mut writer)?;
                     }
 
                     Ok::<_, Error>(())
@@ -0,0 +1,398 @@
+//! Async streams of log records for tokio based consumers.
+
+#[cfg(feature = "encryption")]
+use crate::crypto::Keyring;
+use crate::{
+    buf::LogBuf,
+    cursor::{Cursor, Event},
+    log::Log,
+    storage::Storage,
+};
+use futures::{
+    Stream,
+    future::{self, Either},
+};
+use std::{
+    io::{Error, Result},
+    pin::{Pin, pin},
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{sync::mpsc, task};
+
+/// An async stream of blocks of log records from storage.
+///
+/// Every item is either a batch of owned log records, decoded from a single block,
+/// or a range of log records that were lost before they could be read. Use
+/// [`futures::StreamExt::flat_map`] or similar to consume individual log records.
+///
+/// A task reads storage one block ahead of the consumer. Blocks are read on tokio's
+/// blocking pool, which is only occupied while reading. When storage does not have a
+/// complete block to read, the task waits for new appends instead of polling. So the
+/// stream must be polled from within a tokio runtime.
+///
+/// The stream never ends on its own, it follows storage until dropped. It ends
+/// after yielding the first error.
+pub struct LogStream {
+    reader: Option<Reader>,
+    receiver: Option<mpsc::Receiver<Result<Delivery>>>,
+}
+
+/// Item of a [`LogStream`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// Owned log records, decoded from a single block.
+    Logs(Vec<Log<'static>>),
+
+    /// Log records were lost before they could be read, see [`Cursor::poll`].
+    Lost {
+        /// Sequence number of the first log record lost.
+        from: u64,
+
+        /// Sequence number of the last log record lost.
+        to: u64,
+    },
+}
+
+impl LogStream {
+    /// Create a new stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `storage` - Storage to read blocks from.
+    /// * `offset` - Offset in storage of the first block to read.
+    pub fn new(storage: Arc<Storage>, offset: u64) -> Self {
+        let reader = Reader {
+            offset,
+            storage,
+            start: None,
+            next_seq_no: None,
+            logs: LogBuf::with_capacity(0),
+            #[cfg(feature = "encryption")]
+            keyring: None,
+        };
+
+        Self {
+            reader: Some(reader),
+            receiver: None,
+        }
+    }
+
+    /// Decrypt encrypted blocks with keys from a keyring.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyring` - Keys to decrypt blocks with.
+    #[cfg(feature = "encryption")]
+    pub fn with_keyring(mut self, keyring: Keyring) -> Self {
+        if let Some(reader) = &mut self.reader {
+            reader.keyring = Some(keyring);
+        }
+
+        self
+    }
+
+    /// Start with the log record with a sequence number.
+    ///
+    /// Log records before it are skipped. If it no longer exists, loss of log records
+    /// from it up to the oldest one that does is reported first.
+    ///
+    /// # Arguments
+    ///
+    /// * `seq_no` - Sequence number of the first log record to read.
+    pub fn with_seq_no(mut self, seq_no: u64) -> Self {
+        if let Some(reader) = &mut self.reader {
+            reader.start = Some(seq_no);
+            reader.next_seq_no = Some(seq_no);
+        }
+
+        self
+    }
+}
+
+impl Stream for LogStream {
+    type Item = Result<Delivery>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Start reading on first poll, after the stream is fully configured.
+        if let Some(reader) = self.reader.take() {
+            self.receiver = Some(reader.spawn());
+        }
+
+        match &mut self.receiver {
+            Some(receiver) => receiver.poll_recv(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Reader behind a stream, moved onto the blocking pool for every read.
+struct Reader {
+    offset: u64,
+    start: Option<u64>,
+    next_seq_no: Option<u64>,
+    logs: LogBuf,
+    storage: Arc<Storage>,
+    #[cfg(feature = "encryption")]
+    keyring: Option<Keyring>,
+}
+
+impl Reader {
+    /// Start reading blocks in a task.
+    ///
+    /// Returns the receiving end of deliveries, the reader stops once it is dropped.
+    fn spawn(self) -> mpsc::Receiver<Result<Delivery>> {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(self.run(sender));
+        receiver
+    }
+
+    /// Read blocks until the receiver is dropped, or an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` - Sending end of deliveries.
+    async fn run(self, sender: mpsc::Sender<Result<Delivery>>) {
+        let mut appended = self.storage.subscribe();
+        let mut reader = self;
+        loop {
+            // Mark current size as seen before reading, so that appends that
+            // race with the read still wake up the reader.
+            appended.borrow_and_update();
+
+            let read = task::spawn_blocking(move || {
+                let delivery = reader.read();
+                (reader, delivery)
+            });
+
+            let delivery = match read.await {
+                Ok((read, delivery)) => {
+                    reader = read;
+                    delivery
+                }
+
+                // Reader panicked or the runtime is shutting down.
+                Err(error) => {
+                    let _ = sender.send(Err(Error::other(error))).await;
+                    return;
+                }
+            };
+
+            let delivery = match delivery {
+                Ok(Some(delivery)) => Ok(delivery),
+
+                // Storage outlives the reader, so waiting for appends never fails.
+                Ok(None) => {
+                    let changed = pin!(appended.changed());
+                    let closed = pin!(sender.closed());
+                    match future::select(changed, closed).await {
+                        Either::Left(_) => continue,
+                        Either::Right(_) => return,
+                    }
+                }
+
+                Err(error) => {
+                    let _ = sender.send(Err(error)).await;
+                    return;
+                }
+            };
+
+            if sender.send(delivery).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Read the next block with something to deliver.
+    ///
+    /// Returns None if storage does not have a complete block to read yet.
+    fn read(&mut self) -> Result<Option<Delivery>> {
+        let mut cursor = Cursor::new(&self.storage, self.offset);
+        #[cfg(feature = "encryption")]
+        if let Some(keyring) = &self.keyring {
+            cursor = cursor.with_keyring(keyring);
+        }
+
+        if let Some(seq_no) = self.next_seq_no {
+            cursor = cursor.with_next_seq_no(seq_no);
+        }
+
+        let delivery = loop {
+            match cursor.poll(&mut self.logs)? {
+                Event::Logs => {
+                    let mut owned = Vec::with_capacity(self.logs.count());
+                    let mut iter = self.logs.iter();
+                    while let Some(log) = iter.next() {
+                        if self.start.is_none_or(|start| log.seq_no() >= start) {
+                            owned.push(log.into_static());
+                        }
+                    }
+
+                    // Blocks before the start have nothing to deliver.
+                    if !owned.is_empty() {
+                        break Some(Delivery::Logs(owned));
+                    }
+                }
+
+                // Log records before the start are not lost, just skipped.
+                Event::Lost { from, to, .. } => {
+                    let from = self.start.map_or(from, |start| from.max(start));
+                    if from <= to {
+                        break Some(Delivery::Lost { from, to });
+                    }
+                }
+
+                Event::Pending => break None,
+            }
+        };
+
+        self.offset = cursor.offset();
+        self.next_seq_no = cursor.next_seq_no();
+        Ok(delivery)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Codec};
+    use anyhow::{Result, anyhow};
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    fn append(storage: &Storage, seq_nos: std::ops::RangeInclusive<u64>) -> Result<()> {
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in seq_nos {
+            assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+        }
+
+        block.encode(&logs)?;
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+        };
+
+        Ok(())
+    }
+
+    fn seq_nos(delivery: &Delivery) -> Vec<u64> {
+        match delivery {
+            Delivery::Logs(logs) => logs.iter().map(Log::seq_no).collect(),
+            Delivery::Lost { .. } => panic!("Should deliver log records"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_reads_existing_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Arc::new(Storage::create(dir.path().join("test.storage"))?);
+        append(&storage, 1..=10)?;
+        append(&storage, 11..=20)?;
+
+        let mut stream = LogStream::new(storage, 0);
+        let logs = stream.next().await.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos(&logs));
+
+        let logs = stream.next().await.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((11..=20).collect::<Vec<_>>(), seq_nos(&logs));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_wakes_on_append() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Arc::new(Storage::create(dir.path().join("test.storage"))?);
+        let mut stream = LogStream::new(storage.clone(), 0);
+
+        // Nothing to read yet.
+        let pending = timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(pending.is_err());
+
+        let writer = storage.clone();
+        let handle = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            append(&writer, 1..=10)
+        });
+
+        let logs = timeout(Duration::from_secs(5), stream.next()).await?;
+        let logs = logs.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos(&logs));
+        handle.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn waiting_streams_do_not_hold_blocking_threads() -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(1)
+            .enable_time()
+            .build()?;
+
+        runtime.block_on(async {
+            let dir = tempdir()?;
+            let first = Arc::new(Storage::create(dir.path().join("first.storage"))?);
+            let second = Arc::new(Storage::create(dir.path().join("second.storage"))?);
+            let mut first = LogStream::new(first, 0);
+            let mut stream = LogStream::new(second.clone(), 0);
+
+            // Both streams wait for appends, with a single blocking thread between them.
+            let pending = timeout(Duration::from_millis(50), first.next()).await;
+            assert!(pending.is_err());
+            let pending = timeout(Duration::from_millis(50), stream.next()).await;
+            assert!(pending.is_err());
+
+            append(&second, 1..=10)?;
+            let logs = timeout(Duration::from_secs(5), stream.next()).await?;
+            let logs = logs.ok_or(anyhow!("Should have logs"))??;
+            assert_eq!((1..=10).collect::<Vec<_>>(), seq_nos(&logs));
+            Ok(())
+        })
+    }
+
+    #[tokio::test]
+    async fn stream_ends_after_error() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Arc::new(Storage::create(dir.path().join("test.storage"))?);
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0xFF; 32], &mut writer)?,
+        };
+
+        let mut stream = LogStream::new(storage, 0);
+        assert!(matches!(stream.next().await, Some(Err(_))));
+        assert!(stream.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_starts_from_seq_no_and_reports_loss() -> Result<()> {
+        let dir = tempdir()?;
+        let storage = Arc::new(Storage::create(dir.path().join("test.storage"))?);
+        append(&storage, 1..=10)?;
+        append(&storage, 21..=30)?;
+
+        let mut stream = LogStream::new(storage.clone(), 0).with_seq_no(5);
+        let logs = stream.next().await.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((5..=10).collect::<Vec<_>>(), seq_nos(&logs));
+
+        let lost = stream.next().await.ok_or(anyhow!("Should report loss"))??;
+        assert_eq!(Delivery::Lost { from: 11, to: 20 }, lost);
+
+        let logs = stream.next().await.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((21..=30).collect::<Vec<_>>(), seq_nos(&logs));
+
+        // Loss is only reported from the start on.
+        let mut stream = LogStream::new(storage, 0).with_seq_no(15);
+        let lost = stream.next().await.ok_or(anyhow!("Should report loss"))??;
+        assert_eq!(Delivery::Lost { from: 15, to: 20 }, lost);
+
+        let logs = stream.next().await.ok_or(anyhow!("Should have logs"))??;
+        assert_eq!((21..=30).collect::<Vec<_>>(), seq_nos(&logs));
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! Roll storage back to a sequence number.
+
+use crate::{
+    block::Block,
+    buf::LogBuf,
+    cursor::Cursor,
+    metrics,
+    storage::{Storage, Writer},
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Error, ErrorKind, Result},
+    os::unix::fs::FileExt,
+};
+
+/// Remove every log record after a sequence number.
+///
+/// Blocks are read from the given offset until the one holding log records after
+/// the sequence number. Storage is truncated at the start of that block, and log
+/// records in it up to the sequence number are encoded into a new block and appended
+/// back. Incomplete blocks at the end of storage are removed as well.
+///
+/// Returns the sequence number of the last log record that remains, None if there
+/// are none after the offset. Whoever tracks the last sequence number appended into
+/// storage should update it with the returned value.
+///
+/// Like [`Storage::truncate`], this requires mutable access to storage, so there can
+/// be no live cursors reading from storage at the same time. Cursors created later
+/// with offsets past the new end of storage simply find no blocks to read.
+///
+/// # Durability
+///
+/// Storage is synced before returning. When log records of a block are retained,
+/// storage is not truncated in place, since a crash before the rewritten block is
+/// synced would lose them. Instead, a copy of storage ending with the rewritten
+/// block is synced and then renamed over it. A crash at any point leaves behind
+/// either storage as it was, or as it should be, along with a leftover copy that
+/// is overwritten next time.
+///
+/// # Arguments
+///
+/// * `storage` - Storage to truncate.
+/// * `offset` - Offset of a block at or before the sequence number, 0 to scan all of storage.
+/// * `seq_no` - Sequence number of the last log record to keep.
+/// * `block` - Block to encode retained log records with, keys are also used to read blocks.
+/// * `writer` - Writer of storage.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "info", skip(storage, block, writer), fields(path = %storage.path().display()), err)
+)]
+pub fn truncate_after(
+    storage: &mut Storage,
+    offset: u64,
+    seq_no: u64,
+    block: &mut Block,
+    writer: &mut Writer,
+) -> Result<Option<u64>> {
+    if !storage.owns(writer) {
+        let kind = ErrorKind::InvalidInput;
+        return Err(Error::new(kind, "Writer belongs to different storage"));
+    }
+
+    let mut logs = LogBuf::with_capacity(0);
+    let mut retained = LogBuf::with_capacity(0).with_limits(logs.limits());
+
+    // Find the first block with log records after the sequence number.
+    let mut last = None;
+    let mut cursor = Cursor::new(storage, offset);
+    #[cfg(feature = "encryption")]
+    if let Some(keyring) = block.keyring() {
+        cursor = cursor.with_keyring(keyring);
+    }
+
+    let (offset, torn) = loop {
+        let offset = cursor.offset();
+        if !cursor.next(&mut logs)? {
+            // Whatever is left is an incomplete block, torn by a crash.
+            break (offset, true);
+        }
+
+        if logs.last().is_some_and(|last| last > seq_no) {
+            // Log records in the block up to the sequence number are retained.
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next().filter(|log| log.seq_no() <= seq_no) {
+                assert!(retained.append(&log), "Should append log record");
+            }
+
+            break (offset, false);
+        }
+
+        last = logs.last();
+    };
+
+    let removed = storage.len() - offset;
+    if removed > 0 {
+        if torn {
+            metrics::record(|recorder| recorder.corrupted(removed));
+        }
+
+        event!(
+            info,
+            offset,
+            removed,
+            reason = match torn {
+                true => "incomplete block",
+                false => "log records after sequence number",
+            },
+            retained = retained.count(),
+            "Removing end of storage"
+        );
+    }
+
+    // Remove the block, and everything after it.
+    if retained.is_empty() {
+        storage.truncate(offset)?;
+        storage.sync()?;
+        return Ok(last);
+    }
+
+    // Replace storage with a durable copy that ends with the rewritten block.
+    block.encode(&retained)?;
+    let mut tmp_path = storage.path().as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::copy(storage.path(), &tmp_path)?;
+
+    let file = OpenOptions::new().write(true).open(&tmp_path)?;
+    file.set_len(offset)?;
+    file.write_all_at(block.bytes(), offset)?;
+    file.sync_all()?;
+    storage.replace(&tmp_path)?;
+
+    Ok(retained.last())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::{block::Codec, log::Log};
+    use anyhow::{Result, anyhow};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn storage_with_logs(path: &Path) -> Result<Storage> {
+        let storage = Storage::create(path)?;
+
+        // Blocks of 10 log records, with sequence numbers 1 to 30.
+        let mut block = Block::new(Codec::default());
+        let mut logs = LogBuf::with_capacity(1024);
+        for seq_no in (1..=30).step_by(10) {
+            logs.clear();
+            for seq_no in seq_no..seq_no + 10 {
+                assert!(logs.append(&Log::new_borrowed(seq_no, b"Batman")));
+            }
+
+            block.encode(&logs)?;
+            match storage.writer() {
+                None => Err(anyhow!("Should obtain writer"))?,
+                Some(mut writer) => storage.append(block.bytes(), &mut writer)?,
+            };
+        }
+
+        Ok(storage)
+    }
+
+    fn truncate(storage: &mut Storage, seq_no: u64) -> Result<Option<u64>> {
+        let mut block = Block::new(Codec::default());
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => Ok(truncate_after(storage, 0, seq_no, &mut block, &mut writer)?),
+        }
+    }
+
+    fn read_all(storage: &Storage) -> Result<Vec<u64>> {
+        let mut seq_nos = Vec::new();
+        let mut logs = LogBuf::with_capacity(1024);
+        let mut cursor = Cursor::new(storage, 0);
+        while cursor.next(&mut logs)? {
+            let mut iter = logs.iter();
+            while let Some(log) = iter.next() {
+                seq_nos.push(log.seq_no());
+            }
+        }
+
+        assert_eq!(storage.len(), cursor.offset());
+        Ok(seq_nos)
+    }
+
+    #[test]
+    fn truncate_after_within_block() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+
+        assert_eq!(Some(15), truncate(&mut storage, 15)?);
+        assert_eq!((1..=15).collect::<Vec<_>>(), read_all(&storage)?);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_after_end_of_block() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+
+        assert_eq!(Some(20), truncate(&mut storage, 20)?);
+        assert_eq!((1..=20).collect::<Vec<_>>(), read_all(&storage)?);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_after_last_is_noop() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+        let len = storage.len();
+
+        assert_eq!(Some(30), truncate(&mut storage, 100)?);
+        assert_eq!(len, storage.len());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_before_first_removes_everything() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+
+        assert_eq!(None, truncate(&mut storage, 0)?);
+        assert!(storage.is_empty());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_removes_incomplete_block() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+        let len = storage.len();
+
+        // Partially written block at the end.
+        match storage.writer() {
+            None => Err(anyhow!("Should obtain writer"))?,
+            Some(mut writer) => storage.append(&[0, 0, 0, 0, 100], &mut writer)?,
+        };
+
+        assert_eq!(Some(30), truncate(&mut storage, 100)?);
+        assert_eq!(len, storage.len());
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_within_block_replaces_storage() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.storage");
+        let mut storage = storage_with_logs(&path)?;
+
+        // Leftover copy from a crash during a previous truncation.
+        let tmp_path = dir.path().join("test.storage.tmp");
+        fs::write(&tmp_path, b"Joker")?;
+
+        let mut block = Block::new(Codec::default());
+        let mut writer = storage.writer().ok_or(anyhow!("Should obtain writer"))?;
+        assert_eq!(
+            Some(15),
+            truncate_after(&mut storage, 0, 15, &mut block, &mut writer)?
+        );
+        assert!(!tmp_path.exists());
+
+        // Storage still takes appends with the same writer, and survives reopening.
+        let mut logs = LogBuf::with_capacity(1024);
+        assert!(logs.append(&Log::new_borrowed(16, b"Robin")));
+        block.encode(&logs)?;
+        storage.append(block.bytes(), &mut writer)?;
+        drop(writer);
+        storage.close()?;
+
+        let storage = Storage::open(&path)?;
+        assert_eq!((1..=16).collect::<Vec<_>>(), read_all(&storage)?);
+        Ok(storage.close()?)
+    }
+
+    #[test]
+    fn truncate_rejects_writer_of_other_storage() -> Result<()> {
+        let dir = tempdir()?;
+        let mut storage = storage_with_logs(&dir.path().join("test.storage"))?;
+        let other = Storage::create(dir.path().join("other.storage"))?;
+        let len = storage.len();
+
+        let mut block = Block::new(Codec::default());
+        let mut writer = other.writer().ok_or(anyhow!("Should obtain writer"))?;
+        let error = truncate_after(&mut storage, 0, 15, &mut block, &mut writer).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!(len, storage.len());
+        Ok(storage.close()?)
+    }
+}